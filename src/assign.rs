@@ -0,0 +1,563 @@
+//! Assignment of features to the bins of their histograms.
+use crate::DynResult;
+use faiss::{FlatIndex, Index};
+use ndarray::{s, Array2, ArrayView2, Axis};
+
+/// Build a flat L2 index over the given codebook.
+pub fn codebook_index(codebook: ArrayView2<f32>) -> DynResult<FlatIndex> {
+    let d = codebook.shape()[1] as u32;
+    let mut index = FlatIndex::new_l2(d)?;
+    index.add(
+        codebook
+            .as_slice()
+            .expect("codebook should be in standard layout"),
+    )?;
+    Ok(index)
+}
+
+/// Assign each feature in `x` to the nearest vector in the index.
+///
+/// With `stable`, the two nearest vectors are searched instead, and ties
+/// between them are broken by picking the lowest index, so that
+/// assignments do not depend on the search order of the index.
+/// Labels are `-1` where no assignment could be made.
+pub fn nearest<I: Index + ?Sized>(index: &mut I, x: &[f32], stable: bool) -> DynResult<Vec<i64>> {
+    if !stable {
+        return Ok(index.assign(x, 1)?.labels);
+    }
+    Ok(nearest_with_distances(index, x, true)?.0)
+}
+
+/// Like [`nearest`](fn.nearest.html), also returning the distance
+/// (squared, for L2 indices) of each feature to its nearest vector.
+pub fn nearest_with_distances<I: Index + ?Sized>(
+    index: &mut I,
+    x: &[f32],
+    stable: bool,
+) -> DynResult<(Vec<i64>, Vec<f32>)> {
+    if !stable {
+        let result = index.search(x, 1)?;
+        return Ok((result.labels, result.distances));
+    }
+    let result = index.search(x, 2)?;
+    let (labels, distances) = result
+        .distances
+        .chunks(2)
+        .zip(result.labels.chunks(2))
+        .map(|(distances, labels)| {
+            if labels[1] >= 0 && distances[0] == distances[1] {
+                (i64::min(labels[0], labels[1]), distances[0])
+            } else {
+                (labels[0], distances[0])
+            }
+        })
+        .unzip();
+    Ok((labels, distances))
+}
+
+/// Search the index for the `knn` nearest vectors of each feature,
+/// nearest first, along with their distances (squared, for L2 indices).
+///
+/// With `stable`, one more vector is searched, and vectors at the same
+/// distance are ordered by the lowest index, so that the vectors picked
+/// do not depend on the search order of the index.
+pub fn nearest_k_with_distances<I: Index + ?Sized>(
+    index: &mut I,
+    x: &[f32],
+    knn: usize,
+    stable: bool,
+) -> DynResult<(Vec<i64>, Vec<f32>)> {
+    if !stable {
+        let result = index.search(x, knn)?;
+        return Ok((result.labels, result.distances));
+    }
+    let result = index.search(x, knn + 1)?;
+    let n = result.labels.len() / (knn + 1);
+    let mut labels = Vec::with_capacity(n * knn);
+    let mut distances = Vec::with_capacity(n * knn);
+    let mut found = Vec::with_capacity(knn + 1);
+    for (l, d) in result
+        .labels
+        .chunks(knn + 1)
+        .zip(result.distances.chunks(knn + 1))
+    {
+        found.clear();
+        found.extend(l.iter().cloned().zip(d.iter().cloned()));
+        // missing vectors (labelled -1) stay last
+        found.sort_by(|a, b| {
+            (a.0 < 0)
+                .cmp(&(b.0 < 0))
+                .then(a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+                .then(a.0.cmp(&b.0))
+        });
+        labels.extend(found[..knn].iter().map(|&(label, _)| label));
+        distances.extend(found[..knn].iter().map(|&(_, distance)| distance));
+    }
+    Ok((labels, distances))
+}
+
+/// Search the index for the `knn` nearest vectors of each feature,
+/// with their distances only if `with_distances` (otherwise left empty).
+fn search<I: Index + ?Sized>(
+    index: &mut I,
+    x: &[f32],
+    knn: usize,
+    stable: bool,
+    with_distances: bool,
+) -> DynResult<(Vec<i64>, Vec<f32>)> {
+    if knn > 1 {
+        nearest_k_with_distances(index, x, knn, stable)
+    } else if with_distances {
+        nearest_with_distances(index, x, stable)
+    } else {
+        Ok((nearest(index, x, stable)?, Vec::new()))
+    }
+}
+
+/// The number of features assigned to each bin,
+/// and the sum of their distances to its codeword.
+#[derive(Debug, Clone)]
+pub struct CodewordStats {
+    pub counts: Vec<u64>,
+    pub distance_sums: Vec<f64>,
+}
+
+impl CodewordStats {
+    /// Create empty statistics of `n_bins` bins.
+    pub fn new(n_bins: usize) -> Self {
+        CodewordStats {
+            counts: vec![0; n_bins],
+            distance_sums: vec![0.; n_bins],
+        }
+    }
+
+    /// Record the assignment of a feature to a bin, given the squared
+    /// L2 distance between them.
+    pub fn add(&mut self, bin: usize, squared_distance: f64) {
+        self.counts[bin] += 1;
+        self.distance_sums[bin] += squared_distance.max(0.).sqrt();
+    }
+
+    /// The mean distance of the features assigned to each bin,
+    /// 0 for bins without any.
+    pub fn mean_distances(&self) -> Vec<f32> {
+        self.counts
+            .iter()
+            .zip(&self.distance_sums)
+            .map(|(&count, &sum)| {
+                if count > 0 {
+                    (sum / count as f64) as f32
+                } else {
+                    0.
+                }
+            })
+            .collect()
+    }
+}
+
+/// Assigns features to the bins of their histograms.
+pub struct Assigner {
+    codebook: Codebook,
+    stable: bool,
+    /// The number of nearest codewords each feature is assigned to.
+    knn: usize,
+    /// The labels of the last assigned batch, kept to be reused by the next.
+    labels: Vec<i64>,
+    /// The distances of the last assigned batch to its labels, if searched.
+    distances: Vec<f32>,
+    /// The statistics of all assignments so far, if collected.
+    stats: Option<CodewordStats>,
+}
+
+/// The codebooks of an assigner.
+enum Codebook {
+    /// Each feature is assigned to its nearest codeword.
+    Flat(FlatIndex),
+    /// Each feature is assigned to its nearest codeword among a subset
+    /// of the `k` codewords, the `i`-th codeword of the index being
+    /// `codewords[i]`.
+    Subset {
+        index: FlatIndex,
+        codewords: Vec<usize>,
+        k: usize,
+    },
+    /// Each feature is split into `m` sub-vectors of `d_sub` dimensions,
+    /// each assigned to the nearest codeword of its own sub-codebook.
+    /// The bins of the `j`-th sub-codebook start at `j * k`.
+    /// `sub` holds the contiguous sub-vectors of a batch,
+    /// and is reused from one batch to the next.
+    Product {
+        indices: Vec<FlatIndex>,
+        k: usize,
+        d_sub: usize,
+        sub: Vec<f32>,
+    },
+    /// Each feature is assigned to its nearest codeword in each of
+    /// several codebooks, the bins of the `j`-th codebook starting at
+    /// `offsets[j]`. `offsets` ends with the total number of bins.
+    Multi {
+        indices: Vec<FlatIndex>,
+        offsets: Vec<usize>,
+    },
+}
+
+impl Assigner {
+    /// Create an assigner to the codewords of a flat codebook.
+    pub fn flat(codebook: ArrayView2<f32>) -> DynResult<Self> {
+        Ok(Assigner {
+            codebook: Codebook::Flat(codebook_index(codebook)?),
+            stable: false,
+            knn: 1,
+            labels: Vec::new(),
+            distances: Vec::new(),
+            stats: None,
+        })
+    }
+
+    /// Create an assigner to only some of the codewords of a flat codebook,
+    /// labelling features with their index in the whole codebook.
+    pub fn flat_subset(codebook: ArrayView2<f32>, codewords: &[usize]) -> DynResult<Self> {
+        if codewords.is_empty() {
+            return Err("codeword subset is empty".into());
+        }
+        if let Some(&i) = codewords.iter().find(|&&i| i >= codebook.rows()) {
+            return Err(format!(
+                "codeword {} is out of range, the codebook has {} codewords",
+                i,
+                codebook.rows()
+            )
+            .into());
+        }
+        let subset = codebook.select(Axis(0), codewords);
+        Ok(Assigner {
+            codebook: Codebook::Subset {
+                index: codebook_index(subset.view())?,
+                codewords: codewords.to_vec(),
+                k: codebook.rows(),
+            },
+            stable: false,
+            knn: 1,
+            labels: Vec::new(),
+            distances: Vec::new(),
+            stats: None,
+        })
+    }
+
+    /// Create an assigner to the codewords of a product quantization
+    /// codebook, given its sub-codebooks.
+    pub fn product(codebooks: &[Array2<f32>]) -> DynResult<Self> {
+        let (k, d_sub) = match codebooks.first() {
+            Some(codebook) => codebook.dim(),
+            None => return Err("product codebook has no sub-codebooks".into()),
+        };
+        let mut indices = Vec::with_capacity(codebooks.len());
+        for (j, codebook) in codebooks.iter().enumerate() {
+            if codebook.dim() != (k, d_sub) {
+                return Err(format!(
+                    "sub-codebook {} has shape {:?}, but {:?} was expected",
+                    j,
+                    codebook.dim(),
+                    (k, d_sub)
+                )
+                .into());
+            }
+            indices.push(codebook_index(codebook.view())?);
+        }
+        Ok(Assigner {
+            codebook: Codebook::Product {
+                indices,
+                k,
+                d_sub,
+                sub: Vec::new(),
+            },
+            stable: false,
+            knn: 1,
+            labels: Vec::new(),
+            distances: Vec::new(),
+            stats: None,
+        })
+    }
+
+    /// Create an assigner to the codewords of several flat codebooks
+    /// of the same dimensionality, whose bins are concatenated.
+    pub fn multi(codebooks: &[ArrayView2<f32>]) -> DynResult<Self> {
+        let d = match codebooks.first() {
+            Some(codebook) => codebook.cols(),
+            None => return Err("no codebooks given".into()),
+        };
+        let mut indices = Vec::with_capacity(codebooks.len());
+        let mut offsets = vec![0];
+        for (j, codebook) in codebooks.iter().enumerate() {
+            if codebook.cols() != d {
+                return Err(format!(
+                    "codebook {} has codewords of {} dimensions, but {} were expected",
+                    j,
+                    codebook.cols(),
+                    d
+                )
+                .into());
+            }
+            indices.push(codebook_index(codebook.view())?);
+            offsets.push(offsets[j] + codebook.rows());
+        }
+        Ok(Assigner {
+            codebook: Codebook::Multi { indices, offsets },
+            stable: false,
+            knn: 1,
+            labels: Vec::new(),
+            distances: Vec::new(),
+            stats: None,
+        })
+    }
+
+    /// Break ties between equally near codewords by the lowest index
+    /// (see [`nearest`](fn.nearest.html)).
+    pub fn stable(mut self, stable: bool) -> Self {
+        self.stable = stable;
+        self
+    }
+
+    /// Assign each feature to its `knn` nearest codewords rather than
+    /// only the nearest one, which only flat codebooks and subsets of them
+    /// support.
+    pub fn knn(mut self, knn: usize) -> DynResult<Self> {
+        let codewords = match &self.codebook {
+            Codebook::Flat(index) => index.ntotal() as usize,
+            Codebook::Subset { codewords, .. } => codewords.len(),
+            Codebook::Product { .. } | Codebook::Multi { .. } if knn == 1 => 1,
+            Codebook::Product { .. } | Codebook::Multi { .. } => {
+                return Err("only flat codebooks support more than one nearest codeword".into())
+            }
+        };
+        if knn == 0 || knn > codewords {
+            return Err(format!(
+                "cannot assign features to {} of {} codewords",
+                knn, codewords
+            )
+            .into());
+        }
+        self.knn = knn;
+        Ok(self)
+    }
+
+    /// Collect the number of features assigned to each bin and their
+    /// distances to its codeword, which needs searching for distances.
+    pub fn collect_stats(mut self, collect: bool) -> Self {
+        self.stats = if collect {
+            Some(CodewordStats::new(self.n_bins()))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// The statistics of all assignments so far, if collected.
+    pub fn stats(&self) -> Option<&CodewordStats> {
+        self.stats.as_ref()
+    }
+
+    /// The total number of histogram bins.
+    pub fn n_bins(&self) -> usize {
+        match &self.codebook {
+            Codebook::Flat(index) => index.ntotal() as usize,
+            Codebook::Subset { k, .. } => *k,
+            Codebook::Product { indices, k, .. } => indices.len() * k,
+            Codebook::Multi { offsets, .. } => offsets[offsets.len() - 1],
+        }
+    }
+
+    /// The dimensionality of the features to assign.
+    pub fn dim(&self) -> usize {
+        match &self.codebook {
+            Codebook::Flat(index) | Codebook::Subset { index, .. } => index.d() as usize,
+            Codebook::Product { indices, d_sub, .. } => indices.len() * d_sub,
+            Codebook::Multi { indices, .. } => indices[0].d() as usize,
+        }
+    }
+
+    /// The number of bins assigned to each feature.
+    pub fn bins_per_feature(&self) -> usize {
+        match &self.codebook {
+            Codebook::Flat(_) | Codebook::Subset { .. } => self.knn,
+            Codebook::Product { indices, .. } | Codebook::Multi { indices, .. } => indices.len(),
+        }
+    }
+
+    /// Assign a batch of features to their bins, returning
+    /// `bins_per_feature()` consecutive labels per feature.
+    /// Labels are `-1` where no assignment could be made.
+    ///
+    /// The returned labels borrow a buffer of the assigner, which is
+    /// reused by the next batch. The faiss binding still allocates
+    /// the results of each search, which are copied into it.
+    pub fn assign(&mut self, batch: ArrayView2<f32>) -> DynResult<&[i64]> {
+        let with_distances = self.stats.is_some();
+        self.search_batch(batch, with_distances)?;
+        Ok(&self.labels)
+    }
+
+    /// Like [`assign`](#method.assign), also returning the distance
+    /// (squared) of each feature to each of its bins, which is unspecified
+    /// where no assignment could be made.
+    pub fn assign_with_distances(&mut self, batch: ArrayView2<f32>) -> DynResult<(&[i64], &[f32])> {
+        self.search_batch(batch, true)?;
+        Ok((&self.labels, &self.distances))
+    }
+
+    /// Assign a batch of features to their bins into the label buffer,
+    /// and their distances into the distance buffer if `with_distances`.
+    fn search_batch(&mut self, batch: ArrayView2<f32>, with_distances: bool) -> DynResult<()> {
+        let (stable, knn) = (self.stable, self.knn);
+        self.distances.clear();
+        match &mut self.codebook {
+            Codebook::Flat(index) => {
+                let (labels, distances) = search(
+                    index,
+                    batch
+                        .as_slice()
+                        .expect("features should be in standard layout"),
+                    knn,
+                    stable,
+                    with_distances,
+                )?;
+                self.labels.clear();
+                self.labels.extend_from_slice(&labels);
+                if with_distances {
+                    self.distances.extend_from_slice(&distances);
+                }
+                if let Some(stats) = &mut self.stats {
+                    record(stats, &self.labels, &distances);
+                }
+            }
+            Codebook::Subset {
+                index, codewords, ..
+            } => {
+                let (labels, distances) = search(
+                    index,
+                    batch
+                        .as_slice()
+                        .expect("features should be in standard layout"),
+                    knn,
+                    stable,
+                    with_distances,
+                )?;
+                self.labels.clear();
+                self.labels.extend_from_slice(&labels);
+                if with_distances {
+                    self.distances.extend_from_slice(&distances);
+                }
+                for label in self.labels.iter_mut().filter(|label| **label >= 0) {
+                    *label = codewords[*label as usize] as i64;
+                }
+                if let Some(stats) = &mut self.stats {
+                    record(stats, &self.labels, &distances);
+                }
+            }
+            Codebook::Product {
+                indices,
+                k,
+                d_sub,
+                sub,
+            } => {
+                let (n, m) = (batch.rows(), indices.len());
+                self.labels.clear();
+                self.labels.resize(n * m, -1);
+                if with_distances {
+                    self.distances.resize(n * m, 0.);
+                }
+                for (j, index) in indices.iter_mut().enumerate() {
+                    sub.clear();
+                    sub.extend(batch.slice(s![.., j * *d_sub..(j + 1) * *d_sub]).iter());
+                    let (sub_labels, distances) = search(index, sub, 1, stable, with_distances)?;
+                    for (i, label) in sub_labels.into_iter().enumerate() {
+                        if label >= 0 {
+                            let bin = j * *k + label as usize;
+                            self.labels[i * m + j] = bin as i64;
+                            if with_distances {
+                                self.distances[i * m + j] = distances[i];
+                            }
+                            if let Some(stats) = &mut self.stats {
+                                stats.add(bin, f64::from(distances[i]));
+                            }
+                        }
+                    }
+                }
+            }
+            Codebook::Multi { indices, offsets } => {
+                let x = batch
+                    .as_slice()
+                    .expect("features should be in standard layout");
+                let (n, m) = (batch.rows(), indices.len());
+                self.labels.clear();
+                self.labels.resize(n * m, -1);
+                if with_distances {
+                    self.distances.resize(n * m, 0.);
+                }
+                for (j, index) in indices.iter_mut().enumerate() {
+                    let (labels, distances) = search(index, x, 1, stable, with_distances)?;
+                    for (i, label) in labels.into_iter().enumerate() {
+                        if label >= 0 {
+                            let bin = offsets[j] + label as usize;
+                            self.labels[i * m + j] = bin as i64;
+                            if with_distances {
+                                self.distances[i * m + j] = distances[i];
+                            }
+                            if let Some(stats) = &mut self.stats {
+                                stats.add(bin, f64::from(distances[i]));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Record the assignments of one label per feature with their distances.
+fn record(stats: &mut CodewordStats, labels: &[i64], distances: &[f32]) {
+    for (&label, &distance) in labels.iter().zip(distances) {
+        if label >= 0 {
+            stats.add(label as usize, f64::from(distance));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codebook() -> Array2<f32> {
+        Array2::from_shape_vec((3, 2), vec![0., 0., 10., 0., 0., 10.]).unwrap()
+    }
+
+    #[test]
+    fn assign_flat() {
+        let mut assigner = Assigner::flat(codebook().view()).unwrap();
+        let batch = Array2::from_shape_vec((3, 2), vec![9., 1., 1., 9., 1., 1.]).unwrap();
+        assert_eq!(assigner.assign(batch.view()).unwrap(), &[1, 2, 0]);
+    }
+
+    #[test]
+    fn assign_subset_labels_whole_codebook() {
+        let mut assigner = Assigner::flat_subset(codebook().view(), &[0, 2]).unwrap();
+        let batch = Array2::from_shape_vec((2, 2), vec![9., 1., 1., 9.]).unwrap();
+        assert_eq!(assigner.assign(batch.view()).unwrap(), &[0, 2]);
+    }
+
+    #[test]
+    fn assign_knn_orders_ties_by_index() {
+        let mut assigner = Assigner::flat(codebook().view())
+            .unwrap()
+            .stable(true)
+            .knn(2)
+            .unwrap();
+        assert_eq!(assigner.bins_per_feature(), 2);
+        // (1, 1) is as far from codewords 1 and 2
+        let batch = Array2::from_shape_vec((2, 2), vec![1., 1., 9., 1.]).unwrap();
+        let (labels, distances) = assigner.assign_with_distances(batch.view()).unwrap();
+        assert_eq!(labels, &[0, 1, 1, 0]);
+        assert_eq!(distances, &[2., 82., 2., 82.]);
+        assert!(Assigner::flat(codebook().view()).unwrap().knn(4).is_err());
+    }
+}
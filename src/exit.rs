@@ -0,0 +1,64 @@
+//! Process exit codes by category of failure, so that scripts can tell
+//! failures worth retrying (such as I/O errors) from the others.
+use std::error::Error;
+use std::fmt;
+
+/// A category of failure with its own exit code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    /// Invalid input data or settings (exit code 2)
+    BadInput,
+    /// Features and codebooks of incompatible dimensions (exit code 3)
+    DimensionMismatch,
+    /// Failure to read or write a file (exit code 4)
+    Io,
+    /// Nothing to work on, such as an empty training sample (exit code 5)
+    EmptyData,
+}
+
+impl Category {
+    /// The process exit code of this category.
+    pub fn code(self) -> i32 {
+        match self {
+            Category::BadInput => 2,
+            Category::DimensionMismatch => 3,
+            Category::Io => 4,
+            Category::EmptyData => 5,
+        }
+    }
+}
+
+/// An error message of a known category.
+#[derive(Debug)]
+pub struct Failure {
+    category: Category,
+    message: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Failure {}
+
+/// Create an error of the given category.
+pub fn failure(category: Category, message: impl Into<String>) -> Box<dyn Error> {
+    Box::new(Failure {
+        category,
+        message: message.into(),
+    })
+}
+
+/// The process exit code for the given error: that of its category,
+/// 4 for I/O and HDF5 errors, and 1 for any other error.
+pub fn code(error: &(dyn Error + 'static)) -> i32 {
+    if let Some(failure) = error.downcast_ref::<Failure>() {
+        failure.category.code()
+    } else if error.is::<std::io::Error>() || error.is::<h5::Error>() {
+        Category::Io.code()
+    } else {
+        1
+    }
+}
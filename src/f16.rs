@@ -0,0 +1,315 @@
+//! Conversion of centroids to and from half precision floats.
+//!
+//! Neither faiss nor the HDF5 binding handle half precision floats,
+//! so they are rounded here, and saved in a 16-bit IEEE float data set
+//! created through the HDF5 library itself, as by NumPy's `float16`.
+use crate::exit::{failure, Category};
+use crate::DynResult;
+use h5::{Dataset, Datatype, Group};
+use libhdf5_sys::h5d::{H5Dclose, H5Dcreate2, H5Dget_type, H5Dread, H5Dwrite};
+use libhdf5_sys::h5i::hid_t;
+use libhdf5_sys::h5p::H5P_DEFAULT;
+use libhdf5_sys::h5s::{H5Sclose, H5Screate_simple, H5S_ALL};
+use libhdf5_sys::h5t::{
+    H5T_class_t, H5Tclose, H5Tcopy, H5Tget_class, H5Tget_size, H5Tset_ebias, H5Tset_fields,
+    H5Tset_size,
+};
+use ndarray::{Array2, ArrayView2};
+use std::ffi::CString;
+
+/// The binary16 encoding of `x`, rounded to the nearest value
+/// (ties to even). Values too large for half precision become infinite.
+pub fn from_f32(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let man = bits & 0x7f_ffff;
+    if exp == 0xff {
+        // infinity, or NaN with the quiet bit set
+        return sign | 0x7c00 | if man != 0 { 0x200 } else { 0 };
+    }
+    let e = exp - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if e <= 0 {
+        // subnormal in half precision, in units of 2^-24
+        let shift = (14 - e) as u32;
+        if shift > 24 {
+            return sign;
+        }
+        let full = man | 0x80_0000;
+        let rem = full & ((1 << shift) - 1);
+        let half = 1 << (shift - 1);
+        let mut m = (full >> shift) as u16;
+        if rem > half || (rem == half && m & 1 == 1) {
+            // may carry into the smallest normal number, which is exact
+            m += 1;
+        }
+        return sign | m;
+    }
+    let rem = man & 0x1fff;
+    let h = ((e as u16) << 10) | (man >> 13) as u16;
+    // a carry into the exponent rounds up to the next power of two,
+    // or to infinity
+    if rem > 0x1000 || (rem == 0x1000 && h & 1 == 1) {
+        sign | (h + 1)
+    } else {
+        sign | h
+    }
+}
+
+/// The value of the binary16 encoding `h`.
+pub fn to_f32(h: u16) -> f32 {
+    let sign = u32::from(h & 0x8000) << 16;
+    let exp = u32::from((h >> 10) & 0x1f);
+    let man = u32::from(h & 0x3ff);
+    match exp {
+        0 => {
+            let x = man as f32 * 2f32.powi(-24);
+            if sign != 0 {
+                -x
+            } else {
+                x
+            }
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (man << 13)),
+        _ => f32::from_bits(sign | ((exp + 112) << 23) | (man << 13)),
+    }
+}
+
+/// Encode a matrix of centroids in half precision.
+pub fn encode(x: ArrayView2<f32>) -> Array2<u16> {
+    x.mapv(from_f32)
+}
+
+/// Decode a matrix of centroids from half precision.
+pub fn decode(x: ArrayView2<u16>) -> Array2<f32> {
+    x.mapv(to_f32)
+}
+
+/// Fail with the current HDF5 error if `status` is negative.
+fn check<T: Copy + Default + PartialOrd>(status: T, what: &str) -> DynResult<T> {
+    if status < T::default() {
+        Err(failure(
+            Category::Io,
+            format!("could not {} a half precision data set", what),
+        ))
+    } else {
+        Ok(status)
+    }
+}
+
+/// A new IEEE 754 binary16 type, little endian like the native `f32`
+/// type it is derived from, to be closed by the caller.
+fn half_type() -> DynResult<hid_t> {
+    let native = Datatype::from_type::<f32>()?;
+    unsafe {
+        let t = check(H5Tcopy(native.id()), "create the type of")?;
+        // sign bit, exponent position and size, mantissa position and size
+        let set = check(H5Tset_fields(t, 15, 10, 5, 0, 10), "create the type of")
+            .and_then(|_| check(H5Tset_size(t, 2), "create the type of"))
+            .and_then(|_| check(H5Tset_ebias(t, 15), "create the type of"));
+        if let Err(e) = set {
+            H5Tclose(t);
+            return Err(e);
+        }
+        Ok(t)
+    }
+}
+
+/// Create a new two-dimensional data set `name` of 16-bit IEEE floats
+/// in `loc`, of the given shape.
+///
+/// The HDF5 binding serializes its own calls, which these do not take
+/// part in, so no other thread should use HDF5 meanwhile.
+pub fn create_dataset(loc: &Group, name: &str, shape: (usize, usize)) -> DynResult<Dataset> {
+    let dims = [shape.0 as u64, shape.1 as u64];
+    let cname = CString::new(name)?;
+    let t = half_type()?;
+    let status = unsafe {
+        let space = H5Screate_simple(2, dims.as_ptr() as *const _, std::ptr::null());
+        let dset = if space < 0 {
+            -1
+        } else {
+            let dset = H5Dcreate2(
+                loc.id(),
+                cname.as_ptr(),
+                t,
+                space,
+                H5P_DEFAULT,
+                H5P_DEFAULT,
+                H5P_DEFAULT,
+            );
+            H5Sclose(space);
+            dset
+        };
+        H5Tclose(t);
+        if dset >= 0 {
+            H5Dclose(dset);
+        }
+        dset
+    };
+    check(status, "create")?;
+    Ok(loc.dataset(name)?)
+}
+
+/// Round a matrix of centroids to half precision and write them to a data
+/// set created by [`create_dataset`](fn.create_dataset.html).
+pub fn write_dataset(dset: &Dataset, x: ArrayView2<f32>) -> DynResult<()> {
+    if dset.shape() != [x.rows(), x.cols()] {
+        return Err(failure(
+            Category::DimensionMismatch,
+            format!(
+                "cannot write centroids of shape {:?} to a data set of shape {:?}",
+                x.dim(),
+                dset.shape()
+            ),
+        ));
+    }
+    let bits = encode(x);
+    let bits = bits
+        .as_slice()
+        .expect("encoded centroids should be contiguous");
+    let t = half_type()?;
+    let status = unsafe {
+        // the memory type is the file type, so the bits are written as is
+        let status = H5Dwrite(
+            dset.id(),
+            t,
+            H5S_ALL,
+            H5S_ALL,
+            H5P_DEFAULT,
+            bits.as_ptr() as *const _,
+        );
+        H5Tclose(t);
+        status
+    };
+    check(status, "write")?;
+    Ok(())
+}
+
+/// Whether the data set holds 16-bit floats.
+pub fn is_half(dset: &Dataset) -> DynResult<bool> {
+    unsafe {
+        let t = check(H5Dget_type(dset.id()), "read the type of")?;
+        let half = H5Tget_class(t) == H5T_class_t::H5T_FLOAT && H5Tget_size(t) == 2;
+        H5Tclose(t);
+        Ok(half)
+    }
+}
+
+/// Read a two-dimensional data set of 16-bit floats as 32-bit floats.
+pub fn read_dataset(dset: &Dataset) -> DynResult<Array2<f32>> {
+    let (rows, cols) = match dset.shape()[..] {
+        [rows, cols] => (rows, cols),
+        ref shape => {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "centroids should be two-dimensional, found shape {:?}",
+                    shape
+                ),
+            ))
+        }
+    };
+    let mut bits = vec![0_u16; rows * cols];
+    let t = half_type()?;
+    let status = unsafe {
+        let status = H5Dread(
+            dset.id(),
+            t,
+            H5S_ALL,
+            H5S_ALL,
+            H5P_DEFAULT,
+            bits.as_mut_ptr() as *mut _,
+        );
+        H5Tclose(t);
+        status
+    };
+    check(status, "read")?;
+    Ok(decode(Array2::from_shape_vec((rows, cols), bits)?.view()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(x: f32) -> f32 {
+        to_f32(from_f32(x))
+    }
+
+    #[test]
+    fn exact_values_round_trip() {
+        // zeros, the smallest normal number and the largest finite one
+        for &x in &[0., -0., 1., -2.5, 2f32.powi(-14), 65504., -65504.] {
+            assert_eq!(round_trip(x).to_bits(), x.to_bits());
+        }
+    }
+
+    #[test]
+    fn subnormals_round_trip() {
+        // the smallest and largest subnormal half precision floats
+        let smallest = 2f32.powi(-24);
+        let largest = 1023. * 2f32.powi(-24);
+        assert_eq!(from_f32(smallest), 0x0001);
+        assert_eq!(from_f32(largest), 0x03ff);
+        assert_eq!(round_trip(smallest), smallest);
+        assert_eq!(round_trip(-largest), -largest);
+        // below half of the smallest subnormal, values flush to zero
+        assert_eq!(from_f32(2f32.powi(-26)), 0x0000);
+        assert_eq!(from_f32(-2f32.powi(-26)), 0x8000);
+        // the largest subnormal rounds up into the smallest normal
+        assert_eq!(from_f32(1023.75 * 2f32.powi(-24)), 0x0400);
+    }
+
+    #[test]
+    fn ties_round_to_even() {
+        // 1 + 2^-11 is halfway between 1 and 1 + 2^-10
+        assert_eq!(from_f32(1. + 2f32.powi(-11)), 0x3c00);
+        // 1 + 3 * 2^-11 is halfway between 1 + 2^-10 and 1 + 2^-9
+        assert_eq!(from_f32(1. + 3. * 2f32.powi(-11)), 0x3c02);
+        // just above halfway rounds up
+        assert_eq!(from_f32(1. + 2f32.powi(-11) + 2f32.powi(-20)), 0x3c01);
+        // ties between subnormals, 0.5 and 1.5 units of 2^-24
+        assert_eq!(from_f32(2f32.powi(-25)), 0x0000);
+        assert_eq!(from_f32(1.5 * 2f32.powi(-24)), 0x0002);
+    }
+
+    #[test]
+    fn overflow_becomes_infinite() {
+        assert_eq!(from_f32(65520.), 0x7c00);
+        assert_eq!(from_f32(-1e10), 0xfc00);
+        assert_eq!(round_trip(std::f32::INFINITY), std::f32::INFINITY);
+        assert_eq!(round_trip(std::f32::NEG_INFINITY), std::f32::NEG_INFINITY);
+        // the largest finite value below the rounding threshold stays finite
+        assert_eq!(from_f32(65519.), 0x7bff);
+    }
+
+    #[test]
+    fn nan_stays_nan() {
+        let h = from_f32(std::f32::NAN);
+        assert_eq!(h & 0x7c00, 0x7c00);
+        assert_ne!(h & 0x3ff, 0);
+        assert!(round_trip(std::f32::NAN).is_nan());
+        assert!(to_f32(0xfe00).is_nan());
+    }
+
+    #[test]
+    fn dataset_round_trip() {
+        let path = std::env::temp_dir().join(format!("cluster-bob-f16-{}.h5", std::process::id()));
+        let x = Array2::from_shape_vec((2, 3), vec![1., -0.5, 3.140625, 65504., 1e-7, 0.]).unwrap();
+        {
+            let file = h5::File::open(&path, "w").unwrap();
+            let dset = create_dataset(&file, "data", x.dim()).unwrap();
+            write_dataset(&dset, x.view()).unwrap();
+            assert!(is_half(&dset).unwrap());
+            assert_eq!(dset.shape(), vec![2, 3]);
+            assert_eq!(
+                read_dataset(&dset).unwrap(),
+                decode(encode(x.view()).view())
+            );
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
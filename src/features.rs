@@ -0,0 +1,601 @@
+//! Reading feature matrices from HDF5 files.
+use crate::exit::{failure, Category};
+use crate::retry;
+use crate::DynResult;
+use h5::{Dataset, Datatype, File};
+use memmap::Mmap;
+use ndarray::{s, Array1, Array2, ArrayView2};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A range of feature columns, `start:end` (end exclusive).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColumnRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl FromStr for ColumnRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let (start, end) = match (parts.next(), parts.next()) {
+            (Some(start), Some(end)) => (start.trim(), end.trim()),
+            _ => {
+                return Err(format!(
+                    "invalid column range `{}`, expected `start:end`",
+                    s
+                ))
+            }
+        };
+        let start = start
+            .parse()
+            .map_err(|_| format!("invalid start of column range `{}`", s))?;
+        let end = end
+            .parse()
+            .map_err(|_| format!("invalid end of column range `{}`", s))?;
+        if start >= end {
+            return Err(format!("column range `{}` is empty", s));
+        }
+        Ok(ColumnRange { start, end })
+    }
+}
+
+impl fmt::Display for ColumnRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.start, self.end)
+    }
+}
+
+/// A feature matrix made of one or more two-dimensional data sets
+/// with the same number of rows, concatenated along their columns.
+/// Transposed data sets store one feature per column instead.
+#[derive(Debug)]
+pub struct FeatureSet {
+    dsets: Vec<Dataset>,
+    /// whether the data sets are stored as `d x n` rather than `n x d`
+    transposed: bool,
+    /// the number of rows in use, at most that of the data sets
+    rows: usize,
+    /// the range of columns in use, over the concatenation of the data sets
+    cols: Option<ColumnRange>,
+    /// the subset of columns in use, within the column range
+    dims: Option<Vec<usize>>,
+    /// the mean subtracted from every feature read
+    mean: Option<Array1<f32>>,
+    /// the file mapped to memory, for reading contiguous features in place
+    mmap: Option<Mmap>,
+    /// whether to read the next batch in the background
+    prefetch: bool,
+}
+
+/// A batch of features, either read from the file
+/// or viewed in place in a memory mapped file.
+pub enum Batch<'a> {
+    Owned(Array2<f32>),
+    Mapped(ArrayView2<'a, f32>),
+}
+
+impl<'a> Batch<'a> {
+    /// View the features of the batch.
+    pub fn view(&self) -> ArrayView2<f32> {
+        match self {
+            Batch::Owned(x) => x.view(),
+            Batch::Mapped(x) => x.view(),
+        }
+    }
+}
+
+impl FeatureSet {
+    /// Open the features in the given comma-separated list of data set paths,
+    /// which store one feature per column if `transposed`.
+    ///
+    /// The number of features is that of the data sets when opened:
+    /// features appended to extendible data sets afterwards are ignored.
+    pub fn open(file: &File, names: &str, transposed: bool) -> DynResult<Self> {
+        let row_axis = if transposed { 1 } else { 0 };
+        let mut dsets = Vec::new();
+        for name in names.split(',').map(str::trim) {
+            let dset = file.dataset(name)?;
+            if dset.ndim() != 2 {
+                return Err(failure(
+                    Category::BadInput,
+                    format!(
+                        "feature data set `{}` should be two-dimensional, found shape {:?}",
+                        name,
+                        dset.shape()
+                    ),
+                ));
+            }
+            if let Some(first) = dsets.first() {
+                let rows = first.shape()[row_axis];
+                if dset.shape()[row_axis] != rows {
+                    return Err(failure(
+                        Category::BadInput,
+                        format!(
+                            "feature data set `{}` has {} features, but {} were expected",
+                            name,
+                            dset.shape()[row_axis],
+                            rows
+                        ),
+                    ));
+                }
+            }
+            dsets.push(dset);
+        }
+        let rows = dsets[0].shape()[row_axis];
+        Ok(FeatureSet {
+            dsets,
+            transposed,
+            rows,
+            cols: None,
+            dims: None,
+            mean: None,
+            mmap: None,
+            prefetch: false,
+        })
+    }
+
+    /// The number of features.
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// The number of features currently in the data sets,
+    /// regardless of truncation.
+    pub fn total_rows(&self) -> usize {
+        self.dsets[0].shape()[self.row_axis()]
+    }
+
+    /// The axis of the data sets along which features are stored.
+    pub fn row_axis(&self) -> usize {
+        if self.transposed {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// Only use the first `n` features from now on.
+    pub fn truncate(&mut self, n: usize) {
+        self.rows = usize::min(self.rows, n);
+    }
+
+    /// The dimensionality of each feature.
+    pub fn cols(&self) -> usize {
+        match self.dims {
+            Some(ref dims) => dims.len(),
+            None => self.range_cols(),
+        }
+    }
+
+    /// The dimensionality of the column range, before the subset of columns.
+    fn range_cols(&self) -> usize {
+        match self.cols {
+            Some(range) => range.end - range.start,
+            None => self.total_cols(),
+        }
+    }
+
+    /// The dimensionality of the whole composition, before column selection.
+    fn total_cols(&self) -> usize {
+        let col_axis = 1 - self.row_axis();
+        self.dsets.iter().map(|d| d.shape()[col_axis]).sum()
+    }
+
+    /// Only use the given range of columns from now on,
+    /// which are the only ones read from the data sets.
+    pub fn select_cols(&mut self, range: ColumnRange) -> DynResult<()> {
+        if range.end > self.total_cols() {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "column range {} is out of bounds for features of {} dimensions",
+                    range,
+                    self.total_cols()
+                ),
+            ));
+        }
+        self.cols = Some(range);
+        Ok(())
+    }
+
+    /// Only use the given subset of columns from now on,
+    /// as indices within the column range, in the given order.
+    pub fn select_dims(&mut self, dims: Vec<usize>) -> DynResult<()> {
+        if let Some(&dim) = dims.iter().find(|&&dim| dim >= self.range_cols()) {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "dimension {} is out of bounds for features of {} dimensions",
+                    dim,
+                    self.range_cols()
+                ),
+            ));
+        }
+        self.dims = Some(dims);
+        Ok(())
+    }
+
+    /// Subtract `mean` from every feature read from now on.
+    pub fn center(&mut self, mean: Array1<f32>) -> DynResult<()> {
+        if mean.len() != self.cols() {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "mean vector has {} elements, but the features have {} dimensions",
+                    mean.len(),
+                    self.cols()
+                ),
+            ));
+        }
+        self.mean = Some(mean);
+        Ok(())
+    }
+
+    /// The first data set of the composition.
+    pub fn primary(&self) -> &Dataset {
+        &self.dsets[0]
+    }
+
+    /// Read the features in the rows `begin..end`.
+    pub fn read_rows(&self, begin: usize, end: usize) -> h5::Result<Array2<f32>> {
+        let mut rows = Array2::zeros((0, 0));
+        self.read_rows_into(begin, end, &mut rows)?;
+        Ok(rows)
+    }
+
+    /// Read the features in the rows `begin..end` into `out`, which is only
+    /// reallocated if it does not have their shape already, so that the same
+    /// buffer can be reused from one batch to the next.
+    ///
+    /// The HDF5 binding still reads each data set into a buffer of its own,
+    /// from which the columns in use are copied.
+    pub fn read_rows_into(
+        &self,
+        begin: usize,
+        end: usize,
+        out: &mut Array2<f32>,
+    ) -> h5::Result<()> {
+        let shape = (end - begin, self.cols());
+        if out.dim() != shape {
+            *out = Array2::zeros(shape);
+        }
+        let range = self.cols.unwrap_or(ColumnRange {
+            start: 0,
+            end: self.total_cols(),
+        });
+        // read the part of the column range within each data set,
+        // `col` being where it starts within the range
+        let mut offset = 0;
+        let mut col = 0;
+        for dset in &self.dsets {
+            let width = dset.shape()[1 - self.row_axis()];
+            let start = usize::max(range.start, offset);
+            let stop = usize::min(range.end, offset + width);
+            if start < stop {
+                let (start, stop) = (start - offset, stop - offset);
+                // a transposed block of columns is laid out row by row as it is copied
+                let part = if self.transposed {
+                    retry::read(|| dset.read_slice_2d::<f32, _>(s![start..stop, begin..end]))?
+                        .reversed_axes()
+                } else {
+                    retry::read(|| dset.read_slice_2d::<f32, _>(s![begin..end, start..stop]))?
+                };
+                let part_cols = col..col + (stop - start);
+                match self.dims {
+                    Some(ref dims) => {
+                        for (j, &dim) in dims.iter().enumerate() {
+                            if part_cols.contains(&dim) {
+                                out.column_mut(j).assign(&part.column(dim - col));
+                            }
+                        }
+                    }
+                    None => out
+                        .slice_mut(s![.., part_cols.start..part_cols.end])
+                        .assign(&part),
+                }
+                col = part_cols.end;
+            }
+            offset += width;
+        }
+        if let Some(ref mean) = self.mean {
+            *out -= mean;
+        }
+        Ok(())
+    }
+
+    /// Load the first `n` features to memory, `batch_size` rows at a time,
+    /// calling `tick_fn` with the number of rows read after each batch.
+    pub fn load<F>(&self, n: usize, batch_size: usize, tick_fn: F) -> h5::Result<Array2<f32>>
+    where
+        F: Fn(usize),
+    {
+        let mut out = Array2::zeros((n, self.cols()));
+        for begin in (0..n).step_by(batch_size) {
+            let end = usize::min(begin + batch_size, n);
+            let batch = self.read_rows(begin, end)?;
+            out.slice_mut(s![begin..end, ..]).assign(&batch);
+            tick_fn(end - begin);
+        }
+        Ok(out)
+    }
+
+    /// Load up to `n` features to memory, keeping those for which `keep_fn`
+    /// returns true, as called with each row index in increasing order.
+    /// `tick_fn` is called with the number of rows read after each batch.
+    pub fn load_where<K, F>(
+        &self,
+        n: usize,
+        batch_size: usize,
+        mut keep_fn: K,
+        tick_fn: F,
+    ) -> DynResult<Array2<f32>>
+    where
+        K: FnMut(usize) -> bool,
+        F: Fn(usize),
+    {
+        let mut values = Vec::new();
+        let mut rows = 0;
+        for begin in (0..self.rows()).step_by(batch_size) {
+            if rows == n {
+                break;
+            }
+            let end = usize::min(begin + batch_size, self.rows());
+            let batch = self.read_rows(begin, end)?;
+            for (i, feature) in batch.outer_iter().enumerate() {
+                if rows < n && keep_fn(begin + i) {
+                    values.extend(feature.iter().cloned());
+                    rows += 1;
+                }
+            }
+            tick_fn(end - begin);
+        }
+        Ok(Array2::from_shape_vec((rows, self.cols()), values)?)
+    }
+
+    /// Load up to `n` features to memory, taking no more than the first `cap`
+    /// features of each item, with the item of each feature in `id_slice_dset`.
+    /// `tick_fn` is called with the number of rows read after each batch.
+    pub fn load_per_item<F>(
+        &self,
+        id_slice_dset: &Dataset,
+        cap: usize,
+        n: usize,
+        batch_size: usize,
+        tick_fn: F,
+    ) -> DynResult<Array2<f32>>
+    where
+        F: Fn(usize),
+    {
+        if id_slice_dset.shape()[0] < self.rows() {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "item ID data set has {} elements, but there are {} features",
+                    id_slice_dset.shape()[0],
+                    self.rows()
+                ),
+            ));
+        }
+        let d = self.cols();
+        let mut taken: HashMap<u32, usize> = HashMap::new();
+        let mut values = Vec::new();
+        let mut rows = 0;
+        'batches: for begin in (0..self.rows()).step_by(batch_size) {
+            let end = usize::min(begin + batch_size, self.rows());
+            let batch = self.read_rows(begin, end)?;
+            let ids = retry::read(|| id_slice_dset.read_slice_1d::<u32, _>(s![begin..end]))?;
+            for (feature, id) in batch.outer_iter().zip(&ids) {
+                let count = taken.entry(*id).or_insert(0);
+                if *count >= cap {
+                    continue;
+                }
+                *count += 1;
+                values.extend(feature.iter().cloned());
+                rows += 1;
+                if rows == n {
+                    tick_fn(end - begin);
+                    break 'batches;
+                }
+            }
+            tick_fn(end - begin);
+        }
+        Ok(Array2::from_shape_vec((rows, d), values)?)
+    }
+
+    /// Map the file to memory, so that batches are viewed in place
+    /// rather than read. This is only possible for a single data set
+    /// of 32-bit floats stored contiguously and not transposed,
+    /// with all columns and no centering.
+    /// Returns whether the file was mapped.
+    pub fn map_to_memory(&mut self) -> DynResult<bool> {
+        if self.dsets.len() != 1
+            || self.transposed
+            || self.cols.is_some()
+            || self.dims.is_some()
+            || self.mean.is_some()
+        {
+            return Ok(false);
+        }
+        let dset = &self.dsets[0];
+        if dset.is_chunked() || dset.dtype()? != Datatype::from_type::<f32>()? {
+            return Ok(false);
+        }
+        let offset = match dset.offset() {
+            // floats in the mapping must be aligned
+            Some(offset) if offset % 4 == 0 => offset as usize,
+            _ => return Ok(false),
+        };
+        let file = std::fs::File::open(dset.filename())?;
+        // the mapping is read-only, and the file is not expected
+        // to be modified while features are read from it
+        let mmap = unsafe { Mmap::map(&file)? };
+        if mmap.len() < offset + dset.size() * 4 {
+            return Ok(false);
+        }
+        self.mmap = Some(mmap);
+        Ok(true)
+    }
+
+    /// Read the next batch in a background thread while the current one
+    /// is processed, when going through the batches with `for_each_batch`.
+    /// Features mapped to memory are viewed in place and never prefetched.
+    pub fn prefetch(&mut self, prefetch: bool) {
+        self.prefetch = prefetch;
+    }
+
+    /// Whether `for_each_batch` reads the next batch in the background,
+    /// as requested and unless the features are mapped to memory.
+    pub fn prefetches(&self) -> bool {
+        self.prefetch && self.mmap.is_none()
+    }
+
+    /// The number of batches held in memory at once by `for_each_batch`.
+    pub fn batches_held(&self) -> usize {
+        if self.prefetches() {
+            3
+        } else {
+            1
+        }
+    }
+
+    /// View the features in the rows `begin..end` in the memory mapped file.
+    fn view_rows(&self, mmap: &Mmap, begin: usize, end: usize) -> ArrayView2<f32> {
+        let dset = &self.dsets[0];
+        let offset = dset.offset().expect("mapped data set should be contiguous") as usize;
+        let d = dset.shape()[1];
+        let bytes = &mmap[offset + begin * d * 4..offset + end * d * 4];
+        // alignment and bounds were checked when mapping the file
+        let values =
+            unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const f32, (end - begin) * d) };
+        ArrayView2::from_shape((end - begin, d), values).expect("batch shape should be consistent")
+    }
+
+    /// Read (or view, if mapped to memory) the features in the rows `begin..end`.
+    pub fn batch(&self, begin: usize, end: usize) -> h5::Result<Batch> {
+        match self.mmap {
+            Some(ref mmap) => Ok(Batch::Mapped(self.view_rows(mmap, begin, end))),
+            None => Ok(Batch::Owned(self.read_rows(begin, end)?)),
+        }
+    }
+
+    /// View the features in the rows `begin..end` if mapped to memory,
+    /// and otherwise read them into `buffer`, as in `read_rows_into`.
+    pub fn batch_into<'b>(
+        &'b self,
+        begin: usize,
+        end: usize,
+        buffer: &'b mut Array2<f32>,
+    ) -> h5::Result<ArrayView2<'b, f32>> {
+        match self.mmap {
+            Some(ref mmap) => Ok(self.view_rows(mmap, begin, end)),
+            None => {
+                self.read_rows_into(begin, end, buffer)?;
+                Ok(buffer.view())
+            }
+        }
+    }
+
+    /// Iterate over the features in batches of `batch_size` rows.
+    pub fn batches(&self, batch_size: usize) -> impl Iterator<Item = h5::Result<Batch>> + '_ {
+        let total = self.rows();
+        let batch_offset = total % batch_size;
+        let nbatches = total / batch_size + if batch_offset > 0 { 1 } else { 0 };
+
+        (0..nbatches).map(move |i| {
+            let begin = i * batch_size;
+            let end = usize::min(begin + batch_size, total);
+            self.batch(begin, end)
+        })
+    }
+
+    /// Call `f` with each batch of `batch_size` features in turn, until it
+    /// returns `false`. Batches are viewed in place if mapped to memory,
+    /// and otherwise read into buffers reused from one batch to the next.
+    /// With prefetching, the next batch is read in the background while
+    /// `f` is called, so that up to three batches are held at once:
+    /// the current one, the next one and the one being read after it.
+    /// Returns the time spent waiting for batches to be read.
+    pub fn for_each_batch<F>(&self, batch_size: usize, mut f: F) -> DynResult<Duration>
+    where
+        F: FnMut(ArrayView2<f32>) -> DynResult<bool>,
+    {
+        let total = self.rows();
+        let mut waited = Duration::default();
+        if let Some(ref mmap) = self.mmap {
+            for begin in (0..total).step_by(batch_size) {
+                let end = usize::min(begin + batch_size, total);
+                if !f(self.view_rows(mmap, begin, end))? {
+                    break;
+                }
+            }
+            return Ok(waited);
+        }
+        if !self.prefetch {
+            let mut buffer = Array2::zeros((0, 0));
+            for begin in (0..total).step_by(batch_size) {
+                let end = usize::min(begin + batch_size, total);
+                let start = Instant::now();
+                self.read_rows_into(begin, end, &mut buffer)?;
+                waited += start.elapsed();
+                if !f(buffer.view())? {
+                    break;
+                }
+            }
+            return Ok(waited);
+        }
+        // read batches go to `f` through one channel,
+        // and their buffers come back to the reader through the other
+        let (full_sender, full_receiver) = mpsc::sync_channel(1);
+        let (empty_sender, empty_receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                for begin in (0..total).step_by(batch_size) {
+                    let end = usize::min(begin + batch_size, total);
+                    let mut buffer = empty_receiver
+                        .try_recv()
+                        .unwrap_or_else(|_| Array2::zeros((0, 0)));
+                    let batch = self
+                        .read_rows_into(begin, end, &mut buffer)
+                        .map(|()| buffer);
+                    let failed = batch.is_err();
+                    if full_sender.send(batch).is_err() || failed {
+                        break;
+                    }
+                }
+            });
+            let mut result = Ok(());
+            loop {
+                let start = Instant::now();
+                let batch = match full_receiver.recv() {
+                    Ok(batch) => batch,
+                    Err(_) => break,
+                };
+                waited += start.elapsed();
+                let go = batch.map_err(Into::into).and_then(|batch| {
+                    let go = f(batch.view());
+                    // the reader may have finished already
+                    let _ = empty_sender.send(batch);
+                    go
+                });
+                match go {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            // stop the reader if not all batches were processed
+            drop(full_receiver);
+            result.map(|()| waited)
+        })
+    }
+}
@@ -0,0 +1,358 @@
+//! Gaussian mixture models with diagonal covariances,
+//! and Fisher vector encoding on top of them.
+use crate::exit::{failure, Category};
+use crate::DynResult;
+use ndarray::{s, Array1, Array2, ArrayView2};
+use std::f32::consts::PI;
+
+/// The smallest variance allowed in any component dimension,
+/// which keeps degenerate components from collapsing.
+const VARIANCE_FLOOR: f32 = 1e-6;
+
+/// The number of features for which posteriors are computed at once.
+const CHUNK_SIZE: usize = 1024;
+
+/// A Gaussian mixture model with diagonal covariance matrices.
+#[derive(Debug, Clone)]
+pub struct Gmm {
+    /// The component means, `k x d`
+    pub means: Array2<f32>,
+    /// The diagonal of each component's covariance matrix, `k x d`
+    pub variances: Array2<f32>,
+    /// The mixture weights, of length `k`
+    pub weights: Array1<f32>,
+}
+
+impl Gmm {
+    /// Initialize a mixture from a hard k-means partition of the features.
+    ///
+    /// Components without enough assigned features to estimate a variance
+    /// take the variance of the whole sample instead.
+    /// Fails if there are no features, or if they, their labels and the
+    /// centroids do not match.
+    pub fn from_kmeans(
+        features: ArrayView2<f32>,
+        centroids: ArrayView2<f32>,
+        labels: &[i64],
+    ) -> DynResult<Self> {
+        let (k, d) = centroids.dim();
+        let n = features.rows();
+        if n == 0 {
+            return Err(failure(
+                Category::EmptyData,
+                "cannot fit a mixture model to no features",
+            ));
+        }
+        if features.cols() != d {
+            return Err(failure(
+                Category::DimensionMismatch,
+                format!(
+                    "features have {} dimensions, but the centroids have {}",
+                    features.cols(),
+                    d
+                ),
+            ));
+        }
+        if labels.len() != n {
+            return Err(failure(
+                Category::BadInput,
+                format!("{} labels were given for {} features", labels.len(), n),
+            ));
+        }
+        if let Some(&label) = labels.iter().find(|&&label| label >= k as i64) {
+            return Err(failure(
+                Category::BadInput,
+                format!("label {} is out of bounds for {} centroids", label, k),
+            ));
+        }
+
+        let mut global_mean = vec![0_f64; d];
+        let mut global_sq = vec![0_f64; d];
+        let mut counts = vec![0_u32; k];
+        let mut variances = Array2::<f32>::zeros((k, d));
+        for (x, &label) in features.outer_iter().zip(labels) {
+            for j in 0..d {
+                let v = f64::from(x[j]);
+                global_mean[j] += v;
+                global_sq[j] += v * v;
+            }
+            if label < 0 {
+                continue;
+            }
+            let c = label as usize;
+            counts[c] += 1;
+            let mu = centroids.row(c);
+            let mut var = variances.row_mut(c);
+            for j in 0..d {
+                let diff = x[j] - mu[j];
+                var[j] += diff * diff;
+            }
+        }
+
+        let global_var: Vec<f32> = (0..d)
+            .map(|j| {
+                let mean = global_mean[j] / n as f64;
+                (global_sq[j] / n as f64 - mean * mean) as f32
+            })
+            .collect();
+
+        for (c, mut var) in variances.outer_iter_mut().enumerate() {
+            if counts[c] < 2 {
+                for j in 0..d {
+                    var[j] = global_var[j];
+                }
+            } else {
+                let count = counts[c] as f32;
+                var.mapv_inplace(|v| v / count);
+            }
+            var.mapv_inplace(|v| v.max(VARIANCE_FLOOR));
+        }
+
+        let total: u32 = counts.iter().sum();
+        let weights = counts
+            .iter()
+            .map(|&c| c as f32 / total.max(1) as f32)
+            .collect();
+
+        Ok(Gmm {
+            means: centroids.to_owned(),
+            variances,
+            weights,
+        })
+    }
+
+    /// The number of mixture components.
+    pub fn k(&self) -> usize {
+        self.means.rows()
+    }
+
+    /// The dimensionality of the modelled features.
+    pub fn d(&self) -> usize {
+        self.means.cols()
+    }
+
+    /// Compute the posterior probability of each component (`n x k`)
+    /// for each of the given features (`n x d`).
+    pub fn posteriors(&self, features: ArrayView2<f32>) -> Array2<f32> {
+        self.posteriors_with_loglik(features).0
+    }
+
+    /// Compute the posteriors of each component for each feature,
+    /// as well as the total log-likelihood of the features.
+    fn posteriors_with_loglik(&self, features: ArrayView2<f32>) -> (Array2<f32>, f64) {
+        let (k, d) = self.means.dim();
+        // constant part of each component's weighted log density
+        let log_norm: Vec<f32> = (0..k)
+            .map(|c| {
+                let log_det: f32 = self.variances.row(c).iter().map(|v| v.ln()).sum();
+                self.weights[c].ln() - 0.5 * (d as f32 * (2. * PI).ln() + log_det)
+            })
+            .collect();
+
+        let mut loglik = 0_f64;
+        let mut out = Array2::<f32>::zeros((features.rows(), k));
+        for (x, mut gamma) in features.outer_iter().zip(out.outer_iter_mut()) {
+            for c in 0..k {
+                let mu = self.means.row(c);
+                let var = self.variances.row(c);
+                let mut dist = 0.;
+                for j in 0..d {
+                    let diff = x[j] - mu[j];
+                    dist += diff * diff / var[j];
+                }
+                gamma[c] = log_norm[c] - 0.5 * dist;
+            }
+
+            let max = gamma.iter().cloned().fold(std::f32::NEG_INFINITY, f32::max);
+            if !max.is_finite() {
+                gamma.fill(1. / k as f32);
+                continue;
+            }
+            let mut total = 0.;
+            for g in gamma.iter_mut() {
+                *g = (*g - max).exp();
+                total += *g;
+            }
+            for g in gamma.iter_mut() {
+                *g /= total;
+            }
+            loglik += f64::from(max + total.ln());
+        }
+        (out, loglik)
+    }
+
+    /// Perform one expectation-maximization iteration over the features,
+    /// returning the mean log-likelihood of the features prior to the update.
+    pub fn em_step(&mut self, features: ArrayView2<f32>) -> f64 {
+        let (k, d) = self.means.dim();
+        let n = features.rows();
+
+        let mut resp = vec![0_f64; k];
+        let mut sum_x = Array2::<f64>::zeros((k, d));
+        let mut sum_x2 = Array2::<f64>::zeros((k, d));
+        let mut loglik = 0_f64;
+        for begin in (0..n).step_by(CHUNK_SIZE) {
+            let end = usize::min(begin + CHUNK_SIZE, n);
+            let chunk = features.slice(s![begin..end, ..]);
+            let (gamma, chunk_loglik) = self.posteriors_with_loglik(chunk);
+            loglik += chunk_loglik;
+
+            for (x, g) in chunk.outer_iter().zip(gamma.outer_iter()) {
+                for c in 0..k {
+                    let gc = f64::from(g[c]);
+                    if gc == 0. {
+                        continue;
+                    }
+                    resp[c] += gc;
+                    let mut sx = sum_x.row_mut(c);
+                    let mut sx2 = sum_x2.row_mut(c);
+                    for j in 0..d {
+                        let v = f64::from(x[j]);
+                        sx[j] += gc * v;
+                        sx2[j] += gc * v * v;
+                    }
+                }
+            }
+        }
+
+        for c in 0..k {
+            if resp[c] <= 0. {
+                // no responsibility, keep the component as is
+                continue;
+            }
+            self.weights[c] = (resp[c] / n as f64) as f32;
+            for j in 0..d {
+                let mean = sum_x[[c, j]] / resp[c];
+                let var = sum_x2[[c, j]] / resp[c] - mean * mean;
+                self.means[[c, j]] = mean as f32;
+                self.variances[[c, j]] = (var as f32).max(VARIANCE_FLOOR);
+            }
+        }
+
+        loglik / n as f64
+    }
+}
+
+/// Accumulates the Fisher vectors of several items at once.
+///
+/// Each item is encoded as the normalized gradient of the features'
+/// log-likelihood with respect to the component means (`k * d` values),
+/// followed by the gradient with respect to the standard deviations
+/// (another `k * d` values). No power or L2 normalization is applied.
+#[derive(Debug)]
+pub struct FisherEncoder<'a> {
+    gmm: &'a Gmm,
+    std_devs: Array2<f32>,
+    /// gradient statistics, one row of `2 * k * d` per item
+    stats: Array2<f32>,
+    /// number of features seen per item
+    counts: Vec<u32>,
+}
+
+impl<'a> FisherEncoder<'a> {
+    /// Create an encoder of `n_items` items.
+    pub fn new(gmm: &'a Gmm, n_items: usize) -> Self {
+        FisherEncoder {
+            gmm,
+            std_devs: gmm.variances.mapv(f32::sqrt),
+            stats: Array2::zeros((n_items, 2 * gmm.k() * gmm.d())),
+            counts: vec![0; n_items],
+        }
+    }
+
+    /// Accumulate a batch of features,
+    /// each one belonging to the item in the respective position of `items`.
+    /// Fails at the first item out of bounds.
+    pub fn add<I>(&mut self, features: ArrayView2<f32>, items: I) -> DynResult<()>
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        let (k, d) = self.gmm.means.dim();
+        let gamma = self.gmm.posteriors(features);
+        for ((x, g), item) in features.outer_iter().zip(gamma.outer_iter()).zip(items) {
+            if item >= self.counts.len() {
+                return Err(failure(
+                    Category::BadInput,
+                    format!(
+                        "item ID {} is out of bounds for {} items",
+                        item,
+                        self.counts.len()
+                    ),
+                ));
+            }
+            self.counts[item] += 1;
+            let mut row = self.stats.row_mut(item);
+            for c in 0..k {
+                let gc = g[c];
+                if gc == 0. {
+                    continue;
+                }
+                let mu = self.gmm.means.row(c);
+                let sigma = self.std_devs.row(c);
+                for j in 0..d {
+                    let z = (x[j] - mu[j]) / sigma[j];
+                    row[c * d + j] += gc * z;
+                    row[(k + c) * d + j] += gc * (z * z - 1.);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Normalize the accumulated statistics into the final Fisher vectors,
+    /// one row of `2 * k * d` per item.
+    pub fn finish(mut self) -> Array2<f32> {
+        let (k, d) = self.gmm.means.dim();
+        for (item, mut row) in self.stats.outer_iter_mut().enumerate() {
+            let t = self.counts[item] as f32;
+            if t == 0. {
+                continue;
+            }
+            for c in 0..k {
+                let w = self.gmm.weights[c];
+                let (scale_mu, scale_sigma) = if w > 0. {
+                    (1. / (t * w.sqrt()), 1. / (t * (2. * w).sqrt()))
+                } else {
+                    (0., 0.)
+                };
+                for j in 0..d {
+                    row[c * d + j] *= scale_mu;
+                    row[(k + c) * d + j] *= scale_sigma;
+                }
+            }
+        }
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exit::code;
+    use ndarray::arr2;
+
+    #[test]
+    fn from_kmeans_checks_its_inputs() {
+        let centroids = arr2(&[[0., 0.], [1., 1.]]);
+        let features = arr2(&[[0., 0.1], [0.9, 1.], [1., 1.1]]);
+        let empty = Array2::<f32>::zeros((0, 2));
+        match Gmm::from_kmeans(empty.view(), centroids.view(), &[]) {
+            Err(e) => assert_eq!(code(&*e), 5),
+            Ok(_) => panic!("an empty sample should be rejected"),
+        }
+        assert!(Gmm::from_kmeans(features.view(), centroids.view(), &[0, 1]).is_err());
+        assert!(Gmm::from_kmeans(features.view(), centroids.view(), &[0, 1, 2]).is_err());
+        let gmm = Gmm::from_kmeans(features.view(), centroids.view(), &[0, 1, -1]).unwrap();
+        assert_eq!(gmm.weights, Array1::from_vec(vec![0.5, 0.5]));
+    }
+
+    #[test]
+    fn add_rejects_items_out_of_bounds() {
+        let centroids = arr2(&[[0., 0.], [1., 1.]]);
+        let features = arr2(&[[0., 0.1], [0.9, 1.]]);
+        let gmm = Gmm::from_kmeans(features.view(), centroids.view(), &[0, 1]).unwrap();
+        let mut encoder = FisherEncoder::new(&gmm, 2);
+        assert!(encoder.add(features.view(), vec![0, 1]).is_ok());
+        assert!(encoder.add(features.view(), vec![1, 2]).is_err());
+    }
+}
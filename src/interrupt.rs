@@ -0,0 +1,36 @@
+//! Handling of interruptions (Ctrl-C), so that long running stages
+//! can stop early and save what they have done so far.
+use crate::DynResult;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether an interruption was requested.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the current stage can stop early on an interruption,
+/// rather than the process exiting right away.
+static DEFERRED: AtomicBool = AtomicBool::new(false);
+
+/// Install the interruption handler.
+///
+/// Outside of deferred stages, an interruption exits the process.
+/// Within them, the first interruption is only recorded,
+/// and a second one exits the process.
+pub fn install() -> DynResult<()> {
+    ctrlc::set_handler(|| {
+        if !DEFERRED.load(Ordering::SeqCst) || INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted, saving partial results (press Ctrl-C again to abort) ...");
+    })?;
+    Ok(())
+}
+
+/// Set whether the current stage can stop early on an interruption.
+pub fn set_deferred(deferred: bool) {
+    DEFERRED.store(deferred, Ordering::SeqCst);
+}
+
+/// Check whether an interruption was requested during a deferred stage.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,7 @@
+//! Assignment of features to codewords and histograms of codeword counts,
+//! as used by the `cluster-bob` command line tool.
+pub mod assign;
+pub mod quantizer;
+
+/// A result with any kind of error.
+pub type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
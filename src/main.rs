@@ -1,15 +1,74 @@
 use faiss::cluster::{Clustering, ClusteringParameters};
 use faiss::{FlatIndex, Index};
-use h5::{Dataset, File};
 use h5::types::VarLenUnicode;
+use h5::{Dataset, Datatype, File, Group, H5Type, Location};
 use indicatif::{ProgressBar, ProgressStyle};
-use ndarray::{s, Array1, Array2, ArrayView2, Axis};
-use std::path::PathBuf;
+use ndarray::{s, Array1, Array2, ArrayView1, ArrayView2, ArrayViewMut2, Axis, Zip};
+use serde_json::Value;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
+#[macro_use]
+mod status;
+
+mod exit;
+mod f16;
+mod features;
+mod gmm;
+mod interrupt;
+mod npy;
+mod output;
+mod remote;
+mod retry;
+mod stream;
+
+use crate::exit::{failure, Category};
+use crate::features::{ColumnRange, FeatureSet};
+use crate::gmm::{FisherEncoder, Gmm};
+use crate::npy::{write_npy, NpyElement};
+use crate::stream::FeatureStream;
+use cluster_bob::assign::{
+    codebook_index, nearest, nearest_with_distances, Assigner, CodewordStats,
+};
+use cluster_bob::quantizer::{Count, Quantizer};
+
 type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
 
+/// The version of the format of the bags files written by `quantize`
+const FORMAT_VERSION: u32 = 1;
+
+/// The default number of features read and assigned at once
+const BATCH_SIZE: usize = 1024;
+/// The bounds of batch sizes chosen to fit `--limit-memory`
+const MIN_BATCH_SIZE: usize = 64;
+const MAX_BATCH_SIZE: usize = 1 << 20;
+
+/// The default group path of the item names, in features and bags files
+const ITEM_NAME: &str = "id_volume";
+
+/// The fraction of features without a valid assignment
+/// above which the user is warned
+const UNASSIGNED_WARN_FRACTION: f64 = 0.1;
+
+/// The number of iterations run after re-seeding empty clusters
+const RECLUSTER_NITER: u32 = 5;
+
 #[derive(Debug, StructOpt)]
+#[structopt(
+    after_help = "EXIT CODES:\n    0    success\n    1    other errors, including invalid usage\n    \
+                  2    invalid input data or settings\n    \
+                  3    features and codebooks of incompatible dimensions\n    \
+                  4    failure to read or write a file\n    \
+                  5    nothing to work on, such as an empty training sample"
+)]
 enum App {
     /// Generate a feature vocabulary
     #[structopt(name = "vocabulary")]
@@ -17,19 +76,59 @@ enum App {
     /// Generate bags of features
     #[structopt(name = "quantize", alias = "bows")]
     Quantize(QuantizeArgs),
+    /// Generate a feature vocabulary and bags of features over it
+    #[structopt(name = "pipeline")]
+    Pipeline(PipelineArgs),
+    /// Assign each feature to its nearest codeword
+    #[structopt(name = "assign")]
+    Assign(AssignArgs),
+    /// Check that a bags file is consistent with a vocabulary
+    #[structopt(name = "verify")]
+    Verify(VerifyArgs),
+    /// Evaluate how well a vocabulary fits a sample of features
+    #[structopt(name = "evaluate")]
+    Evaluate(EvaluateArgs),
+    /// Search a database of bags for the nearest bags to each query
+    #[structopt(name = "search")]
+    Search(SearchArgs),
+    /// Convert a file of dense bags to the sparse layout, or back
+    #[structopt(name = "sparsify")]
+    Sparsify(SparsifyArgs),
 }
 
 #[derive(Debug, StructOpt)]
 pub struct VocabularyArgs {
-    /// The hdf5 file containing the features
+    /// The hdf5 file containing the features (or an `http(s)://` or `s3://` URL to it)
     #[structopt(name = "FEATURES", parse(from_os_str))]
     features: PathBuf,
-    /// Group path where the features are
+    /// The directory where features given by URL are downloaded
+    /// (defaults to a `cluster-bob` directory in the system's temporary directory)
+    #[structopt(long = "cache-dir", parse(from_os_str))]
+    cache_dir: Option<PathBuf>,
+    /// The format of the features file: `hdf5`, or `npy` for a
+    /// two-dimensional NumPy array of floats
+    #[structopt(
+        long = "input-format",
+        default_value = "hdf5",
+        raw(possible_values = r#"&["hdf5", "npy"]"#)
+    )]
+    input_format: InputFormat,
+    /// Print the paths of all data sets in the features file and exit
+    #[structopt(long = "list-datasets", alias = "list")]
+    list_datasets: bool,
+    /// Group path where the features are (a comma-separated list of paths is concatenated)
     #[structopt(long = "name", default_value = "data")]
     dataset_name: String,
-    /// The size of the codebook
+    /// Only use the columns `start:end` of the features (end exclusive)
+    #[structopt(long = "feature-cols")]
+    feature_cols: Option<ColumnRange>,
+    /// The features are stored transposed, one feature per column (`d x n`)
+    #[structopt(long = "features-transpose")]
+    features_transpose: bool,
+    /// The size of the codebook, or `sqrt` (the square root of the number
+    /// of training features) or `auto` (the square root of half of them)
     #[structopt(short = "k", long = "size")]
-    size: u32,
+    size: CodebookSize,
     /// The hdf5 file to store the k centroids
     #[structopt(
         short = "o",
@@ -41,9 +140,222 @@ pub struct VocabularyArgs {
     /// Only use `n` features for clustering
     #[structopt(short = "N")]
     n: Option<usize>,
+    /// Only use this fraction of the features for clustering (e.g. 0.1)
+    #[structopt(long = "sample-fraction", raw(conflicts_with = r#""n""#))]
+    sample_fraction: Option<f32>,
+    /// Train on the rows listed in this text file, one row index per line,
+    /// instead of picking a sample
+    #[structopt(
+        long = "sample-file",
+        parse(from_os_str),
+        raw(
+            conflicts_with_all = r#"&["n", "sample_fraction", "per_item_cap", "coarse_then_fine"]"#
+        )
+    )]
+    sample_file: Option<PathBuf>,
+    /// How the features for clustering are picked: the `first` ones,
+    /// or a uniform `random` sample (see `--seed`)
+    #[structopt(
+        long = "sample",
+        default_value = "first",
+        raw(
+            possible_values = r#"&["first", "random"]"#,
+            conflicts_with = r#""per_item_cap""#
+        )
+    )]
+    sample: Sampling,
+    /// Remove exact duplicates from the training sample before clustering
+    #[structopt(long = "dedup", alias = "dedup-features")]
+    dedup: bool,
+    /// Only use the first `n` features of each item for clustering,
+    /// so that items with many features do not dominate the codebook
+    #[structopt(long = "per-item-cap")]
+    per_item_cap: Option<usize>,
+    /// Group path where the item IDs are defined for each feature
+    /// (only read with `--per-item-cap`)
+    #[structopt(long = "item_id", alias = "id_slice", default_value = "item_id")]
+    item_id: String,
+    /// The distance between features and codewords
+    /// (only `l2` is supported by the faiss binding in use)
+    #[structopt(
+        long = "metric",
+        default_value = "l2",
+        raw(possible_values = r#"&["l2", "l1", "linf"]"#)
+    )]
+    metric: Metric,
+    /// A preset of k-means parameters, overridden by any of them given explicitly
+    #[structopt(
+        long = "quality",
+        raw(possible_values = r#"&["fast", "balanced", "best"]"#)
+    )]
+    quality: Option<Quality>,
     /// Number of k-means clustering iterations
     #[structopt(long = "niter")]
     niter: Option<u32>,
+    /// Number of k-means runs, keeping the best one
+    #[structopt(long = "nredo")]
+    nredo: Option<u32>,
+    /// Maximum number of training features per centroid,
+    /// above which the sample is subsampled
+    #[structopt(long = "max-points-per-centroid")]
+    max_points_per_centroid: Option<u32>,
+    /// Stop k-means early once the relative improvement of the objective
+    /// drops below this tolerance (`niter` remains the maximum)
+    #[structopt(long = "tol")]
+    tol: Option<f32>,
+    /// Fail without saving the vocabulary if the final k-means objective
+    /// is above this threshold
+    #[structopt(long = "max-objective")]
+    max_objective: Option<f32>,
+    /// Also print the mean objective per training point,
+    /// comparable across sample sizes
+    #[structopt(long = "report-objective-per-point")]
+    report_objective_per_point: bool,
+    /// Fail without saving the vocabulary if the fraction of clusters
+    /// without any training feature is above this threshold
+    #[structopt(long = "max-empty-frac", raw(conflicts_with = r#""pq""#))]
+    max_empty_frac: Option<f64>,
+    /// After training, re-seed empty clusters by splitting the largest ones,
+    /// and continue training for a few more iterations
+    #[structopt(long = "recluster-empty", raw(conflicts_with = r#""pq""#))]
+    recluster_empty: bool,
+    /// After training, merge centroids closer than this L2 distance into
+    /// their mean, which shrinks the codebook
+    #[structopt(
+        long = "merge-threshold",
+        alias = "reassign-threshold",
+        raw(conflicts_with = r#""pq""#)
+    )]
+    merge_threshold: Option<f32>,
+    /// Keep the centroids of this vocabulary fixed, and only train the
+    /// remaining k minus as many centroids, which come after them
+    #[structopt(
+        long = "freeze",
+        parse(from_os_str),
+        raw(
+            conflicts_with_all = r#"&["pq", "tol", "recluster_empty", "coarse_then_fine", "center", "merge_threshold", "gmm"]"#
+        )
+    )]
+    freeze: Option<PathBuf>,
+    /// Append the timestamp, k, niter, seed and final objective of the run
+    /// to this CSV file, created with a header on first use
+    #[structopt(
+        long = "objective-log",
+        alias = "append-objectives",
+        parse(from_os_str)
+    )]
+    objective_log: Option<PathBuf>,
+    /// Let faiss print its own training logs to stderr
+    #[structopt(long = "faiss-verbose")]
+    faiss_verbose: bool,
+    /// Fit a Gaussian mixture model with diagonal covariances after k-means
+    #[structopt(long = "gmm")]
+    gmm: bool,
+    /// Number of expectation-maximization iterations of the mixture model
+    #[structopt(long = "gmm-niter", default_value = "5")]
+    gmm_niter: u32,
+    /// Build a product quantization codebook of `m` sub-codebooks,
+    /// each clustering its own slice of `d/m` dimensions into k codewords
+    #[structopt(long = "pq", raw(conflicts_with = r#""gmm""#))]
+    pq: Option<u32>,
+    /// Write the cluster of each training feature to the `assignments`
+    /// data set of the given hdf5 file
+    #[structopt(
+        long = "write-assignments",
+        parse(from_os_str),
+        raw(conflicts_with = r#""pq""#)
+    )]
+    write_assignments: Option<PathBuf>,
+    /// Cluster the features this many at a time into k centroids each, and then
+    /// the pooled centroids into the final k, without loading all features at once
+    #[structopt(
+        long = "coarse-then-fine",
+        raw(
+            conflicts_with_all = r#"&["pq", "gmm", "center", "dedup", "per_item_cap", "write_assignments"]"#
+        )
+    )]
+    coarse_then_fine: Option<usize>,
+    /// The order of the saved centroids: as produced by faiss, or `sorted`
+    /// by increasing norm (then by their coordinates), for comparable codebooks
+    #[structopt(
+        long = "centroid-order",
+        default_value = "faiss",
+        raw(
+            possible_values = r#"&["faiss", "sorted"]"#,
+            conflicts_with = r#""pq""#
+        )
+    )]
+    centroid_order: CentroidOrder,
+    /// How the centroids are initialized: `random` training features,
+    /// as picked by faiss, or `kmeanspp` for k-means++ seeding (see `--seed`)
+    #[structopt(
+        long = "seed-init",
+        default_value = "random",
+        raw(possible_values = r#"&["random", "kmeanspp"]"#)
+    )]
+    seed_init: SeedInit,
+    /// Scale each saved centroid to unit L2 norm after training, so that
+    /// inner products with normalized features are cosine similarities
+    /// (centroids are trained as usual, and the k-means objective is unchanged)
+    #[structopt(
+        long = "store-normalized-centroids",
+        raw(conflicts_with_all = r#"&["gmm", "pq"]"#)
+    )]
+    store_normalized_centroids: bool,
+    /// Subtract the mean of the training sample from the features before
+    /// clustering (the mean is saved, and also subtracted when quantizing)
+    #[structopt(long = "center")]
+    center: bool,
+    /// Save the mean and variance of each dimension of the training sample
+    /// to the `feature_mean` and `feature_var` data sets
+    #[structopt(long = "write-stats", raw(conflicts_with = r#""coarse_then_fine""#))]
+    write_stats: bool,
+    /// Cluster only `n` of the feature dimensions, picked at random
+    /// (the picked dimensions are saved, and also used when quantizing)
+    #[structopt(long = "subsample-dims")]
+    subsample_dims: Option<usize>,
+    /// The seed of the random choice of features and dimensions,
+    /// and of the initialization of the centroids by faiss
+    #[structopt(long = "seed", default_value = "1234")]
+    seed: u64,
+    /// The element type in which features are clustered
+    /// (only `f32` is supported for now)
+    #[structopt(
+        long = "cluster-dtype",
+        default_value = "f32",
+        raw(possible_values = r#"&["f32"]"#)
+    )]
+    cluster_dtype: ClusterDtype,
+    /// The element type in which the centroids are saved
+    /// (`f16` halves the size of the vocabulary, at a loss of precision)
+    #[structopt(
+        long = "centroid-dtype",
+        default_value = "f32",
+        raw(possible_values = r#"&["f32", "f16"]"#),
+        raw(conflicts_with_all = r#"&["gmm", "pq"]"#)
+    )]
+    centroid_dtype: CentroidDtype,
+    /// Write into an existing output file, replacing only the data sets
+    /// of the same name instead of the whole file
+    #[structopt(long = "force")]
+    force: bool,
+    /// Retry reads failing with HDF5 errors this many times, with a backoff
+    /// (for transient errors of network file systems)
+    #[structopt(long = "io-retries", default_value = "0")]
+    io_retries: u32,
+    /// Do not record the content hashes of the input files in the output,
+    /// which reads all of them once more
+    #[structopt(long = "no-manifest-hash")]
+    no_manifest_hash: bool,
+    /// Print status messages to stderr, keeping stdout for results
+    /// (`false` prints them to stdout along with the results)
+    #[structopt(
+        long = "progress-to-stderr",
+        default_value = "true",
+        parse(try_from_str),
+        raw(possible_values = r#"&["true", "false"]"#)
+    )]
+    progress_to_stderr: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -51,22 +363,313 @@ pub struct QuantizeArgs {
     /// The hdf5 file containing the codebook
     #[structopt(name = "VOCABULARY", parse(from_os_str))]
     vocabulary: PathBuf,
-    /// The hdf5 file containing the features
+    /// The hdf5 files containing the features (or `http(s)://` or `s3://` URLs to them)
     #[structopt(name = "FEATURES", parse(from_os_str))]
-    features: PathBuf,
+    features: Vec<PathBuf>,
+    /// The directory where features given by URL are downloaded
+    /// (defaults to a `cluster-bob` directory in the system's temporary directory)
+    #[structopt(long = "cache-dir", parse(from_os_str))]
+    cache_dir: Option<PathBuf>,
+    /// Read the features of a single item from standard input instead:
+    /// a little-endian `u32` with the dimensionality `d`,
+    /// followed by features of `d` little-endian `f32` values
+    #[structopt(
+        long = "features-stdin",
+        raw(conflicts_with_all = r#"&["FEATURES", "fisher", "residual_mag"]"#)
+    )]
+    features_stdin: bool,
+    /// Print the paths of all data sets in the vocabulary
+    /// and features files and exit
+    #[structopt(
+        long = "list-datasets",
+        alias = "list",
+        conflicts_with = "features_stdin"
+    )]
+    list_datasets: bool,
+    /// Only check that the features files have as many dimensions as the
+    /// codebook and that the metric is supported, reading their shapes
+    /// and attributes but no features, and exit
+    #[structopt(long = "dim-check-only", conflicts_with = "features_stdin")]
+    dim_check_only: bool,
     /// Group path where the features are
-    #[structopt(long = "name", default_value = "data")]
-    features_dataset_name: String,
+    /// (defaults to the composition recorded in the vocabulary, or `data`)
+    #[structopt(long = "name")]
+    features_dataset_name: Option<String>,
+    /// Only use the columns `start:end` of the features (end exclusive)
+    /// (defaults to the range recorded in the vocabulary)
+    #[structopt(long = "feature-cols")]
+    feature_cols: Option<ColumnRange>,
+    /// The features are stored transposed, one feature per column (`d x n`)
+    #[structopt(long = "features-transpose", conflicts_with = "features_stdin")]
+    features_transpose: bool,
+    /// Group path where the codebook is in the vocabulary file
+    /// (defaults to `data`, or the `codebook` group of product codebooks)
+    #[structopt(long = "vocab-name")]
+    vocab_name: Option<String>,
     /// Group path where the item IDs are defined for each feature
     #[structopt(long = "item_id", alias = "id_slice", default_value = "item_id")]
     item_id: String,
     /// Group path where the names (or textual IDs) are defined for each item
-    #[structopt(long = "item_name", default_value = "id_volume")]
-    item_name: String,
+    /// (defaults to `id_volume`, which is also where they are saved in the bags)
+    #[structopt(long = "item_name")]
+    item_name: Option<String>,
+    /// Attribute of the features file with a JSON map from item IDs to names
+    /// (or an array of names), read instead of the item_name data set
+    #[structopt(
+        long = "item-name-attr",
+        raw(conflicts_with_all = r#"&["item_name", "single_item"]"#)
+    )]
+    item_name_attr: Option<String>,
+    /// Rename items with a CSV file of `from,to` lines before saving their names
+    /// (names which are not listed are kept as they are)
+    #[structopt(
+        long = "rename-items",
+        parse(from_os_str),
+        raw(conflicts_with_all = r#"&["single_item", "features_stdin"]"#)
+    )]
+    rename_items: Option<PathBuf>,
+    /// Group path of a `n_items x 2` data set with the `[start, end)` rows
+    /// of each item's features, read item by item instead of using item_id
+    #[structopt(
+        long = "item-offsets",
+        raw(
+            conflicts_with_all = r#"&["single_item", "fisher", "two_pass_idf", "progress_items"]"#
+        )
+    )]
+    item_offsets: Option<String>,
+    /// The item_id data set is run-length encoded, as `n_runs x 2`
+    /// `(item_id, run_length)` pairs expanded as features are read
+    #[structopt(
+        long = "item-id-rle",
+        raw(
+            conflicts_with_all = r#"&["single_item", "item_offsets", "fisher", "two_pass_idf", "progress_items"]"#
+        )
+    )]
+    item_id_rle: bool,
+    /// Check that item IDs never decrease from one feature to the next,
+    /// failing at the first feature where they do
+    #[structopt(
+        long = "assert-monotonic-item-id",
+        raw(
+            conflicts_with_all = r#"&["single_item", "item_offsets", "item_id_rle", "fisher", "two_pass_idf"]"#
+        )
+    )]
+    assert_monotonic_item_id: bool,
+    /// When item IDs go past the item names, size the bags by the item IDs
+    /// instead of failing, naming the extra items by their ID
+    /// (and dropping the names of items past the largest ID)
+    #[structopt(
+        long = "trust-item-id",
+        raw(conflicts_with_all = r#"&["single_item", "item_offsets", "item_id_rle"]"#)
+    )]
+    trust_item_id: bool,
+    /// Copy the codebook (and whatever else is needed to quantize with it)
+    /// to the `vocabulary` group of the output, making it self-contained
+    #[structopt(long = "embed-vocab")]
+    embed_vocab: bool,
+    /// Do not write the `item_id` data set to the output,
+    /// which is only the sequential range of the bags' rows
+    #[structopt(long = "no-item-id-out")]
+    no_item_id_out: bool,
     /// Features file represents a single item (don't read item_id nor item_name)
     #[structopt(long = "single_item", alias = "single_volume")]
     single_item: bool,
-    /// The hdf5 file to store the bags
+    /// Align feature batches with the data set's chunks
+    #[structopt(long = "read-chunk-aligned")]
+    read_chunk_aligned: bool,
+    /// Only redraw the progress bar every `n` batches (or `n` items,
+    /// with `--progress-items`), for less terminal overhead with small batches
+    #[structopt(long = "progress-every", default_value = "1")]
+    progress_every: usize,
+    /// Break ties between equally near codewords by the lowest codeword index,
+    /// for reproducible assignments (slightly slower)
+    #[structopt(long = "stable-assign")]
+    stable_assign: bool,
+    /// The distance between features and codewords
+    /// (only `l2` is supported by the faiss binding in use)
+    #[structopt(
+        long = "metric",
+        default_value = "l2",
+        raw(possible_values = r#"&["l2", "l1", "linf"]"#)
+    )]
+    metric: Metric,
+    /// Map feature files to memory, viewing contiguous features in place
+    /// instead of reading them to new buffers
+    #[structopt(long = "mmap")]
+    mmap: bool,
+    /// Read the next batch of features in the background
+    /// while the current one is assigned
+    #[structopt(long = "prefetch")]
+    prefetch: bool,
+    /// Print a histogram of the number of features of each item
+    #[structopt(
+        long = "feature-count-hist",
+        raw(conflicts_with_all = r#"&["single_item", "features_stdin"]"#)
+    )]
+    feature_count_hist: bool,
+    /// Show progress in completed items rather than features
+    #[structopt(long = "progress-items", raw(conflicts_with = r#""single_item""#))]
+    progress_items: bool,
+    /// Group path of a boolean data set marking which features are valid,
+    /// so that invalid ones (such as padding) are skipped
+    #[structopt(long = "valid-mask", raw(conflicts_with = r#""fisher""#))]
+    valid_mask: Option<String>,
+    /// Group path of a data set with one `f32` weight per feature,
+    /// added to the bin of each feature instead of 1
+    /// (the bags are then saved as floating point numbers)
+    #[structopt(
+        long = "feature-weights",
+        raw(
+            conflicts_with_all = r#"&["fisher", "residual_mag", "two_pass_idf", "features_stdin"]"#
+        )
+    )]
+    feature_weights: Option<String>,
+    /// Produce Fisher vectors of `2*k*d` components, using the vocabulary's mixture model
+    #[structopt(long = "fisher")]
+    fisher: bool,
+    /// Also save the label of each feature to this data set of the bags,
+    /// in input order (`-1` where a feature was not assigned)
+    #[structopt(
+        long = "write-labels",
+        raw(
+            conflicts_with_all = r#"&["fisher", "residual_mag", "two_pass_idf", "features_stdin"]"#
+        )
+    )]
+    write_labels: Option<String>,
+    /// Produce histograms of the mean distance between each codeword
+    /// and the features assigned to it, instead of counts
+    #[structopt(long = "residual-mag", raw(conflicts_with = r#""fisher""#))]
+    residual_mag: bool,
+    /// Accumulate codeword counts as floating point numbers from the start
+    #[structopt(
+        long = "float-counts",
+        raw(conflicts_with_all = r#"&["fisher", "residual_mag"]"#)
+    )]
+    float_counts: bool,
+    /// Apply TF-IDF weighting with the inverse document frequencies
+    /// in the `data` data set of the given hdf5 file (one per codeword)
+    #[structopt(
+        long = "idf",
+        parse(from_os_str),
+        raw(conflicts_with_all = r#"&["fisher", "residual_mag"]"#)
+    )]
+    idf: Option<PathBuf>,
+    /// Apply TF-IDF weighting with document frequencies counted over the
+    /// quantized items, in two passes over the features, writing each bag as
+    /// soon as it is complete (item IDs must be in non-decreasing order)
+    #[structopt(
+        long = "two-pass-idf",
+        raw(
+            conflicts_with_all = r#"&["fisher", "residual_mag", "idf", "transpose", "single_item", "progress_items", "features_stdin"]"#
+        )
+    )]
+    two_pass_idf: bool,
+    /// Never count the codewords listed in the given text file
+    /// (one codeword index per line, `#` starts a comment)
+    #[structopt(
+        long = "exclude-codewords",
+        parse(from_os_str),
+        raw(conflicts_with_all = r#"&["fisher", "residual_mag"]"#)
+    )]
+    exclude_codewords: Option<PathBuf>,
+    /// Only assign features to the codewords listed in the given text file
+    /// (in the same format as `--exclude-codewords`), keeping the bins of all codewords
+    #[structopt(
+        long = "codeword-subset",
+        parse(from_os_str),
+        raw(conflicts_with = r#""fisher""#)
+    )]
+    codeword_subset: Option<PathBuf>,
+    /// Save the number of features assigned to each codeword and their mean
+    /// distance to it to the given hdf5 file (over all features files)
+    #[structopt(
+        long = "codeword-stats",
+        parse(from_os_str),
+        raw(conflicts_with_all = r#"&["fisher", "two_pass_idf"]"#)
+    )]
+    codeword_stats: Option<PathBuf>,
+    /// Also quantize against the flat codebook of this vocabulary file, concatenating
+    /// its histogram after those of the previous ones (can be given more than once)
+    #[structopt(
+        long = "add-vocabulary",
+        parse(from_os_str),
+        raw(
+            number_of_values = "1",
+            conflicts_with_all = r#"&["fisher", "residual_mag", "codeword_subset"]"#
+        )
+    )]
+    add_vocabulary: Vec<PathBuf>,
+    /// Normalize each bag to unit norm after any weighting
+    #[structopt(
+        long = "normalize",
+        default_value = "none",
+        raw(
+            possible_values = r#"&["none", "l1", "l2"]"#,
+            conflicts_with = r#""residual_mag""#
+        )
+    )]
+    normalize: Normalization,
+    /// Also write the bags as they were before weighting and normalization
+    /// to the `counts` data set
+    #[structopt(
+        long = "keep-raw",
+        raw(conflicts_with_all = r#"&["fisher", "residual_mag", "two_pass_idf"]"#)
+    )]
+    keep_raw: bool,
+    /// Only quantize the first `n` features of each file, regardless of items
+    /// (the output is incomplete, useful for testing)
+    #[structopt(long = "max-features")]
+    max_features: Option<usize>,
+    /// Fail early if the dense bags would take more than this many bytes
+    /// (defaults to the memory available to the system, when known)
+    #[structopt(long = "max-dense-bytes")]
+    max_dense_bytes: Option<u64>,
+    /// Read and assign as many features at once as fit in this many bytes,
+    /// as `f32` vectors, instead of a fixed number of them
+    #[structopt(long = "limit-memory")]
+    limit_memory: Option<u64>,
+    /// Write the bags in a sparse layout when the fraction of their
+    /// components which are not zero is below this threshold
+    #[structopt(
+        long = "sparse-threshold",
+        alias = "write-sparse-threshold",
+        raw(conflicts_with = r#""two_pass_idf""#)
+    )]
+    sparse_threshold: Option<f64>,
+    /// Print how many components of each bag are not zero
+    /// (mean, median and maximum) and the fraction of all components which are not
+    #[structopt(
+        long = "report-nonzero-per-item",
+        raw(conflicts_with = r#""two_pass_idf""#)
+    )]
+    report_nonzero_per_item: bool,
+    /// The format of the bags: `dense`, or `coo` for the `row`, `col` and `value`
+    /// of each nonzero component, in row-major order
+    #[structopt(
+        long = "format",
+        default_value = "dense",
+        raw(
+            possible_values = r#"&["dense", "coo"]"#,
+            conflicts_with_all = r#"&["sparse_threshold", "two_pass_idf"]"#
+        )
+    )]
+    format: BagsFormat,
+    /// Write the bags in codeword-major layout, as a `k x n_items` matrix
+    #[structopt(long = "transpose")]
+    transpose: bool,
+    /// Warn if the codewords have more than this many dimensions
+    #[structopt(long = "max-dim", default_value = "8192")]
+    max_dim: usize,
+    /// Check that the codebook has only finite values and distinct centroids
+    /// before quantizing, warning about all-zero and duplicate centroids
+    #[structopt(long = "validate-codebook")]
+    validate_codebook: bool,
+    /// Write into an existing output file, replacing only the data sets
+    /// of the same name instead of the whole file
+    #[structopt(long = "force")]
+    force: bool,
+    /// The hdf5 file to store the bags. When quantizing multiple feature files,
+    /// each output is named after its input instead (e.g. `a.h5` -> `a_bows.h5`)
     #[structopt(
         short = "o",
         long = "out",
@@ -74,265 +677,6196 @@ pub struct QuantizeArgs {
         default_value = "bows.h5"
     )]
     out: PathBuf,
+    /// Retry reads failing with HDF5 errors this many times, with a backoff
+    /// (for transient errors of network file systems)
+    #[structopt(long = "io-retries", default_value = "0")]
+    io_retries: u32,
+    /// Do not record the content hashes of the input files in the output,
+    /// which reads all of them once more
+    #[structopt(long = "no-manifest-hash")]
+    no_manifest_hash: bool,
+    /// Print status messages to stderr, keeping stdout for results
+    /// (`false` prints them to stdout along with the results)
+    #[structopt(
+        long = "progress-to-stderr",
+        default_value = "true",
+        parse(try_from_str),
+        raw(possible_values = r#"&["true", "false"]"#)
+    )]
+    progress_to_stderr: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct PipelineArgs {
+    #[structopt(flatten)]
+    vocabulary: VocabularyArgs,
+    /// The options and features files of `quantize`, without the vocabulary,
+    /// after `--` (the features default to those of the vocabulary)
+    #[structopt(name = "QUANTIZE_ARGS", raw(last = "true"))]
+    quantize_args: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct AssignArgs {
+    /// The hdf5 file containing the codebook
+    #[structopt(name = "VOCABULARY", parse(from_os_str))]
+    vocabulary: PathBuf,
+    /// The hdf5 file containing the features
+    #[structopt(name = "FEATURES", parse(from_os_str))]
+    features: PathBuf,
+    /// Group path where the features are
+    /// (defaults to the composition recorded in the vocabulary, or `data`)
+    #[structopt(long = "name")]
+    features_dataset_name: Option<String>,
+    /// Only use the columns `start:end` of the features (end exclusive)
+    /// (defaults to the range recorded in the vocabulary)
+    #[structopt(long = "feature-cols")]
+    feature_cols: Option<ColumnRange>,
+    /// Group path where the labels are written, one per input feature
+    #[structopt(long = "labels", default_value = "labels")]
+    labels_dataset_name: String,
+    /// Align feature batches with the data set's chunks
+    #[structopt(long = "read-chunk-aligned")]
+    read_chunk_aligned: bool,
+    /// Only redraw the progress bar every `n` batches,
+    /// for less terminal overhead with small batches
+    #[structopt(long = "progress-every", default_value = "1")]
+    progress_every: usize,
+    /// Break ties between equally near codewords by the lowest codeword index,
+    /// for reproducible assignments (slightly slower)
+    #[structopt(long = "stable-assign")]
+    stable_assign: bool,
+    /// The element type of the labels data set
+    #[structopt(
+        long = "assign-dtype",
+        default_value = "i64",
+        raw(possible_values = r#"&["u16", "u32", "i64"]"#)
+    )]
+    assign_dtype: LabelType,
+    /// The format of the output: `hdf5`, `text` for one label per line,
+    /// or `npy` for a one-dimensional NumPy array
+    #[structopt(
+        long = "out-format",
+        default_value = "hdf5",
+        raw(possible_values = r#"&["hdf5", "text", "npy"]"#)
+    )]
+    out_format: ResultsFormat,
+    /// Write into an existing output file, replacing only the data sets
+    /// of the same name instead of the whole file
+    #[structopt(long = "force")]
+    force: bool,
+    /// The file to store the labels (`-` for standard output, except for hdf5)
+    #[structopt(
+        short = "o",
+        long = "out",
+        parse(from_os_str),
+        default_value = "labels.h5"
+    )]
+    out: PathBuf,
+    /// Retry reads failing with HDF5 errors this many times, with a backoff
+    /// (for transient errors of network file systems)
+    #[structopt(long = "io-retries", default_value = "0")]
+    io_retries: u32,
+    /// Do not record the content hashes of the input files in the output,
+    /// which reads all of them once more
+    #[structopt(long = "no-manifest-hash")]
+    no_manifest_hash: bool,
+    /// Print status messages to stderr, keeping stdout for results
+    /// (`false` prints them to stdout along with the results)
+    #[structopt(
+        long = "progress-to-stderr",
+        default_value = "true",
+        parse(try_from_str),
+        raw(possible_values = r#"&["true", "false"]"#)
+    )]
+    progress_to_stderr: bool,
+}
+
+/// How the training sample is picked from the features.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sampling {
+    First,
+    Random,
 }
 
-fn main() -> DynResult<()> {
-    match App::from_args() {
-        App::Vocabulary(args) => generate_vocabulary(args)?,
-        App::Quantize(args) => generate_descriptors(args)?,
+impl FromStr for Sampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(Sampling::First),
+            "random" => Ok(Sampling::Random),
+            _ => Err(format!("unsupported sampling `{}`", s)),
+        }
     }
+}
 
-    Ok(())
+/// How the centroids are initialized before training.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeedInit {
+    Random,
+    KMeansPP,
 }
 
-fn generate_vocabulary(args: VocabularyArgs) -> DynResult<()> {
-    let file = File::open(args.features, "r")?;
+impl FromStr for SeedInit {
+    type Err = String;
 
-    let data = file.dataset(&args.dataset_name)?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(SeedInit::Random),
+            "kmeanspp" => Ok(SeedInit::KMeansPP),
+            _ => Err(format!("unsupported centroid initialization `{}`", s)),
+        }
+    }
+}
 
-    let k = args.size;
+/// The order in which the centroids are saved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CentroidOrder {
+    /// As produced by faiss
+    Faiss,
+    /// By increasing norm, then by their coordinates
+    Sorted,
+}
 
-    let progress = ProgressBar::new_spinner();
-    progress.set_message("Loading features to memory...");
-    progress.enable_steady_tick(100);
+impl FromStr for CentroidOrder {
+    type Err = String;
 
-    let features: Array2<f32> = if let Some(n) = args.n {
-        data.read_slice_2d(s![0..n, ..])?
-    } else {
-        data.read_2d()?
-    };
-    let d = features.shape()[1] as u32;
-    let mut params = ClusteringParameters::new();
-    if let Some(niter) = args.niter {
-        params.set_niter(niter);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "faiss" => Ok(CentroidOrder::Faiss),
+            "sorted" => Ok(CentroidOrder::Sorted),
+            _ => Err(format!("unsupported centroid order `{}`", s)),
+        }
     }
-    let mut cluster = Clustering::new_with_params(d, k, &params)?;
-    let mut index = FlatIndex::new_l2(d)?;
+}
 
-    progress.set_message(&format!(
-        "Clustering {} descriptors into {} components ...",
-        features.shape()[0],
-        k
-    ));
-    progress.enable_steady_tick(300);
+/// The element type in which features are clustered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClusterDtype {
+    F32,
+}
 
-    cluster.train(
-        features
-            .as_slice()
-            .expect("array must be in standard order"),
-        &mut index,
-    )?;
+impl ClusterDtype {
+    /// The name of the type, as recorded in the vocabulary.
+    fn name(self) -> &'static str {
+        match self {
+            ClusterDtype::F32 => "f32",
+        }
+    }
+}
 
-    println!(
-        "Done. Final objective loss: {}",
-        cluster
-            .objectives()?
-            .last()
-            .cloned()
-            .unwrap_or(std::f32::INFINITY)
-    );
-    println!("Saving centroids to {} ...", args.out.display());
+impl FromStr for ClusterDtype {
+    type Err = String;
 
-    let vocabulary_shape = (k as usize, d as usize);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(ClusterDtype::F32),
+            _ => Err(format!("unsupported cluster type `{}`", s)),
+        }
+    }
+}
 
-    let file = File::with_options().mode("w").open(&args.out)?;
-    let data = file
-        .new_dataset::<f32>()
-        .no_chunk()
-        .create("data", vocabulary_shape)?;
+/// The element type in which centroids are saved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CentroidDtype {
+    F32,
+    /// Half precision, stored as 16-bit IEEE floats
+    F16,
+}
 
-    let centroids: ArrayView2<f32> = ArrayView2::from_shape(vocabulary_shape, index.xb())?;
+impl CentroidDtype {
+    /// The name of the type, as recorded in the vocabulary.
+    fn name(self) -> &'static str {
+        match self {
+            CentroidDtype::F32 => "f32",
+            CentroidDtype::F16 => "f16",
+        }
+    }
+}
 
-    data.write(centroids)?;
+impl FromStr for CentroidDtype {
+    type Err = String;
 
-    Ok(())
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(CentroidDtype::F32),
+            "f16" => Ok(CentroidDtype::F16),
+            _ => Err(format!("unsupported centroid type `{}`", s)),
+        }
+    }
 }
 
-fn generate_descriptors(args: QuantizeArgs) -> DynResult<()> {
-    let progress = ProgressBar::new_spinner();
+/// The format of a features file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Hdf5,
+    Npy,
+}
 
-    progress.set_message("Reading data ...");
-    let codebook: Array2<f32> = {
-        let file = File::open(args.vocabulary, "r")?;
-        let vocabulary_dset = file.dataset("data")?;
-        vocabulary_dset.read_2d()?
-    };
-    let d = codebook.shape()[1] as u32;
-    let mut index = FlatIndex::new_l2(d)?;
-    index.add(
-        codebook
-            .as_slice()
-            .expect("codebook should be in standard layout"),
-    )?;
+impl FromStr for InputFormat {
+    type Err = String;
 
-    let file = File::open(args.features, "r")?;
-    let features_dset = file.dataset(&args.features_dataset_name)?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hdf5" => Ok(InputFormat::Hdf5),
+            "npy" => Ok(InputFormat::Npy),
+            _ => Err(format!("unsupported input format `{}`", s)),
+        }
+    }
+}
 
-    let bows: Array2<_> = if args.single_item {
-        drop(progress);
+/// The format in which bags are written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BagsFormat {
+    /// A dense matrix, or a sparse one under `--sparse-threshold`
+    Dense,
+    /// Triplets of the item, codeword and value of each nonzero component
+    Coo,
+}
 
-        let progress = ProgressBar::new(features_dset.shape()[0] as u64);
-        progress.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] {bar:50} {pos:>7}/{len:7} {msg}"),
-        );
-        progress.set_message("Building bags ...");
-        let bows = construct_bows_one(&features_dset, &mut index, |n| {
-            progress.inc(u64::from(n));
-        })?;
+impl FromStr for BagsFormat {
+    type Err = String;
 
-        bows.insert_axis(Axis(0))
-    } else {
-        let id_slice_dset = file.dataset(&args.item_id)?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dense" => Ok(BagsFormat::Dense),
+            "coo" => Ok(BagsFormat::Coo),
+            _ => Err(format!("unsupported bags format `{}`", s)),
+        }
+    }
+}
 
-        // peek at item_name to identify the number of items
-        let n_items = {
-            let id_item_dset = file.dataset(&args.item_name)?;
-            id_item_dset.shape()[0]
-        };
+/// A preset of k-means parameters, trading training time for quality.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quality {
+    Fast,
+    Balanced,
+    Best,
+}
 
-        drop(progress);
+impl Quality {
+    /// The number of iterations, of runs,
+    /// and the maximum number of points per centroid.
+    fn params(self) -> (u32, u32, u32) {
+        match self {
+            Quality::Fast => (10, 1, 64),
+            Quality::Balanced => (25, 1, 256),
+            Quality::Best => (50, 5, 1024),
+        }
+    }
+}
 
-        let progress = ProgressBar::new(id_slice_dset.shape()[0] as u64);
-        progress.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] {bar:50} {pos:>7}/{len:7} {msg}"),
-        );
-        progress.set_message("Building bags ...");
-        construct_bows(&features_dset, &id_slice_dset, n_items, &mut index, |n| {
-            progress.inc(u64::from(n));
-        })?
-    };
-    // save them
-    let progress = ProgressBar::new_spinner();
-    progress.set_message("Saving to file ...");
+impl FromStr for Quality {
+    type Err = String;
 
-    let out = File::open(&args.out, "w")?;
-    let bows_dset = out
-        .new_dataset::<f32>()
-        .no_chunk()
-        .create("data", bows.dim())?;
-    bows_dset.write(bows.view())?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fast" => Ok(Quality::Fast),
+            "balanced" => Ok(Quality::Balanced),
+            "best" => Ok(Quality::Best),
+            _ => Err(format!("unsupported quality preset `{}`", s)),
+        }
+    }
+}
 
-    let n_items = bows_dset.shape()[0];
+/// The distance between features and codewords.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    L2,
+    L1,
+    Linf,
+}
 
-    if !args.single_item {
-        // write sequential range to `id_slice`
-        let id_slice_dset_out = out
-            .new_dataset::<u32>()
-            .no_chunk()
-            .create(&args.item_id, (n_items,))?;
-        id_slice_dset_out.write_raw(&(0..n_items).collect::<Vec<_>>())?;
+impl Metric {
+    /// Fail if indices over this metric cannot be constructed.
+    ///
+    /// faiss itself has L1 and L-infinity indices, but the binding only
+    /// constructs L2 and inner product ones, so the others are rejected.
+    fn check_supported(self) -> DynResult<()> {
+        match self {
+            Metric::L2 => Ok(()),
+            Metric::L1 | Metric::Linf => Err(failure(
+                Category::BadInput,
+                format!(
+                    "the {} metric is not supported by this faiss binding, only l2 is",
+                    self.as_str()
+                ),
+            )),
+        }
+    }
 
-        // replicate `id_item` to the output file
-        let id_item_dset_in = file.dataset(&args.item_name)?;
-        let id_item_in: Vec<VarLenUnicode> = id_item_dset_in.read_raw()?;
-        let id_item_dset_out = out
-            .new_dataset::<VarLenUnicode>()
-            .no_chunk()
-            .create(&args.item_name, id_item_dset_in.shape())?;
-        id_item_dset_out.write_raw(&id_item_in)?;
+    /// Create an empty flat index of `d` dimensions over this metric.
+    fn flat_index(self, d: u32) -> DynResult<FlatIndex> {
+        self.check_supported()?;
+        Ok(FlatIndex::new_l2(d)?)
     }
 
-    progress.finish_with_message(&format!("Bags saved: {}", args.out.display()));
-    Ok(())
+    /// The name of the metric, as given in the command line.
+    fn as_str(self) -> &'static str {
+        match self {
+            Metric::L2 => "l2",
+            Metric::L1 => "l1",
+            Metric::Linf => "linf",
+        }
+    }
 }
 
-fn batched_1d<'a, T>(dset: &'a Dataset, batch_size: usize) -> impl Iterator<Item = Array1<T>> + 'a
-where
-    T: h5::H5Type,
-{
-    let batch_offset = dset.shape()[0] % batch_size;
-    let nbatches = dset.shape()[0] / batch_size + if batch_offset > 0 { 1 } else { 0 };
+impl FromStr for Metric {
+    type Err = String;
 
-    (0..nbatches).map(move |i| {
-        let begin = i * batch_size;
-        let end = usize::min(begin + batch_size, dset.shape()[0]);
-        dset.read_slice_1d::<T, _>(s![begin..end])
-            .expect("out of range")
-    })
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "l2" => Ok(Metric::L2),
+            "l1" => Ok(Metric::L1),
+            "linf" => Ok(Metric::Linf),
+            _ => Err(format!("unsupported metric `{}`", s)),
+        }
+    }
 }
 
-fn batched_2d<'a, T>(dset: &'a Dataset, batch_size: usize) -> impl Iterator<Item = Array2<T>> + 'a
-where
-    T: h5::H5Type,
-{
-    let total = dset.shape()[0];
-    let batch_offset = total % batch_size;
-    let nbatches = total / batch_size + if batch_offset > 0 { 1 } else { 0 };
+/// The size of a codebook, either given or derived from the training sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CodebookSize {
+    Fixed(u32),
+    /// the square root of the number of features
+    Sqrt,
+    /// the square root of half the number of features
+    Auto,
+}
 
-    (0..nbatches).map(move |i| {
-        let begin = i * batch_size;
-        let end = usize::min(begin + batch_size, total);
-        dset.read_slice_2d::<T, _>(s![begin..end, ..])
-            .expect("out of range")
-    })
+impl CodebookSize {
+    /// The number of codewords for a training sample of `n` features.
+    fn resolve(self, n: usize) -> u32 {
+        match self {
+            CodebookSize::Fixed(k) => k,
+            CodebookSize::Sqrt => u32::max((n as f64).sqrt().round() as u32, 1),
+            CodebookSize::Auto => u32::max((n as f64 / 2.).sqrt().round() as u32, 1),
+        }
+    }
 }
 
-fn construct_bows_one<F>(
-    features_dset: &Dataset,
-    index: &mut Index,
-    tick_fn: F,
-) -> DynResult<Array1<u32>>
-where
-    F: Fn(u32),
-{
-    let batch_size = 1024;
-    let mut bows = Array1::<u32>::zeros([index.ntotal() as usize]);
-    for feature_batch in batched_2d::<f32>(&features_dset, batch_size) {
-        let b_size = feature_batch.shape()[0];
-        let nearest = index.assign(
-            feature_batch
+impl FromStr for CodebookSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sqrt" => Ok(CodebookSize::Sqrt),
+            "auto" => Ok(CodebookSize::Auto),
+            _ => s.parse().map(CodebookSize::Fixed).map_err(|_| {
+                format!(
+                    "invalid codebook size `{}`, expected a number, `sqrt` or `auto`",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+/// The element type of a labels data set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LabelType {
+    U16,
+    U32,
+    I64,
+}
+
+impl FromStr for LabelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "u16" => Ok(LabelType::U16),
+            "u32" => Ok(LabelType::U32),
+            "i64" => Ok(LabelType::I64),
+            _ => Err(format!("unsupported label type `{}`", s)),
+        }
+    }
+}
+
+/// The format of the results of `assign` and `search`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResultsFormat {
+    Hdf5,
+    /// Whitespace separated values, one line per row
+    Text,
+    /// A NumPy `.npy` array
+    Npy,
+}
+
+impl FromStr for ResultsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hdf5" => Ok(ResultsFormat::Hdf5),
+            "text" => Ok(ResultsFormat::Text),
+            "npy" => Ok(ResultsFormat::Npy),
+            _ => Err(format!("unsupported output format `{}`", s)),
+        }
+    }
+}
+
+/// The normalization applied to each bag.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    None,
+    L1,
+    L2,
+}
+
+impl Normalization {
+    /// The name of the normalization, as recorded in the output.
+    fn as_str(self) -> &'static str {
+        match self {
+            Normalization::None => "none",
+            Normalization::L1 => "l1",
+            Normalization::L2 => "l2",
+        }
+    }
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Normalization::None),
+            "l1" => Ok(Normalization::L1),
+            "l2" => Ok(Normalization::L2),
+            _ => Err(format!("unsupported normalization `{}`", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct VerifyArgs {
+    /// The hdf5 file containing the bags
+    #[structopt(name = "BOWS", parse(from_os_str))]
+    bows: PathBuf,
+    /// The hdf5 file containing the codebook
+    /// (defaults to the vocabulary embedded in the bags file)
+    #[structopt(name = "VOCABULARY", parse(from_os_str))]
+    vocabulary: Option<PathBuf>,
+    /// Group path where the item IDs are defined for each item
+    #[structopt(long = "item_id", alias = "id_slice", default_value = "item_id")]
+    item_id: String,
+    /// Group path where the names (or textual IDs) are defined for each item
+    #[structopt(long = "item_name", default_value = "id_volume")]
+    item_name: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SearchArgs {
+    /// The hdf5 file containing the bags to search
+    #[structopt(name = "DATABASE", parse(from_os_str))]
+    database: PathBuf,
+    /// The hdf5 file containing the query bags
+    #[structopt(name = "QUERIES", parse(from_os_str))]
+    queries: PathBuf,
+    /// The number of results per query
+    #[structopt(short = "k", long = "top", default_value = "10")]
+    top: usize,
+    /// How bags are compared: L2 distance, cosine similarity,
+    /// or histogram intersection (the sum of the element-wise minimum)
+    #[structopt(
+        long = "score",
+        default_value = "l2",
+        raw(possible_values = r#"&["l2", "cosine", "intersection"]"#)
+    )]
+    score: Score,
+    /// The format of the output: `hdf5`, `text` for one `query index score`
+    /// line per result, or `npy` for the `n_queries x k` NumPy array of indices
+    #[structopt(
+        long = "out-format",
+        default_value = "hdf5",
+        raw(possible_values = r#"&["hdf5", "text", "npy"]"#)
+    )]
+    out_format: ResultsFormat,
+    /// Write into an existing output file, replacing only the data sets
+    /// of the same name instead of the whole file
+    #[structopt(long = "force")]
+    force: bool,
+    /// The file to store the results (`-` for standard output, except for hdf5)
+    #[structopt(
+        short = "o",
+        long = "out",
+        parse(from_os_str),
+        default_value = "results.h5"
+    )]
+    out: PathBuf,
+    /// Do not record the content hashes of the input files in the output,
+    /// which reads all of them once more
+    #[structopt(long = "no-manifest-hash")]
+    no_manifest_hash: bool,
+    /// Print status messages to stderr, keeping stdout for results
+    /// (`false` prints them to stdout along with the results)
+    #[structopt(
+        long = "progress-to-stderr",
+        default_value = "true",
+        parse(try_from_str),
+        raw(possible_values = r#"&["true", "false"]"#)
+    )]
+    progress_to_stderr: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SparsifyArgs {
+    /// The hdf5 file containing the bags
+    #[structopt(name = "BOWS", parse(from_os_str))]
+    bows: PathBuf,
+    /// Convert sparse (or COO) bags to dense bags instead
+    #[structopt(long = "densify")]
+    densify: bool,
+    /// Group path where the item IDs are, copied as they are
+    #[structopt(long = "item_id", default_value = "item_id")]
+    item_id: String,
+    /// Group path where the item names are, copied as they are
+    #[structopt(long = "item_name", default_value = "id_volume")]
+    item_name: String,
+    /// Write into an existing output file, replacing only the data sets
+    /// of the same name instead of the whole file
+    #[structopt(long = "force")]
+    force: bool,
+    /// The hdf5 file to store the converted bags
+    #[structopt(short = "o", long = "out", parse(from_os_str))]
+    out: PathBuf,
+    /// Do not record the content hashes of the input files in the output,
+    /// which reads all of them once more
+    #[structopt(long = "no-manifest-hash")]
+    no_manifest_hash: bool,
+    /// Print status messages to stderr, keeping stdout for results
+    /// (`false` prints them to stdout along with the results)
+    #[structopt(
+        long = "progress-to-stderr",
+        default_value = "true",
+        parse(try_from_str),
+        raw(possible_values = r#"&["true", "false"]"#)
+    )]
+    progress_to_stderr: bool,
+}
+
+/// How query bags are compared to those of the database.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Score {
+    L2,
+    Cosine,
+    Intersection,
+}
+
+impl Score {
+    /// The name of the score, as recorded in the output.
+    fn as_str(self) -> &'static str {
+        match self {
+            Score::L2 => "l2",
+            Score::Cosine => "cosine",
+            Score::Intersection => "intersection",
+        }
+    }
+}
+
+impl FromStr for Score {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "l2" => Ok(Score::L2),
+            "cosine" => Ok(Score::Cosine),
+            "intersection" => Ok(Score::Intersection),
+            _ => Err(format!("unsupported score `{}`", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct EvaluateArgs {
+    /// The hdf5 file containing the codebook
+    #[structopt(name = "VOCABULARY", parse(from_os_str))]
+    vocabulary: PathBuf,
+    /// The hdf5 file containing the features
+    #[structopt(name = "FEATURES", parse(from_os_str))]
+    features: PathBuf,
+    /// Group path where the features are
+    /// (defaults to the composition recorded in the vocabulary, or `data`)
+    #[structopt(long = "name")]
+    features_dataset_name: Option<String>,
+    /// Only use the columns `start:end` of the features (end exclusive)
+    /// (defaults to the range recorded in the vocabulary)
+    #[structopt(long = "feature-cols")]
+    feature_cols: Option<ColumnRange>,
+    /// Only use `n` features for the evaluation
+    #[structopt(short = "N")]
+    n: Option<usize>,
+    /// Report the fraction of codewords used by the sample
+    #[structopt(long = "report-coverage")]
+    report_coverage: bool,
+    /// The number of least used codewords to list with the coverage
+    #[structopt(long = "least-used", default_value = "10")]
+    least_used: usize,
+    /// Save the number of features assigned to each codeword and their mean
+    /// distance to it to the given hdf5 file
+    #[structopt(long = "codeword-stats", parse(from_os_str))]
+    codeword_stats: Option<PathBuf>,
+    /// Retry reads failing with HDF5 errors this many times, with a backoff
+    /// (for transient errors of network file systems)
+    #[structopt(long = "io-retries", default_value = "0")]
+    io_retries: u32,
+    /// Print status messages to stderr, keeping stdout for results
+    /// (`false` prints them to stdout along with the results)
+    #[structopt(
+        long = "progress-to-stderr",
+        default_value = "true",
+        parse(try_from_str),
+        raw(possible_values = r#"&["true", "false"]"#)
+    )]
+    progress_to_stderr: bool,
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(exit::code(&*e));
+    }
+}
+
+fn run() -> DynResult<()> {
+    interrupt::install()?;
+    let result = match App::from_args() {
+        App::Vocabulary(args) => generate_vocabulary(args).map(|_| ()),
+        App::Quantize(args) => generate_descriptors(args, None),
+        App::Pipeline(args) => run_pipeline(args),
+        App::Assign(args) => generate_labels(args),
+        App::Verify(args) => verify_bows(args),
+        App::Evaluate(args) => evaluate_vocabulary(args),
+        App::Search(args) => search_bows(args),
+        App::Sparsify(args) => sparsify_bows(args),
+    };
+    // the partial results of an interruption are complete files
+    if result.is_ok() || interrupt::interrupted() {
+        output::commit()?;
+    } else {
+        output::discard();
+    }
+    result
+}
+
+/// Train a vocabulary and save it, returning its flat codebook as saved
+/// (`None` for product codebooks, or when only listing data sets).
+fn generate_vocabulary(mut args: VocabularyArgs) -> DynResult<Option<Array2<f32>>> {
+    retry::set_retries(args.io_retries);
+    status::set_to_stderr(args.progress_to_stderr);
+    args.features = remote::fetch(
+        &args.features,
+        args.cache_dir.as_ref().map(PathBuf::as_path),
+    )?;
+    if args.list_datasets {
+        if args.input_format == InputFormat::Npy {
+            return Err(failure(
+                Category::BadInput,
+                ".npy files have no data sets to list",
+            ));
+        }
+        list_datasets(&args.features)?;
+        return Ok(None);
+    }
+    args.metric.check_supported()?;
+    if let Some(threshold) = args.merge_threshold {
+        if !(threshold.is_finite() && threshold > 0.) {
+            return Err(failure(
+                Category::BadInput,
+                format!("--merge-threshold {} is not a positive distance", threshold),
+            ));
+        }
+    }
+    let (mut features, dims, mut n_training) = match (args.input_format, args.coarse_then_fine) {
+        (InputFormat::Hdf5, Some(shard_size)) => load_coarse_centroids(&args, shard_size)?,
+        (InputFormat::Npy, Some(_)) => {
+            return Err(failure(
+                Category::BadInput,
+                "--coarse-then-fine only supports hdf5 features",
+            ));
+        }
+        (InputFormat::Hdf5, None) => {
+            let (features, dims) = load_training_features(&args)?;
+            let n = features.rows();
+            (features, dims, n)
+        }
+        (InputFormat::Npy, None) => {
+            let (features, dims) = load_training_npy(&args)?;
+            let n = features.rows();
+            (features, dims, n)
+        }
+    };
+
+    if args.dedup {
+        let rows = features.rows();
+        features = dedup_rows(features);
+        status!(
+            "Dropped {} duplicate training features, {} remain",
+            rows - features.rows(),
+            features.rows()
+        );
+        n_training = features.rows();
+    }
+
+    // with coarse clustering, `features` are the pooled centroids
+    let k = args.size.resolve(n_training);
+    if args.size != CodebookSize::Fixed(k) {
+        status!("Chose k = {} for {} training features", k, n_training);
+    }
+
+    if features.rows() == 0 {
+        return Err(failure(Category::EmptyData, "the training sample is empty"));
+    }
+
+    // before centering, so that they are those of the features as stored
+    let feature_stats = if args.write_stats {
+        Some(column_stats(features.view()))
+    } else {
+        None
+    };
+
+    let mean = if args.center {
+        let mean = features.mean_axis(Axis(0));
+        features -= &mean;
+        Some(mean)
+    } else {
+        None
+    };
+
+    let progress = ProgressBar::new_spinner();
+    let d = features.cols() as u32;
+    let mut params = clustering_params(&args);
+    status!(
+        "k-means parameters: niter = {}, nredo = {}, max_points_per_centroid = {}",
+        params.niter(),
+        params.nredo(),
+        params.max_points_per_centroid()
+    );
+    // with a tolerance, iterations are run one at a time,
+    // and each of them resumes from the previous centroids
+    let max_iter = params.niter();
+    if args.tol.is_some() {
+        if params.nredo() > 1 {
+            eprintln!("Warning: --tol runs k-means only once, ignoring nredo");
+        }
+        params.set_niter(1);
+        params.set_nredo(1);
+    }
+
+    if let Some(m) = args.pq {
+        generate_product_vocabulary(
+            &args,
+            features.view(),
+            k,
+            m,
+            &params,
+            max_iter,
+            mean.as_ref(),
+            dims.as_ref().map(Vec::as_slice),
+            &progress,
+        )?;
+        if let Some(ref stats) = feature_stats {
+            write_feature_stats(&open_output(&args.out, true)?, stats)?;
+        }
+        return Ok(None);
+    }
+
+    let frozen = match args.freeze {
+        Some(ref path) => {
+            // the frozen centroids must stay the first ones, as they were
+            if args.centroid_order == CentroidOrder::Sorted {
+                return Err(failure(
+                    Category::BadInput,
+                    "--centroid-order sorted would reorder the frozen centroids",
+                ));
+            }
+            if args.seed_init == SeedInit::KMeansPP {
+                return Err(failure(
+                    Category::BadInput,
+                    "--seed-init kmeanspp is not supported with --freeze",
+                ));
+            }
+            Some(load_frozen_centroids(path, k as usize, d as usize)?)
+        }
+        None => None,
+    };
+    let mut index = args.metric.flat_index(d)?;
+    let objective = match frozen {
+        Some(ref frozen) => {
+            progress.set_message(&format!(
+                "Clustering {} descriptors into {} new components ...",
+                features.shape()[0],
+                k as usize - frozen.rows()
+            ));
+            progress.enable_steady_tick(300);
+            let (centroids, objective) =
+                cluster_with_frozen(frozen.view(), features.view(), k as usize, &params)?;
+            index = codebook_index(centroids.view())?;
+            objective
+        }
+        None => {
+            let mut cluster = Clustering::new_with_params(d, k, &params)?;
+            if args.seed_init == SeedInit::KMeansPP {
+                progress.set_message("Seeding centroids with k-means++ ...");
+                seed_kmeanspp(&mut cluster, features.view(), k, &params, args.seed)?;
+            }
+
+            progress.set_message(&format!(
+                "Clustering {} descriptors into {} components ...",
+                features.shape()[0],
+                k
+            ));
+            progress.enable_steady_tick(300);
+
+            // when training iteration by iteration, it can stop at any of them
+            interrupt::set_deferred(args.tol.is_some());
+            let objective = train_kmeans(
+                &mut cluster,
+                features
+                    .as_slice()
+                    .expect("array must be in standard order"),
+                &mut index,
+                args.tol,
+                max_iter,
+            )?;
+            interrupt::set_deferred(false);
+            objective
+        }
+    };
+    let partial = interrupt::interrupted();
+
+    let objective = if args.recluster_empty && !partial {
+        progress.set_message("Re-seeding empty clusters ...");
+        match recluster_empty(&mut index, features.view(), k, RECLUSTER_NITER, &params)? {
+            Some((n_empty, objective)) => {
+                status!(
+                    "Re-seeded {} empty clusters and ran {} more iterations",
+                    n_empty,
+                    RECLUSTER_NITER
+                );
+                objective
+            }
+            None => objective,
+        }
+    } else {
+        objective
+    };
+
+    // centroids are merged before the objective is reported and checked,
+    // so that it is that of the saved vocabulary
+    let requested_k = k;
+    let trained_points = effective_training_points(features.rows(), k, &params);
+    let (k, objective, objective_points) = match args.merge_threshold.filter(|_| !partial) {
+        Some(threshold) => {
+            let centroids = index_centroids(&index, (k as usize, d as usize))?;
+            match merge_close_centroids(centroids, threshold) {
+                Some(merged) => {
+                    index = codebook_index(merged.view())?;
+                    progress.set_message("Computing the objective of the merged centroids ...");
+                    let objective = training_objective(&mut index, features.view())?;
+                    status!(
+                        "Merged centroids closer than {}, k = {} instead of {} \
+                         (the objective is that of the merged centroids over all {} training features)",
+                        threshold,
+                        merged.rows(),
+                        k,
+                        features.rows()
+                    );
+                    (merged.rows() as u32, objective, features.rows())
+                }
+                None => (k, objective, trained_points),
+            }
+        }
+        None => (k, objective, trained_points),
+    };
+
+    println!("Done. Final objective loss: {}", objective);
+    if frozen.is_none() {
+        report_training_points(features.rows(), requested_k, &params);
+    }
+    if args.report_objective_per_point {
+        report_objective_per_point(objective, objective_points);
+    }
+    if !partial {
+        if let Some(ref log) = args.objective_log {
+            append_objective_log(log, requested_k, max_iter, args.seed, objective)?;
+        }
+        check_objective(objective, args.max_objective)?;
+    }
+
+    let vocabulary_shape = (k as usize, d as usize);
+
+    // with sorted centroids, the index is rebuilt over them,
+    // so that assignments and the mixture model follow the same order
+    let order = if args.centroid_order == CentroidOrder::Sorted {
+        let centroids = index_centroids(&index, vocabulary_shape)?;
+        let order = sorted_centroid_order(centroids);
+        let sorted = centroids.select(Axis(0), &order);
+        index = codebook_index(sorted.view())?;
+        Some(order)
+    } else {
+        None
+    };
+
+    let labels = if !partial
+        && (args.gmm || args.write_assignments.is_some() || args.max_empty_frac.is_some())
+    {
+        progress.set_message("Assigning training features ...");
+        index
+            .assign(
+                features
+                    .as_slice()
+                    .expect("array must be in standard order"),
+                1,
+            )?
+            .labels
+    } else {
+        Vec::new()
+    };
+
+    if let Some(max) = args.max_empty_frac.filter(|_| !partial) {
+        check_empty_clusters(&labels, k as usize, max)?;
+    }
+
+    if let Some(path) = args.write_assignments.as_ref().filter(|_| !partial) {
+        let file = open_output(path, args.force)?;
+        write_labels(&file, "assignments", &labels)?;
+        println!("Assignments saved: {}", path.display());
+    }
+
+    let gmm = if args.gmm && !partial {
+        let centroids = index_centroids(&index, vocabulary_shape)?;
+        let mut gmm = Gmm::from_kmeans(features.view(), centroids, &labels)?;
+        let mut loglik = std::f64::NEG_INFINITY;
+        for i in 0..args.gmm_niter {
+            progress.set_message(&format!(
+                "Fitting mixture model (iteration {}/{}) ...",
+                i + 1,
+                args.gmm_niter
+            ));
+            loglik = gmm.em_step(features.view());
+        }
+        status!("Mixture model fitted. Mean log-likelihood: {}", loglik);
+        Some(gmm)
+    } else {
+        None
+    };
+
+    status!("Saving centroids to {} ...", args.out.display());
+
+    let file = open_output(&args.out, args.force)?;
+    for name in &[
+        "data",
+        "variances",
+        "weights",
+        "codebook",
+        "mean",
+        "dims",
+        "centroid_order",
+    ] {
+        remove_existing(&file, name)?;
+    }
+    write_mean(&file, mean.as_ref())?;
+    if let Some(ref stats) = feature_stats {
+        write_feature_stats(&file, stats)?;
+    }
+    write_dims(&file, dims.as_ref().map(Vec::as_slice))?;
+    if let Some(order) = order {
+        // the position of each saved centroid in the order of faiss
+        let order: Vec<u32> = order.into_iter().map(|i| i as u32).collect();
+        file.new_dataset::<u32>()
+            .no_chunk()
+            .create("centroid_order", (order.len(),))?
+            .write_raw(&order)?;
+    }
+    let data = match args.centroid_dtype {
+        CentroidDtype::F32 => file
+            .new_dataset::<f32>()
+            .no_chunk()
+            .create("data", vocabulary_shape)?,
+        CentroidDtype::F16 => f16::create_dataset(&file, "data", vocabulary_shape)?,
+    };
+    write_str_attr(&data, "centroid_dtype", args.centroid_dtype.name())?;
+    // record the feature composition so that quantization can rebuild it
+    write_str_attr(&data, "features", &args.dataset_name)?;
+    if let Some(range) = args.feature_cols {
+        write_str_attr(&data, "feature_cols", &range.to_string())?;
+    }
+    write_str_attr(&data, "cluster_dtype", args.cluster_dtype.name())?;
+    write_str_attr(&data, "metric", args.metric.as_str())?;
+    write_attr(&data, "normalized", &args.store_normalized_centroids)?;
+    if k != requested_k {
+        write_attr(&data, "requested_k", &requested_k)?;
+    }
+    if let Some(ref frozen) = frozen {
+        write_attr(&data, "frozen", &(frozen.rows() as u32))?;
+    }
+
+    let codebook = if let Some(gmm) = gmm {
+        data.write(gmm.means.view())?;
+        file.new_dataset::<f32>()
+            .no_chunk()
+            .create("variances", vocabulary_shape)?
+            .write(gmm.variances.view())?;
+        file.new_dataset::<f32>()
+            .no_chunk()
+            .create("weights", (k as usize,))?
+            .write(gmm.weights.view())?;
+        gmm.means
+    } else {
+        let mut centroids = index_centroids(&index, vocabulary_shape)?.to_owned();
+        if args.store_normalized_centroids {
+            normalize_rows(centroids.view_mut(), Normalization::L2);
+        }
+        match args.centroid_dtype {
+            CentroidDtype::F32 => data.write(centroids.view())?,
+            CentroidDtype::F16 => {
+                eprintln!(
+                    "Warning: saving centroids in half precision, \
+                     with about 3 significant digits up to a magnitude of 65504"
+                );
+                f16::write_dataset(&data, centroids.view())?;
+                // the hash is that of the centroids as they are read back
+                centroids = f16::decode(f16::encode(centroids.view()).view());
+            }
+        }
+        centroids
+    };
+    let hash = codebook_hash(codebook.view());
+    write_str_attr(&file, "vocab_hash", &hash)?;
+    write_manifest(
+        &file,
+        &[("features_file_hash", args.features.as_path())],
+        !args.no_manifest_hash,
+    )?;
+
+    if partial {
+        write_attr(&file, "partial", &true)?;
+        return Err(format!(
+            "interrupted, centroids of the last iteration saved to {}",
+            args.out.display()
+        )
+        .into());
+    }
+    Ok(Some(codebook))
+}
+
+/// Load the training sample from an hdf5 features file,
+/// along with the subset of dimensions picked, if any.
+fn load_training_features(args: &VocabularyArgs) -> DynResult<(Array2<f32>, Option<Vec<usize>>)> {
+    let file = File::open(&args.features, "r")?;
+
+    let mut data = FeatureSet::open(&file, &args.dataset_name, args.features_transpose)?;
+    if let Some(range) = args.feature_cols {
+        data.select_cols(range)?;
+    }
+    let dims = training_dims(args, data.cols())?;
+    if let Some(ref dims) = dims {
+        data.select_dims(dims.clone())?;
+    }
+
+    let listed = match args.sample_file {
+        Some(ref path) => {
+            if args.sample == Sampling::Random {
+                return Err(failure(
+                    Category::BadInput,
+                    "--sample random cannot be combined with --sample-file",
+                ));
+            }
+            Some(load_sample_file(path, data.rows())?)
+        }
+        None => None,
+    };
+    let n = match listed {
+        Some(ref listed) => listed.iter().filter(|&&keep| keep).count(),
+        None => training_size(args, data.rows())?,
+    };
+    // with a cap per item, a random sample or listed rows,
+    // all features may have to be read to pick `n`
+    let progress = ProgressBar::new(
+        if args.per_item_cap.is_some() || args.sample == Sampling::Random || listed.is_some() {
+            data.rows() as u64
+        } else {
+            n as u64
+        },
+    );
+    progress.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:50} {pos:>7}/{len:7} {msg}"),
+    );
+    progress.set_message("Loading features to memory...");
+    let tick_fn = |n: usize| {
+        progress.inc(n as u64);
+    };
+    let features = match args.per_item_cap {
+        Some(cap) => {
+            let id_slice_dset = file.dataset(&args.item_id)?;
+            data.load_per_item(&id_slice_dset, cap, n, BATCH_SIZE, tick_fn)?
+        }
+        None if listed.is_some() => {
+            let listed = listed.as_ref().expect("rows should be listed");
+            data.load_where(n, BATCH_SIZE, |i| listed[i], tick_fn)?
+        }
+        None if args.sample == Sampling::Random => {
+            let mut selection = Selection::new(data.rows(), n, args.seed);
+            data.load_where(n, BATCH_SIZE, |_| selection.keep(), tick_fn)?
+        }
+        None => data.load(n, BATCH_SIZE, tick_fn)?,
+    };
+    progress.finish_and_clear();
+    if let Some(cap) = args.per_item_cap {
+        status!(
+            "Sampled {} features with at most {} per item",
+            features.rows(),
+            cap
+        );
+    }
+    if let Some(ref path) = args.sample_file {
+        status!(
+            "Training on the {} rows listed in {}",
+            features.rows(),
+            path.display()
+        );
+    }
+    Ok((features, dims))
+}
+
+/// Load the training sample of a features file of `rows` rows from a text
+/// file of one row index per line, returning whether each row is listed.
+/// Blank lines and anything after a `#` are ignored.
+fn load_sample_file(path: &Path, rows: usize) -> DynResult<Vec<bool>> {
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        failure(
+            Category::Io,
+            format!("could not read {}: {}", path.display(), e),
+        )
+    })?;
+    let mut listed = vec![false; rows];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.splitn(2, '#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let row: usize = line.parse().map_err(|_| {
+            failure(
+                Category::BadInput,
+                format!("{}:{}: invalid row index `{}`", path.display(), i + 1, line),
+            )
+        })?;
+        if row >= rows {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "{}:{}: row {} is out of range, the features have {} rows",
+                    path.display(),
+                    i + 1,
+                    row,
+                    rows
+                ),
+            ));
+        }
+        if listed[row] {
+            return Err(failure(
+                Category::BadInput,
+                format!("{}:{}: row {} is listed twice", path.display(), i + 1, row),
+            ));
+        }
+        listed[row] = true;
+    }
+    Ok(listed)
+}
+
+/// The k-means parameters requested.
+fn clustering_params(args: &VocabularyArgs) -> ClusteringParameters {
+    let mut params = ClusteringParameters::new();
+    params.set_verbose(args.faiss_verbose);
+    // faiss takes a 32-bit seed for its own sampling and initialization
+    params.set_seed(args.seed as u32);
+    if let Some(quality) = args.quality {
+        let (niter, nredo, max_points) = quality.params();
+        params.set_niter(niter);
+        params.set_nredo(nredo);
+        params.set_max_points_per_centroid(max_points);
+    }
+    if let Some(niter) = args.niter {
+        params.set_niter(niter);
+    }
+    if let Some(nredo) = args.nredo {
+        params.set_nredo(nredo);
+    }
+    if let Some(max_points) = args.max_points_per_centroid {
+        params.set_max_points_per_centroid(max_points);
+    }
+    params
+}
+
+/// Cluster the training sample of an hdf5 features file into `k` centroids
+/// per shard of `shard_size` features, reading one shard at a time.
+/// Returns the pooled centroids of all shards, the subset of dimensions
+/// picked, if any, and the number of training features, which decides `k`.
+///
+/// A last shard of fewer than `k` features is pooled as is.
+fn load_coarse_centroids(
+    args: &VocabularyArgs,
+    shard_size: usize,
+) -> DynResult<(Array2<f32>, Option<Vec<usize>>, usize)> {
+    if args.sample == Sampling::Random {
+        return Err(failure(
+            Category::BadInput,
+            "--coarse-then-fine reads the first features, not a random sample",
+        ));
+    }
+    let file = File::open(&args.features, "r")?;
+    let mut data = FeatureSet::open(&file, &args.dataset_name, args.features_transpose)?;
+    if let Some(range) = args.feature_cols {
+        data.select_cols(range)?;
+    }
+    let dims = training_dims(args, data.cols())?;
+    if let Some(ref dims) = dims {
+        data.select_dims(dims.clone())?;
+    }
+    let n = training_size(args, data.rows())?;
+    data.truncate(n);
+    let k = args.size.resolve(n);
+    if shard_size < k as usize {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "shards of {} features cannot be clustered into {} centroids each",
+                shard_size, k
+            ),
+        ));
+    }
+
+    let d = data.cols() as u32;
+    let params = clustering_params(args);
+    let n_shards = (n + shard_size - 1) / shard_size;
+    let progress = ProgressBar::new_spinner();
+    progress.enable_steady_tick(300);
+    let mut pooled = Vec::with_capacity(n_shards);
+    for (i, shard) in data.batches(shard_size).enumerate() {
+        let shard = shard?;
+        let shard = shard.view();
+        if shard.rows() < k as usize {
+            pooled.push(shard.to_owned());
+            continue;
+        }
+        progress.set_message(&format!(
+            "Clustering shard {}/{} into {} components ...",
+            i + 1,
+            n_shards,
+            k
+        ));
+        let mut cluster = Clustering::new_with_params(d, k, &params)?;
+        let mut index = args.metric.flat_index(d)?;
+        let x: Vec<f32> = shard.iter().cloned().collect();
+        cluster.train(&x, &mut index)?;
+        pooled.push(index_centroids(&index, (k as usize, d as usize))?.to_owned());
+    }
+    progress.finish_and_clear();
+    let views: Vec<_> = pooled.iter().map(|c| c.view()).collect();
+    let pooled = ndarray::stack(Axis(0), &views)?;
+    status!(
+        "Pooled {} centroids from {} shards of up to {} features",
+        pooled.rows(),
+        n_shards,
+        shard_size
+    );
+    Ok((pooled, dims, n))
+}
+
+/// Load the training sample from a `.npy` features file,
+/// along with the subset of dimensions picked, if any.
+fn load_training_npy(args: &VocabularyArgs) -> DynResult<(Array2<f32>, Option<Vec<usize>>)> {
+    if args.per_item_cap.is_some() {
+        return Err(failure(
+            Category::BadInput,
+            "--per-item-cap needs item IDs, which .npy inputs do not have",
+        ));
+    }
+    if args.sample_file.is_some() {
+        return Err(failure(
+            Category::BadInput,
+            "--sample-file only supports hdf5 features",
+        ));
+    }
+    let progress = ProgressBar::new_spinner();
+    progress.set_message("Loading features to memory...");
+    let mut features = npy::read_npy(&args.features)?;
+    if args.features_transpose {
+        let (rows, cols) = features.dim();
+        features = Array2::from_shape_vec((cols, rows), features.t().iter().cloned().collect())?;
+    }
+    if let Some(range) = args.feature_cols {
+        if range.end > features.cols() {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "column range {} is out of bounds for features of {} dimensions",
+                    range,
+                    features.cols()
+                ),
+            ));
+        }
+        features = features.slice(s![.., range.start..range.end]).to_owned();
+    }
+    let dims = training_dims(args, features.cols())?;
+    if let Some(ref dims) = dims {
+        features = features.select(Axis(1), dims);
+    }
+    let n = training_size(args, features.rows())?;
+    if args.sample == Sampling::Random {
+        let mut selection = Selection::new(features.rows(), n, args.seed);
+        let rows: Vec<usize> = (0..features.rows()).filter(|_| selection.keep()).collect();
+        features = features.select(Axis(0), &rows);
+    } else if n < features.rows() {
+        features = features.slice(s![..n, ..]).to_owned();
+    }
+    progress.finish_and_clear();
+    Ok((features, dims))
+}
+
+/// The number of features to cluster, out of `rows`.
+fn training_size(args: &VocabularyArgs, rows: usize) -> DynResult<usize> {
+    match (args.n, args.sample_fraction) {
+        (Some(n), _) => Ok(usize::min(n, rows)),
+        (None, Some(f)) if f > 0. && f <= 1. => Ok((f64::from(f) * rows as f64).ceil() as usize),
+        (None, Some(f)) => Err(failure(
+            Category::BadInput,
+            format!("invalid sample fraction {}, expected (0, 1]", f),
+        )),
+        (None, None) => Ok(rows),
+    }
+}
+
+/// Pick the subset of the `d` feature dimensions to cluster, if requested.
+fn training_dims(args: &VocabularyArgs, d: usize) -> DynResult<Option<Vec<usize>>> {
+    match args.subsample_dims {
+        Some(n) if n == 0 || n > d => Err(failure(
+            Category::BadInput,
+            format!("cannot subsample {} of {} feature dimensions", n, d),
+        )),
+        Some(n) => Ok(Some(sample_dims(d, n, args.seed))),
+        None => Ok(None),
+    }
+}
+
+/// The descriptors of all items, one row per item.
+#[derive(Clone)]
+enum Descriptors {
+    /// Histograms of codeword occurrences
+    Counts(Array2<u32>),
+    /// Real valued encodings
+    Real(Array2<f32>),
+}
+
+impl Descriptors {
+    fn dim(&self) -> (usize, usize) {
+        match self {
+            Descriptors::Counts(x) => x.dim(),
+            Descriptors::Real(x) => x.dim(),
+        }
+    }
+
+    /// Swap the rows and columns of the descriptors.
+    fn transposed(self) -> Self {
+        fn transpose<T: Copy>(x: Array2<T>) -> Array2<T> {
+            let (rows, cols) = x.dim();
+            Array2::from_shape_vec((cols, rows), x.t().iter().cloned().collect())
+                .expect("transposed shape should be consistent")
+        }
+        match self {
+            Descriptors::Counts(x) => Descriptors::Counts(transpose(x)),
+            Descriptors::Real(x) => Descriptors::Real(transpose(x)),
+        }
+    }
+
+    /// Take the real valued descriptors, converting counts if needed.
+    fn into_real(self) -> Array2<f32> {
+        match self {
+            Descriptors::Counts(x) => x.mapv(|c| c as f32),
+            Descriptors::Real(x) => x,
+        }
+    }
+
+    fn write_to(&self, dset: &Dataset) -> DynResult<()> {
+        match self {
+            Descriptors::Counts(x) => dset.write(x.view())?,
+            Descriptors::Real(x) => dset.write(x.view())?,
+        }
+        Ok(())
+    }
+
+    /// The number of components of each row which are not zero.
+    fn nonzeros_per_row(&self) -> Vec<usize> {
+        match self {
+            Descriptors::Counts(x) => x
+                .outer_iter()
+                .map(|row| row.iter().filter(|&&v| v != 0).count())
+                .collect(),
+            Descriptors::Real(x) => x
+                .outer_iter()
+                .map(|row| row.iter().filter(|&&v| v != 0.).count())
+                .collect(),
+        }
+    }
+
+    /// The fraction of the components which are not zero.
+    fn density(&self) -> f64 {
+        let (rows, cols) = self.dim();
+        let nonzeros: usize = self.nonzeros_per_row().iter().sum();
+        if rows * cols == 0 {
+            0.
+        } else {
+            nonzeros as f64 / (rows * cols) as f64
+        }
+    }
+
+    /// Write the descriptors to a group in compressed sparse row format:
+    /// the `values` and column `indices` of the components which are
+    /// not zero, row after row, and the offset of each row into them
+    /// in `indptr`. The shape is recorded in the `rows` and `cols` attributes.
+    fn write_sparse_to(self, group: &Group) -> DynResult<()> {
+        let x = self.into_real();
+        let mut indptr = Vec::with_capacity(x.rows() + 1);
+        let mut indices = Vec::new();
+        let mut values = Vec::new();
+        indptr.push(0_u64);
+        for row in x.outer_iter() {
+            for (j, &v) in row.iter().enumerate().filter(|&(_, &v)| v != 0.) {
+                indices.push(j as u32);
+                values.push(v);
+            }
+            indptr.push(indices.len() as u64);
+        }
+        write_attr(group, "rows", &(x.rows() as u64))?;
+        write_attr(group, "cols", &(x.cols() as u64))?;
+        group
+            .new_dataset::<u64>()
+            .no_chunk()
+            .create("indptr", (indptr.len(),))?
+            .write_raw(&indptr)?;
+        group
+            .new_dataset::<u32>()
+            .no_chunk()
+            .create("indices", (indices.len(),))?
+            .write_raw(&indices)?;
+        group
+            .new_dataset::<f32>()
+            .no_chunk()
+            .create("values", (values.len(),))?
+            .write_raw(&values)?;
+        Ok(())
+    }
+
+    /// Write the descriptors to the given group as parallel `row`, `col`
+    /// and `value` data sets of their nonzero components, in row-major order.
+    fn write_coo_to(self, group: &Group) -> DynResult<()> {
+        let x = self.into_real();
+        let mut rows = Vec::new();
+        let mut cols = Vec::new();
+        let mut values = Vec::new();
+        for (i, row) in x.outer_iter().enumerate() {
+            for (j, &v) in row.iter().enumerate().filter(|&(_, &v)| v != 0.) {
+                rows.push(i as u64);
+                cols.push(j as u32);
+                values.push(v);
+            }
+        }
+        write_attr(group, "rows", &(x.rows() as u64))?;
+        write_attr(group, "cols", &(x.cols() as u64))?;
+        group
+            .new_dataset::<u64>()
+            .no_chunk()
+            .create("row", (rows.len(),))?
+            .write_raw(&rows)?;
+        group
+            .new_dataset::<u32>()
+            .no_chunk()
+            .create("col", (cols.len(),))?
+            .write_raw(&cols)?;
+        group
+            .new_dataset::<f32>()
+            .no_chunk()
+            .create("value", (values.len(),))?
+            .write_raw(&values)?;
+        Ok(())
+    }
+}
+
+/// The shape of bags saved in the sparse layout.
+fn sparse_shape(group: &Group) -> DynResult<(usize, usize)> {
+    match (
+        read_attr::<u64>(group, "rows")?,
+        read_attr::<u64>(group, "cols")?,
+    ) {
+        (Some(rows), Some(cols)) => Ok((rows as usize, cols as usize)),
+        _ => Err(failure(
+            Category::BadInput,
+            "sparse bags do not record their shape",
+        )),
+    }
+}
+
+/// Read bags saved in the sparse or COO layout to a dense array.
+fn read_sparse_bags(group: &Group) -> DynResult<Array2<f32>> {
+    let (rows, cols) = sparse_shape(group)?;
+    if read_str_attr(group, "layout")?.as_ref().map(String::as_str) == Some("coo") {
+        return read_coo_bags(group, (rows, cols));
+    }
+    let indptr: Vec<u64> = group.dataset("indptr")?.read_raw()?;
+    let indices: Vec<u32> = group.dataset("indices")?.read_raw()?;
+    let values: Vec<f32> = group.dataset("values")?.read_raw()?;
+    let nonzeros = indptr.last().cloned().unwrap_or_default() as usize;
+    if indptr.len() != rows + 1 || indices.len() != nonzeros || values.len() != nonzeros {
+        return Err(failure(
+            Category::BadInput,
+            "sparse bags are inconsistent with their shape",
+        ));
+    }
+    let mut bags = Array2::zeros((rows, cols));
+    for (i, range) in indptr.windows(2).enumerate() {
+        for k in range[0] as usize..range[1] as usize {
+            let j = indices[k] as usize;
+            if j >= cols {
+                return Err(failure(
+                    Category::BadInput,
+                    format!("sparse bags have a component in column {}", j),
+                ));
+            }
+            bags[(i, j)] = values[k];
+        }
+    }
+    Ok(bags)
+}
+
+/// Read bags saved in the COO layout to a dense array of the given shape.
+fn read_coo_bags(group: &Group, (rows, cols): (usize, usize)) -> DynResult<Array2<f32>> {
+    let row: Vec<u64> = group.dataset("row")?.read_raw()?;
+    let col: Vec<u32> = group.dataset("col")?.read_raw()?;
+    let value: Vec<f32> = group.dataset("value")?.read_raw()?;
+    if row.len() != col.len() || row.len() != value.len() {
+        return Err(failure(
+            Category::BadInput,
+            "COO bags have triplet data sets of different lengths",
+        ));
+    }
+    let mut bags = Array2::zeros((rows, cols));
+    for ((&i, &j), &v) in row.iter().zip(&col).zip(&value) {
+        let (i, j) = (i as usize, j as usize);
+        if i >= rows || j >= cols {
+            return Err(failure(
+                Category::BadInput,
+                format!("COO bags have a component at ({}, {})", i, j),
+            ));
+        }
+        bags[(i, j)] = v;
+    }
+    Ok(bags)
+}
+
+/// Train and save a product quantization vocabulary
+/// of `m` sub-codebooks of `k` codewords.
+///
+/// The sub-codebooks are saved as `codebook/0`, `codebook/1`, ...,
+/// and the `codebook` group describes the layout in its attributes.
+#[allow(clippy::too_many_arguments)]
+fn generate_product_vocabulary(
+    args: &VocabularyArgs,
+    features: ArrayView2<f32>,
+    k: u32,
+    m: u32,
+    params: &ClusteringParameters,
+    max_iter: u32,
+    mean: Option<&Array1<f32>>,
+    dims: Option<&[usize]>,
+    progress: &ProgressBar,
+) -> DynResult<()> {
+    let (n, d) = features.dim();
+    let m = m as usize;
+    if m == 0 || d % m != 0 {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "feature dimensionality {} is not divisible into {} sub-vectors",
+                d, m
+            ),
+        ));
+    }
+    let d_sub = d / m;
+
+    progress.enable_steady_tick(300);
+    let mut codebooks = Vec::with_capacity(m);
+    let mut total_objective = 0.;
+    for j in 0..m {
+        progress.set_message(&format!(
+            "Clustering {} sub-vectors into {} components ({}/{}) ...",
+            n,
+            k,
+            j + 1,
+            m
+        ));
+        let sub = features.slice(s![.., j * d_sub..(j + 1) * d_sub]);
+        let sub: Vec<f32> = sub.iter().cloned().collect();
+        let mut cluster = Clustering::new_with_params(d_sub as u32, k, params)?;
+        let mut index = args.metric.flat_index(d_sub as u32)?;
+        if args.seed_init == SeedInit::KMeansPP {
+            let sub = ArrayView2::from_shape((n, d_sub), &sub[..])?;
+            seed_kmeanspp(&mut cluster, sub, k, params, args.seed)?;
+        }
+        let objective = train_kmeans(&mut cluster, &sub, &mut index, args.tol, max_iter)?;
+        println!(
+            "Sub-codebook {}/{} done. Final objective loss: {}",
+            j + 1,
+            m,
+            objective
+        );
+        total_objective += objective;
+        codebooks.push(index_centroids(&index, (k as usize, d_sub))?.to_owned());
+    }
+    report_training_points(n, k, params);
+    // the sub-vectors' squared distances add up to those of the whole features
+    if args.report_objective_per_point {
+        report_objective_per_point(total_objective, effective_training_points(n, k, params));
+    }
+    if let Some(ref log) = args.objective_log {
+        append_objective_log(log, k, max_iter, args.seed, total_objective)?;
+    }
+    check_objective(total_objective, args.max_objective)?;
+
+    status!("Saving centroids to {} ...", args.out.display());
+
+    let file = open_output(&args.out, args.force)?;
+    for name in &[
+        "data",
+        "variances",
+        "weights",
+        "codebook",
+        "mean",
+        "dims",
+        "centroid_order",
+    ] {
+        remove_existing(&file, name)?;
+    }
+    write_mean(&file, mean)?;
+    write_dims(&file, dims)?;
+    let group = file.create_group("codebook")?;
+    write_str_attr(&group, "layout", "pq")?;
+    write_attr(&group, "m", &(m as u32))?;
+    write_attr(&group, "d_sub", &(d_sub as u32))?;
+    // record the feature composition so that quantization can rebuild it
+    write_str_attr(&group, "features", &args.dataset_name)?;
+    if let Some(range) = args.feature_cols {
+        write_str_attr(&group, "feature_cols", &range.to_string())?;
+    }
+    write_str_attr(&group, "cluster_dtype", args.cluster_dtype.name())?;
+    write_str_attr(&group, "metric", args.metric.as_str())?;
+    for (j, codebook) in codebooks.iter().enumerate() {
+        group
+            .new_dataset::<f32>()
+            .no_chunk()
+            .create(&j.to_string(), codebook.dim())?
+            .write(codebook.view())?;
+    }
+    let hash = codebook_hash(stack_codebooks(&codebooks).view());
+    write_str_attr(&file, "vocab_hash", &hash)?;
+    write_manifest(
+        &file,
+        &[("features_file_hash", args.features.as_path())],
+        !args.no_manifest_hash,
+    )?;
+
+    Ok(())
+}
+
+/// Print the mean objective per point of the `n` training points
+/// it was computed over.
+fn report_objective_per_point(objective: f32, n: usize) {
+    if n > 0 {
+        println!(
+            "Objective per point: {} (over {} training points)",
+            objective / n as f32,
+            n
+        );
+    }
+}
+
+/// The number of the `n` training features which faiss actually clusters
+/// into `k` centroids, since it subsamples them down to
+/// `max_points_per_centroid` per centroid.
+fn effective_training_points(n: usize, k: u32, params: &ClusteringParameters) -> usize {
+    usize::min(n, k as usize * params.max_points_per_centroid() as usize)
+}
+
+/// Report how many of the `n` training features faiss clustered,
+/// and whether it subsampled them.
+fn report_training_points(n: usize, k: u32, params: &ClusteringParameters) {
+    let used = effective_training_points(n, k, params);
+    if used < n {
+        status!(
+            "faiss clustered {} of the {} training features, subsampled to {} per centroid \
+             (see --max-points-per-centroid)",
+            used,
+            n,
+            params.max_points_per_centroid()
+        );
+    } else {
+        status!("faiss clustered all {} training features", n);
+    }
+}
+
+/// Fail if the final objective exceeds the maximum allowed, if any.
+fn check_objective(objective: f32, max_objective: Option<f32>) -> DynResult<()> {
+    match max_objective {
+        Some(max) if objective > max || objective.is_nan() => Err(failure(
+            Category::BadInput,
+            format!(
+                "final objective {} exceeds the maximum of {}",
+                objective, max
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Fail if the fraction of the `k` clusters to which no training feature
+/// was assigned exceeds the maximum allowed.
+fn check_empty_clusters(labels: &[i64], k: usize, max_empty_frac: f64) -> DynResult<()> {
+    let mut sizes = vec![0_usize; k];
+    for &label in labels.iter().filter(|&&label| label >= 0) {
+        sizes[label as usize] += 1;
+    }
+    let empty = sizes.iter().filter(|&&size| size == 0).count();
+    let fraction = empty as f64 / k as f64;
+    status!(
+        "{} of {} clusters are empty ({:.2}%)",
+        empty,
+        k,
+        fraction * 100.
+    );
+    if fraction > max_empty_frac {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "fraction of empty clusters {} exceeds the maximum of {}",
+                fraction, max_empty_frac
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Append a row with the parameters and final objective of a run
+/// to the CSV log at `path`, writing the header if the log is new.
+fn append_objective_log(
+    path: &Path,
+    k: u32,
+    niter: u32,
+    seed: u64,
+    objective: f32,
+) -> DynResult<()> {
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| {
+            failure(
+                Category::Io,
+                format!("could not open objective log {}: {}", path.display(), e),
+            )
+        })?;
+    if log.metadata()?.len() == 0 {
+        writeln!(log, "timestamp,k,niter,seed,objective")?;
+    }
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    writeln!(log, "{},{},{},{},{}", timestamp, k, niter, seed, objective)?;
+    Ok(())
+}
+
+/// Write the mean of the training sample to the `mean` data set,
+/// if the features were centered.
+fn write_mean(file: &Group, mean: Option<&Array1<f32>>) -> DynResult<()> {
+    if let Some(mean) = mean {
+        file.new_dataset::<f32>()
+            .no_chunk()
+            .create("mean", (mean.len(),))?
+            .write(mean.view())?;
+    }
+    Ok(())
+}
+
+/// The mean and (population) variance of each column of `x`,
+/// computed in a single pass over its rows with Welford's algorithm.
+fn column_stats(x: ArrayView2<f32>) -> (Array1<f32>, Array1<f32>) {
+    let mut mean = Array1::<f64>::zeros(x.cols());
+    let mut m2 = Array1::<f64>::zeros(x.cols());
+    for (n, row) in x.outer_iter().enumerate() {
+        let n = (n + 1) as f64;
+        for ((&v, mean), m2) in row.iter().zip(mean.iter_mut()).zip(m2.iter_mut()) {
+            let v = f64::from(v);
+            let delta = v - *mean;
+            *mean += delta / n;
+            *m2 += delta * (v - *mean);
+        }
+    }
+    let n = x.rows().max(1) as f64;
+    (mean.mapv(|v| v as f32), m2.mapv(|v| (v / n) as f32))
+}
+
+/// Write the mean and variance of the training sample,
+/// replacing those already in the file.
+fn write_feature_stats(file: &File, (mean, var): &(Array1<f32>, Array1<f32>)) -> DynResult<()> {
+    remove_existing(file, "feature_mean")?;
+    remove_existing(file, "feature_var")?;
+    file.new_dataset::<f32>()
+        .no_chunk()
+        .create("feature_mean", (mean.len(),))?
+        .write(mean.view())?;
+    file.new_dataset::<f32>()
+        .no_chunk()
+        .create("feature_var", (var.len(),))?
+        .write(var.view())?;
+    Ok(())
+}
+
+/// Load the mean to subtract from the features before assignment,
+/// if the given vocabulary was built from centered features.
+fn load_mean(vocabulary: &Path) -> DynResult<Option<Array1<f32>>> {
+    let file = File::open(vocabulary, "r")?;
+    if !file.link_exists("mean") {
+        return Ok(None);
+    }
+    Ok(Some(file.dataset("mean")?.read_1d()?))
+}
+
+/// Write the feature dimensions in use to the `dims` data set,
+/// if only a subset of them was clustered.
+fn write_dims(file: &Group, dims: Option<&[usize]>) -> DynResult<()> {
+    if let Some(dims) = dims {
+        let dims: Vec<u32> = dims.iter().map(|&dim| dim as u32).collect();
+        file.new_dataset::<u32>()
+            .no_chunk()
+            .create("dims", (dims.len(),))?
+            .write_raw(&dims)?;
+    }
+    Ok(())
+}
+
+/// Load the subset of feature dimensions to use before assignment,
+/// if the given vocabulary was built from a subset of them.
+fn load_dims(vocabulary: &Path) -> DynResult<Option<Vec<usize>>> {
+    let file = File::open(vocabulary, "r")?;
+    if !file.link_exists("dims") {
+        return Ok(None);
+    }
+    let dims: Vec<u32> = file.dataset("dims")?.read_raw()?;
+    Ok(Some(dims.into_iter().map(|dim| dim as usize).collect()))
+}
+
+/// Remove the rows which are exact duplicates of an earlier row,
+/// as identified by the hash of their bytes.
+fn dedup_rows(features: Array2<f32>) -> Array2<f32> {
+    let mut seen = HashSet::with_capacity(features.rows());
+    let mut bytes = Vec::with_capacity(features.cols() * 4);
+    let keep: Vec<usize> = features
+        .outer_iter()
+        .enumerate()
+        .filter(|(_, row)| seen.insert(row_hash(row.view(), &mut bytes)))
+        .map(|(i, _)| i)
+        .collect();
+    if keep.len() == features.rows() {
+        features
+    } else {
+        features.select(Axis(0), &keep)
+    }
+}
+
+/// Hash the bytes of a row, using `bytes` as a buffer.
+fn row_hash(row: ArrayView1<f32>, bytes: &mut Vec<u8>) -> blake3::Hash {
+    bytes.clear();
+    for v in row {
+        bytes.extend_from_slice(&v.to_bits().to_le_bytes());
+    }
+    blake3::hash(bytes)
+}
+
+/// Pick `n` of `d` dimensions at random, in increasing order.
+fn sample_dims(d: usize, n: usize, seed: u64) -> Vec<usize> {
+    let mut selection = Selection::new(d, n, seed);
+    (0..d).filter(|_| selection.keep()).collect()
+}
+
+/// A random selection of `n` out of `total` indices, all equally likely,
+/// decided one index at a time in increasing order (selection sampling),
+/// with a simple deterministic generator (splitmix64).
+struct Selection {
+    state: u64,
+    /// the number of indices still to select
+    needed: usize,
+    /// the number of indices still to decide on
+    left: usize,
+}
+
+impl Selection {
+    fn new(total: usize, n: usize, seed: u64) -> Self {
+        Selection {
+            state: seed,
+            needed: usize::min(n, total),
+            left: total,
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        splitmix64(&mut self.state)
+    }
+
+    /// Decide whether the next index is selected.
+    fn keep(&mut self) -> bool {
+        if self.left == 0 {
+            return false;
+        }
+        let keep = self.next_u64() % (self.left as u64) < self.needed as u64;
+        self.left -= 1;
+        if keep {
+            self.needed -= 1;
+        }
+        keep
+    }
+}
+
+/// Advance the state of a splitmix64 generator, returning its next output.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Initialize the centroids of the clustering, so that the next training
+/// starts from them.
+///
+/// The centroids are handed to faiss by training on them alone:
+/// with exactly `k` training points, it copies them to the centroids.
+fn init_centroids(cluster: &mut Clustering, centroids: ArrayView2<f32>) -> DynResult<()> {
+    let mut index = FlatIndex::new_l2(centroids.cols() as u32)?;
+    let centroids: Vec<f32> = centroids.iter().cloned().collect();
+    cluster.train(&centroids, &mut index)?;
+    Ok(())
+}
+
+/// Initialize the centroids of the clustering with k-means++ seeding
+/// over the training features `x`, so that training starts from them.
+///
+/// Like faiss itself, the seeding only looks at up to
+/// `max_points_per_centroid` features per centroid, picked at random.
+fn seed_kmeanspp(
+    cluster: &mut Clustering,
+    x: ArrayView2<f32>,
+    k: u32,
+    params: &ClusteringParameters,
+    seed: u64,
+) -> DynResult<()> {
+    let n = usize::min(
+        x.rows(),
+        k as usize * params.max_points_per_centroid() as usize,
+    );
+    let seeds = if n < x.rows() {
+        let mut selection = Selection::new(x.rows(), n, seed);
+        let rows: Vec<usize> = (0..x.rows()).filter(|_| selection.keep()).collect();
+        kmeanspp_seeds(x.select(Axis(0), &rows).view(), k as usize, seed)
+    } else {
+        kmeanspp_seeds(x, k as usize, seed)
+    };
+    init_centroids(cluster, seeds.view())
+}
+
+/// Re-seed the empty clusters of a trained index by splitting the most
+/// populous clusters in two, and continue clustering `x` from there for
+/// `niter` iterations. Returns the number of re-seeded clusters
+/// and the new objective, if any cluster was empty.
+///
+/// Like faiss does when it finds an empty cluster during training, each
+/// split moves the two halves slightly apart, one dimension at a time.
+fn recluster_empty(
+    index: &mut FlatIndex,
+    x: ArrayView2<f32>,
+    k: u32,
+    niter: u32,
+    params: &ClusteringParameters,
+) -> DynResult<Option<(usize, f32)>> {
+    const EPS: f32 = 1. / 1024.;
+    let (k, d) = (k as usize, x.cols());
+    let labels = index
+        .assign(x.as_slice().expect("array must be in standard order"), 1)?
+        .labels;
+    let mut sizes = vec![0_usize; k];
+    for &label in labels.iter().filter(|&&label| label >= 0) {
+        sizes[label as usize] += 1;
+    }
+    let empty: Vec<usize> = (0..k).filter(|&c| sizes[c] == 0).collect();
+    if empty.is_empty() {
+        return Ok(None);
+    }
+
+    let mut centroids = index_centroids(index, (k, d))?.to_owned();
+    for &e in &empty {
+        let (c, &size) = sizes
+            .iter()
+            .enumerate()
+            .max_by_key(|&(_, &size)| size)
+            .expect("there should be clusters");
+        if size < 2 {
+            // nothing left to split
+            break;
+        }
+        let source = centroids.row(c).to_owned();
+        for j in 0..d {
+            let (grow, shrink) = (source[j] * (1. + EPS), source[j] * (1. - EPS));
+            let (to_c, to_e) = if j % 2 == 0 {
+                (grow, shrink)
+            } else {
+                (shrink, grow)
+            };
+            centroids[(c, j)] = to_c;
+            centroids[(e, j)] = to_e;
+        }
+        sizes[e] = size / 2;
+        sizes[c] = size - size / 2;
+    }
+
+    let mut params_continued = ClusteringParameters::new();
+    params_continued.set_niter(niter);
+    params_continued.set_max_points_per_centroid(params.max_points_per_centroid());
+    params_continued.set_verbose(params.verbose());
+    let mut cluster = Clustering::new_with_params(d as u32, k as u32, &params_continued)?;
+    init_centroids(&mut cluster, centroids.view())?;
+    let mut continued = FlatIndex::new_l2(d as u32)?;
+    cluster.train(
+        x.as_slice().expect("array must be in standard order"),
+        &mut continued,
+    )?;
+    *index = continued;
+    let objective = cluster
+        .objectives()?
+        .last()
+        .cloned()
+        .unwrap_or(std::f32::INFINITY);
+    Ok(Some((empty.len(), objective)))
+}
+
+/// Load the centroids to keep fixed while training the others
+/// of a codebook of `k` centroids of `d` dimensions.
+fn load_frozen_centroids(path: &Path, k: usize, d: usize) -> DynResult<Array2<f32>> {
+    let frozen = load_codebook(path, "data")?;
+    if frozen.cols() != d {
+        return Err(failure(
+            Category::DimensionMismatch,
+            format!(
+                "frozen centroids have {} dimensions, but the features have {}",
+                frozen.cols(),
+                d
+            ),
+        ));
+    }
+    if frozen.rows() >= k {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "{} frozen centroids leave none of the k = {} centroids to train",
+                frozen.rows(),
+                k
+            ),
+        ));
+    }
+    status!(
+        "Keeping {} frozen centroids, training {} new ones",
+        frozen.rows(),
+        k - frozen.rows()
+    );
+    Ok(frozen)
+}
+
+/// Cluster `x` into `k` centroids, the first of which are the `frozen` ones,
+/// kept as they are. The new centroids are first trained on the residual
+/// data, the features farther from the frozen centroids than the median,
+/// and then refined on all features by `niter` iterations of Lloyd's
+/// algorithm in which only the new centroids move, each to the mean of
+/// the features assigned to it. Returns all centroids along with their
+/// objective over all features, after the last update.
+fn cluster_with_frozen(
+    frozen: ArrayView2<f32>,
+    x: ArrayView2<f32>,
+    k: usize,
+    params: &ClusteringParameters,
+) -> DynResult<(Array2<f32>, f32)> {
+    let (f, d) = frozen.dim();
+    let k_new = k - f;
+    let data = x.as_slice().expect("array must be in standard order");
+    let (_, distances) = nearest_with_distances(&mut codebook_index(frozen)?, data, false)?;
+    let mut sorted = distances.clone();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let median = sorted[sorted.len() / 2];
+    let residual: Vec<usize> = (0..x.rows()).filter(|&i| distances[i] >= median).collect();
+    if residual.len() < k_new {
+        return Err(failure(
+            Category::EmptyData,
+            format!(
+                "{} residual features are too few to train {} new centroids",
+                residual.len(),
+                k_new
+            ),
+        ));
+    }
+    let residual = x.select(Axis(0), &residual);
+    let mut cluster = Clustering::new_with_params(d as u32, k_new as u32, params)?;
+    let mut index = FlatIndex::new_l2(d as u32)?;
+    cluster.train(
+        residual
+            .as_slice()
+            .expect("array must be in standard order"),
+        &mut index,
+    )?;
+    let mut centroids = ndarray::stack(Axis(0), &[frozen, index_centroids(&index, (k_new, d))?])?;
+
+    for _ in 0..params.niter() {
+        let labels = nearest(&mut codebook_index(centroids.view())?, data, false)?;
+        let mut sums = Array2::<f64>::zeros((k_new, d));
+        let mut counts = vec![0_usize; k_new];
+        for (row, &label) in x.outer_iter().zip(&labels) {
+            if label < f as i64 {
+                continue;
+            }
+            let c = label as usize - f;
+            counts[c] += 1;
+            for (sum, &v) in sums.row_mut(c).iter_mut().zip(row.iter()) {
+                *sum += f64::from(v);
+            }
+        }
+        // empty clusters keep their centroid
+        for (c, &count) in counts.iter().enumerate().filter(|&(_, &count)| count > 0) {
+            let mean = sums.row(c).mapv(|v| (v / count as f64) as f32);
+            centroids.row_mut(f + c).assign(&mean);
+        }
+    }
+    let objective = training_objective(&mut codebook_index(centroids.view())?, x)?;
+    Ok((centroids, objective))
+}
+
+/// The k-means objective of the centroids in the index over all of the
+/// features: the sum of their squared distances to the nearest centroid.
+fn training_objective(index: &mut FlatIndex, features: ArrayView2<f32>) -> DynResult<f32> {
+    let mut objective = 0_f64;
+    for batch in features.axis_chunks_iter(Axis(0), BATCH_SIZE) {
+        let batch = batch
+            .as_slice()
+            .expect("features should be in standard layout");
+        let result = index.search(batch, 1)?;
+        objective += result.distances.iter().map(|&d| f64::from(d)).sum::<f64>();
+    }
+    Ok(objective as f32)
+}
+
+/// Merge each group of centroids closer than `threshold` (in L2 distance)
+/// to the first of them into their mean, going through the centroids
+/// in order. Returns `None` if no two centroids are that close.
+fn merge_close_centroids(centroids: ArrayView2<f32>, threshold: f32) -> Option<Array2<f32>> {
+    let k = centroids.rows();
+    let mut merged = vec![false; k];
+    let mut means = Vec::with_capacity(k);
+    for i in 0..k {
+        if merged[i] {
+            continue;
+        }
+        let mut sum = centroids.row(i).to_owned();
+        let mut n = 1;
+        for j in i + 1..k {
+            let distance = Iterator::zip(centroids.row(i).iter(), centroids.row(j).iter())
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>()
+                .sqrt();
+            if !merged[j] && distance < threshold {
+                merged[j] = true;
+                sum += &centroids.row(j);
+                n += 1;
+            }
+        }
+        sum /= n as f32;
+        means.push(sum);
+    }
+    if means.len() == k {
+        return None;
+    }
+    let mut out = Array2::zeros((means.len(), centroids.cols()));
+    for (mut row, mean) in out.outer_iter_mut().zip(means) {
+        row.assign(&mean);
+    }
+    Some(out)
+}
+
+/// Pick `k` of the rows of `x` by k-means++: the first one uniformly,
+/// and each of the next ones with a probability proportional to its squared
+/// distance to the nearest row picked so far.
+fn kmeanspp_seeds(x: ArrayView2<f32>, k: usize, seed: u64) -> Array2<f32> {
+    let n = x.rows();
+    let mut state = seed;
+    let mut seeds = Array2::zeros((k, x.cols()));
+    let mut distances = vec![std::f32::INFINITY; n];
+    let mut pick = (splitmix64(&mut state) % n as u64) as usize;
+    for c in 0..k {
+        seeds.row_mut(c).assign(&x.row(pick));
+        for (distance, row) in distances.iter_mut().zip(x.outer_iter()) {
+            let d = Iterator::zip(row.iter(), seeds.row(c).iter())
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>();
+            *distance = f32::min(*distance, d);
+        }
+        let total: f64 = distances.iter().map(|&d| f64::from(d)).sum();
+        // a uniform number in [0, 1), from the top 53 bits
+        let u = (splitmix64(&mut state) >> 11) as f64 / (1_u64 << 53) as f64;
+        pick = if total > 0. {
+            let mut target = u * total;
+            distances
+                .iter()
+                .position(|&d| {
+                    target -= f64::from(d);
+                    target < 0.
+                })
+                .unwrap_or(n - 1)
+        } else {
+            // all rows coincide with the seeds so far
+            (u * n as f64) as usize
+        };
+    }
+    seeds
+}
+
+/// Train the clustering on `x`, returning the final objective.
+///
+/// Without a tolerance, training runs for as many iterations as the
+/// clustering was configured with. Otherwise, the clustering must be
+/// configured for a single iteration: it is trained repeatedly, resuming
+/// from the previous centroids, until the relative improvement of the
+/// objective drops below `tol` or `max_iter` iterations were run.
+fn train_kmeans(
+    cluster: &mut Clustering,
+    x: &[f32],
+    index: &mut FlatIndex,
+    tol: Option<f32>,
+    max_iter: u32,
+) -> DynResult<f32> {
+    let tol = match tol {
+        Some(tol) => tol,
+        None => {
+            cluster.train(x, index)?;
+            return Ok(cluster
+                .objectives()?
+                .last()
+                .cloned()
+                .unwrap_or(std::f32::INFINITY));
+        }
+    };
+
+    let mut last = std::f32::INFINITY;
+    for i in 1..=max_iter {
+        if interrupt::interrupted() {
+            break;
+        }
+        cluster.train(x, index)?;
+        let objective = cluster
+            .objectives()?
+            .last()
+            .cloned()
+            .unwrap_or(std::f32::INFINITY);
+        let improvement = (last - objective) / last;
+        last = objective;
+        if improvement.is_finite() && improvement < tol {
+            status!(
+                "Stopped at iteration {}/{}: relative improvement {} below tolerance",
+                i,
+                max_iter,
+                improvement
+            );
+            break;
+        }
+    }
+    Ok(last)
+}
+
+/// View the vectors stored in a flat index as a matrix of `k` centroids
+/// of `d` dimensions, checking that the index holds exactly that many values.
+fn index_centroids(index: &FlatIndex, (k, d): (usize, usize)) -> DynResult<ArrayView2<f32>> {
+    let xb = index.xb();
+    if xb.len() != k * d {
+        return Err(failure(
+            Category::DimensionMismatch,
+            format!(
+                "the index holds {} values, but {} centroids of {} dimensions were expected",
+                xb.len(),
+                k,
+                d
+            ),
+        ));
+    }
+    Ok(ArrayView2::from_shape((k, d), xb)?)
+}
+
+/// The order of the centroids by increasing norm,
+/// with ties broken by comparing their coordinates.
+fn sorted_centroid_order(centroids: ArrayView2<f32>) -> Vec<usize> {
+    let norms: Vec<f32> = centroids
+        .outer_iter()
+        .map(|c| c.iter().map(|v| v * v).sum())
+        .collect();
+    let mut order: Vec<usize> = (0..centroids.rows()).collect();
+    order.sort_by(|&a, &b| {
+        norms[a]
+            .partial_cmp(&norms[b])
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| {
+                Iterator::zip(centroids.row(a).iter(), centroids.row(b).iter())
+                    .map(|(x, y)| x.partial_cmp(y).unwrap_or(std::cmp::Ordering::Equal))
+                    .find(|o| *o != std::cmp::Ordering::Equal)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+    });
+    order
+}
+
+/// Stack the sub-codebooks of a product codebook into a single matrix.
+fn stack_codebooks(codebooks: &[Array2<f32>]) -> Array2<f32> {
+    let views: Vec<_> = codebooks.iter().map(|c| c.view()).collect();
+    ndarray::stack(Axis(0), &views).expect("sub-codebooks should have the same shape")
+}
+
+/// Load the sub-codebooks of a product quantization vocabulary,
+/// if the given vocabulary file has one.
+fn load_product_codebooks(file: &File, name: &str) -> DynResult<Option<Vec<Array2<f32>>>> {
+    if !file.link_exists(name) {
+        return Ok(None);
+    }
+    let group = match file.group(name) {
+        Ok(group) => group,
+        // a data set, hence a flat codebook
+        Err(_) => return Ok(None),
+    };
+    match read_str_attr(&group, "layout")? {
+        Some(ref layout) if layout == "pq" => {}
+        layout => {
+            return Err(failure(
+                Category::BadInput,
+                format!("unsupported codebook layout {:?}", layout),
+            ))
+        }
+    }
+    let m: u32 = read_attr(&group, "m")?
+        .ok_or_else(|| failure(Category::BadInput, "product codebook has no `m` attribute"))?;
+    let codebooks = (0..m)
+        .map(|j| group.dataset(&j.to_string())?.read_2d())
+        .collect::<h5::Result<Vec<_>>>()?;
+    Ok(Some(codebooks))
+}
+
+/// Print the path and shape of every data set in an hdf5 file,
+/// under a header with the file name.
+fn list_datasets(path: &Path) -> DynResult<()> {
+    let file = File::open(path, "r")?;
+    println!("{}:", path.display());
+    print_datasets(&file, "")
+}
+
+/// Print the data sets in `group` and its subgroups,
+/// their paths starting with `prefix`.
+fn print_datasets(group: &Group, prefix: &str) -> DynResult<()> {
+    for name in group.member_names()? {
+        let path = format!("{}/{}", prefix, name);
+        match group.group(&name) {
+            Ok(subgroup) => print_datasets(&subgroup, &path)?,
+            Err(_) => {
+                let dset = group.dataset(&name)?;
+                println!("  {} {:?}", path, dset.shape());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Determine where the codebook is in the given vocabulary file:
+/// the `codebook` group of a product codebook if there is one,
+/// and the `data` data set otherwise.
+fn default_codebook_name(vocabulary: &Path) -> DynResult<String> {
+    let file = File::open(vocabulary, "r")?;
+    let name = if file.link_exists("codebook") {
+        "codebook"
+    } else {
+        "data"
+    };
+    Ok(name.to_string())
+}
+
+/// Retrieve the feature composition recorded alongside the codebook at
+/// `codebook_name` in the given vocabulary file,
+/// falling back to the `data` feature data set.
+fn vocabulary_features_name(vocabulary: &Path, codebook_name: &str) -> DynResult<String> {
+    let name = read_codebook_attr(vocabulary, codebook_name, "features")?;
+    Ok(name.unwrap_or_else(|| "data".to_string()))
+}
+
+/// Retrieve the feature column range recorded alongside the codebook at
+/// `codebook_name` in the given vocabulary file, if any.
+fn vocabulary_feature_cols(
+    vocabulary: &Path,
+    codebook_name: &str,
+) -> DynResult<Option<ColumnRange>> {
+    match read_codebook_attr(vocabulary, codebook_name, "feature_cols")? {
+        Some(range) => Ok(Some(range.parse()?)),
+        None => Ok(None),
+    }
+}
+
+/// Read a string attribute of the codebook at `codebook_name`,
+/// be it a data set or a product codebook group.
+fn read_codebook_attr(
+    vocabulary: &Path,
+    codebook_name: &str,
+    attr: &str,
+) -> DynResult<Option<String>> {
+    let file = File::open(vocabulary, "r")?;
+    match file.group(codebook_name) {
+        Ok(group) => read_str_attr(&group, attr),
+        Err(_) => read_str_attr(&file.dataset(codebook_name)?, attr),
+    }
+}
+
+/// Load the Gaussian mixture model in the given vocabulary file.
+fn load_gmm(vocabulary: &Path, name: &str) -> DynResult<Gmm> {
+    let file = File::open(vocabulary, "r")?;
+    let means = file.dataset(name)?.read_2d()?;
+    let variances = file
+        .dataset("variances")
+        .map_err(|_| {
+            failure(
+                Category::BadInput,
+                "vocabulary has no mixture model, create one with `--gmm`",
+            )
+        })?
+        .read_2d()?;
+    let weights = file.dataset("weights")?.read_1d()?;
+    Ok(Gmm {
+        means,
+        variances,
+        weights,
+    })
+}
+
+/// Load the codebook at `name` in the given vocabulary file.
+fn load_codebook(vocabulary: &Path, name: &str) -> DynResult<Array2<f32>> {
+    let file = File::open(vocabulary, "r")?;
+    let vocabulary_dset = file.dataset(name)?;
+    read_centroids(&vocabulary_dset)
+}
+
+/// Read the centroids in a codebook data set as 32-bit floats,
+/// whichever type they were saved in.
+fn read_centroids(dset: &Dataset) -> DynResult<Array2<f32>> {
+    match read_str_attr(dset, "centroid_dtype")?
+        .as_ref()
+        .map(String::as_str)
+    {
+        None | Some("f32") => Ok(dset.read_2d()?),
+        Some("f16") if f16::is_half(dset)? => f16::read_dataset(dset),
+        // the bits of the half precision floats, as saved by older versions
+        Some("f16") => Ok(f16::decode(dset.read_2d::<u16>()?.view())),
+        Some(dtype) => Err(failure(
+            Category::BadInput,
+            format!("unsupported centroid type `{}` in the vocabulary", dtype),
+        )),
+    }
+}
+
+/// Compute the content hash of a codebook,
+/// from the little endian bytes of its centroids in row-major order.
+fn codebook_hash(codebook: ArrayView2<f32>) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for v in codebook.iter() {
+        hasher.update(&v.to_le_bytes());
+    }
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Retrieve the content hash of the codebook in the given vocabulary file,
+/// computing it if the file does not record one.
+fn vocabulary_hash(vocabulary: &Location, codebook: ArrayView2<f32>) -> DynResult<String> {
+    Ok(read_str_attr(vocabulary, "vocab_hash")?.unwrap_or_else(|| codebook_hash(codebook)))
+}
+
+/// Load the codebook in the given vocabulary file into a flat L2 index.
+fn load_codebook_index(vocabulary: &Path) -> DynResult<FlatIndex> {
+    codebook_index(load_codebook(vocabulary, "data")?.view())
+}
+
+/// The codebook and everything else needed to quantize feature files,
+/// loaded once for all of them.
+struct QuantizeContext {
+    /// The codebook centroids, `k x d`
+    /// (or the stacked sub-codebooks of a product codebook)
+    codebook: Array2<f32>,
+    /// Assigns features to histogram bins, leaving out the excluded ones
+    quantizer: Quantizer,
+    /// The mixture model, for Fisher vector encoding
+    gmm: Option<Gmm>,
+    /// The inverse document frequency of each codeword, for TF-IDF weighting
+    idf: Option<Array1<f32>>,
+    /// The mean to subtract from the features, if they were centered
+    mean: Option<Array1<f32>>,
+    /// The subset of feature dimensions in use, within the column range
+    dims: Option<Vec<usize>>,
+    /// The feature composition
+    features_name: String,
+    /// The range of feature columns in use
+    feature_cols: Option<ColumnRange>,
+    /// The content hash of the codebook
+    vocab_hash: String,
+    /// Where the bins of each codebook start when quantizing against
+    /// several, followed by the total number of bins
+    codebook_offsets: Option<Vec<u64>>,
+    /// The new name of each item to rename
+    item_renames: Option<HashMap<String, String>>,
+}
+
+/// Train a vocabulary and quantize features over it, reusing the trained
+/// codebook instead of reading it back from the vocabulary file.
+fn run_pipeline(args: PipelineArgs) -> DynResult<()> {
+    let vocabulary = args.vocabulary;
+    if vocabulary.list_datasets {
+        generate_vocabulary(vocabulary)?;
+        return Ok(());
+    }
+    // parsed before training, so that mistakes in them fail early
+    let matches = QuantizeArgs::clap()
+        .bin_name("cluster-bob pipeline --")
+        .get_matches_from_safe(
+            std::iter::once(OsString::from("quantize"))
+                .chain(std::iter::once(vocabulary.out.clone().into_os_string()))
+                .chain(args.quantize_args.into_iter().map(OsString::from)),
+        )
+        .map_err(|e| failure(Category::BadInput, e.message))?;
+    let mut quantize = QuantizeArgs::from_clap(&matches);
+    if quantize.vocab_name.is_some() {
+        return Err(failure(
+            Category::BadInput,
+            "--vocab-name is not supported after training a vocabulary",
+        ));
+    }
+    if quantize.features.is_empty() && !quantize.features_stdin {
+        if vocabulary.input_format != InputFormat::Hdf5 {
+            return Err(failure(
+                Category::BadInput,
+                "the features to quantize must be given after `--` for .npy training features",
+            ));
+        }
+        quantize.features.push(vocabulary.features.clone());
+    }
+    if quantize.cache_dir.is_none() {
+        quantize.cache_dir = vocabulary.cache_dir.clone();
+    }
+
+    let codebook = generate_vocabulary(vocabulary)?;
+    // the vocabulary is read back from its final path
+    output::commit()?;
+    generate_descriptors(quantize, codebook)
+}
+
+/// Fail if the features have another number of dimensions than the codebook,
+/// suggesting `--features-transpose` if requested.
+fn check_feature_dim(
+    features: &FeatureSet,
+    feature_dim: usize,
+    features_path: &Path,
+    suggest_transpose: bool,
+) -> DynResult<()> {
+    if features.cols() != feature_dim {
+        return Err(failure(
+            Category::DimensionMismatch,
+            format!(
+                "features in {} have {} dimensions, but the codebook has {}{}",
+                features_path.display(),
+                features.cols(),
+                feature_dim,
+                if suggest_transpose {
+                    " (see --features-transpose for features stored one per column)"
+                } else {
+                    ""
+                }
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Fail unless the features fit the flat codebook of the given vocabulary
+/// file, which is assigned to directly. A product codebook is checked
+/// against its full width of `m*d_sub` dimensions, and then rejected.
+fn check_flat_vocabulary_dim(
+    vocabulary: &Path,
+    features: &FeatureSet,
+    features_path: &Path,
+) -> DynResult<()> {
+    let file = File::open(vocabulary, "r")?;
+    let name = default_codebook_name(vocabulary)?;
+    let (_, d) = codebook_shape(&file, &name)?;
+    check_feature_dim(features, d, features_path, false)?;
+    if file.group(&name).is_ok() {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "{} has a product codebook, which only `quantize` supports",
+                vocabulary.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The number of codewords and the dimensionality of the codebook at `name`
+/// in the given vocabulary file, read from the shapes of its data sets.
+/// The codewords of a product codebook are those of all sub-codebooks.
+fn codebook_shape(vocabulary: &Group, name: &str) -> DynResult<(usize, usize)> {
+    let group = match vocabulary.group(name) {
+        Ok(group) => group,
+        Err(_) => {
+            let shape = vocabulary.dataset(name)?.shape();
+            if shape.len() != 2 {
+                return Err(failure(
+                    Category::BadInput,
+                    format!(
+                        "codebook `{}` should be two-dimensional, found shape {:?}",
+                        name, shape
+                    ),
+                ));
+            }
+            return Ok((shape[0], shape[1]));
+        }
+    };
+    let m: u32 = read_attr(&group, "m")?
+        .ok_or_else(|| failure(Category::BadInput, "product codebook has no `m` attribute"))?;
+    let (mut k, mut d) = (0, 0);
+    for j in 0..m {
+        let shape = group.dataset(&j.to_string())?.shape();
+        k += shape[0];
+        d += shape[1];
+    }
+    Ok((k, d))
+}
+
+/// Fail if the codebook at `name` in the given vocabulary file was trained
+/// with another metric than `metric`. Vocabularies which do not record
+/// their metric were trained with L2, the only one supported then.
+fn check_vocabulary_metric(vocabulary: &Path, name: &str, metric: Metric) -> DynResult<()> {
+    let trained = read_codebook_attr(vocabulary, name, "metric")?;
+    let trained = trained.as_ref().map_or("l2", String::as_str);
+    if trained != metric.as_str() {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "{} was trained with the {} metric, but features would be assigned with {}",
+                vocabulary.display(),
+                trained,
+                metric.as_str()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Check that each features file can be quantized over the vocabulary,
+/// with the same feature composition, dimensions and centering as `quantize`
+/// would use, from the shapes and attributes of the data sets alone.
+/// Remote inputs are rejected rather than downloaded.
+/// Fails at the first features file which cannot.
+fn check_dims_only(args: &QuantizeArgs) -> DynResult<()> {
+    let inputs = std::iter::once(&args.vocabulary)
+        .chain(&args.add_vocabulary)
+        .chain(&args.features);
+    for path in inputs {
+        if remote::is_url(path) {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "--dim-check-only does not download remote inputs such as {}",
+                    path.display()
+                ),
+            ));
+        }
+    }
+    args.metric.check_supported()?;
+    if args.features.is_empty() {
+        return Err(failure(Category::BadInput, "no features file given"));
+    }
+
+    let vocab_name = match args.vocab_name {
+        Some(ref name) => name.clone(),
+        None => default_codebook_name(&args.vocabulary)?,
+    };
+    let vocab_file = File::open(&args.vocabulary, "r")?;
+    let (k, d) = codebook_shape(&vocab_file, &vocab_name)?;
+    check_vocabulary_metric(&args.vocabulary, &vocab_name, args.metric)?;
+    let product = vocab_file.group(&vocab_name).is_ok();
+    if let Some(ref path) = args.codeword_subset {
+        if product {
+            return Err(failure(
+                Category::BadInput,
+                "codeword subsets are not supported for product codebooks",
+            ));
+        }
+        load_codeword_list(path, k)?;
+    }
+    // the bins of the added vocabularies follow those of the main one
+    let mut n_bins = k;
+    for path in &args.add_vocabulary {
+        if product {
+            return Err(failure(
+                Category::BadInput,
+                "product codebooks cannot be combined with other vocabularies",
+            ));
+        }
+        let file = File::open(path, "r")?;
+        let name = default_codebook_name(path)?;
+        if file.group(&name).is_ok() {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "{} has a product codebook, which cannot be combined with other vocabularies",
+                    path.display()
+                ),
+            ));
+        }
+        let (k_added, d_added) = codebook_shape(&file, &name)?;
+        if d_added != d {
+            return Err(failure(
+                Category::DimensionMismatch,
+                format!(
+                    "{} has codewords of {} dimensions, but {} were expected",
+                    path.display(),
+                    d_added,
+                    d
+                ),
+            ));
+        }
+        check_vocabulary_metric(path, &name, args.metric)?;
+        n_bins += k_added;
+    }
+    if let Some(ref path) = args.exclude_codewords {
+        load_codeword_list(path, n_bins)?;
+    }
+    let features_name = match args.features_dataset_name {
+        Some(ref name) => name.clone(),
+        None => vocabulary_features_name(&args.vocabulary, &vocab_name)?,
+    };
+    let feature_cols = match args.feature_cols {
+        Some(range) => Some(range),
+        None => vocabulary_feature_cols(&args.vocabulary, &vocab_name)?,
+    };
+    let dims = load_dims(&args.vocabulary)?;
+    let mean = load_mean(&args.vocabulary)?;
+
+    for path in &args.features {
+        let file = File::open(path, "r")?;
+        let mut features = FeatureSet::open(&file, &features_name, args.features_transpose)?;
+        if let Some(range) = feature_cols {
+            features.select_cols(range)?;
+        }
+        if let Some(ref dims) = dims {
+            features.select_dims(dims.clone())?;
+        }
+        check_feature_dim(&features, d, path, !args.features_transpose)?;
+        if let Some(ref mean) = mean {
+            features.center(mean.clone())?;
+        }
+        println!(
+            "{}: {} features of {} dimensions, compatible with {} codewords ({})",
+            path.display(),
+            features.rows(),
+            d,
+            n_bins,
+            args.metric.as_str()
+        );
+    }
+    Ok(())
+}
+
+/// Quantize features over the vocabulary of the arguments, whose flat
+/// codebook is `trained`, if already in memory.
+fn generate_descriptors(mut args: QuantizeArgs, trained: Option<Array2<f32>>) -> DynResult<()> {
+    retry::set_retries(args.io_retries);
+    status::set_to_stderr(args.progress_to_stderr);
+    if args.dim_check_only {
+        return check_dims_only(&args);
+    }
+    let cache_dir = args.cache_dir.as_ref().map(PathBuf::as_path);
+    args.features = args
+        .features
+        .iter()
+        .map(|path| remote::fetch(path, cache_dir))
+        .collect::<DynResult<_>>()?;
+    if args.list_datasets {
+        list_datasets(&args.vocabulary)?;
+        for path in &args.features {
+            list_datasets(path)?;
+        }
+        return Ok(());
+    }
+    args.metric.check_supported()?;
+    if args.features.is_empty() && !args.features_stdin {
+        return Err(failure(
+            Category::BadInput,
+            "no features file given (use --features-stdin to read from standard input)",
+        ));
+    }
+
+    let progress = ProgressBar::new_spinner();
+
+    progress.set_message("Reading data ...");
+    let vocab_name = match args.vocab_name {
+        Some(ref name) => name.clone(),
+        None => default_codebook_name(&args.vocabulary)?,
+    };
+    check_vocabulary_metric(&args.vocabulary, &vocab_name, args.metric)?;
+    let vocab_file = File::open(&args.vocabulary, "r")?;
+    let mut codebook_offsets = None;
+    let product_codebooks = match trained {
+        Some(_) => None,
+        None => load_product_codebooks(&vocab_file, &vocab_name)?,
+    };
+    let (codebook, quantizer) = match product_codebooks {
+        Some(codebooks) => {
+            if args.fisher || args.residual_mag {
+                return Err(failure(
+                    Category::BadInput,
+                    "product codebooks only support histograms of codeword counts",
+                ));
+            }
+            if args.codeword_subset.is_some() {
+                return Err(failure(
+                    Category::BadInput,
+                    "codeword subsets are not supported for product codebooks",
+                ));
+            }
+            if !args.add_vocabulary.is_empty() {
+                return Err(failure(
+                    Category::BadInput,
+                    "product codebooks cannot be combined with other vocabularies",
+                ));
+            }
+            let assigner = Assigner::product(&codebooks)?
+                .stable(args.stable_assign)
+                .collect_stats(args.codeword_stats.is_some());
+            (stack_codebooks(&codebooks), Quantizer::new(assigner))
+        }
+        None => {
+            let codebook = match trained {
+                Some(codebook) => codebook,
+                None => load_codebook(&args.vocabulary, &vocab_name)?,
+            };
+            check_codebook_shape(codebook.dim(), args.max_dim);
+            let mut builder = Quantizer::builder(codebook.view())
+                .stable(args.stable_assign)
+                .collect_stats(args.codeword_stats.is_some());
+            if let Some(ref path) = args.codeword_subset {
+                let listed = load_codeword_list(path, codebook.rows())?;
+                let subset: Vec<usize> = (0..codebook.rows()).filter(|&i| listed[i]).collect();
+                status!(
+                    "Assigning to {} of {} codewords",
+                    subset.len(),
+                    codebook.rows()
+                );
+                builder = builder.subset(subset);
+            }
+            if args.add_vocabulary.is_empty() {
+                (codebook, builder.build()?)
+            } else {
+                let codebooks = load_added_codebooks(&args, codebook)?;
+                let views: Vec<_> = codebooks.iter().map(|c| c.view()).collect();
+                let assigner = Assigner::multi(&views)?
+                    .stable(args.stable_assign)
+                    .collect_stats(args.codeword_stats.is_some());
+                let mut offsets = vec![0_u64];
+                for codebook in &codebooks {
+                    offsets.push(offsets[offsets.len() - 1] + codebook.rows() as u64);
+                }
+                status!(
+                    "Concatenating the histograms of {} codebooks, {} codewords in total",
+                    codebooks.len(),
+                    offsets[codebooks.len()]
+                );
+                codebook_offsets = Some(offsets);
+                (stack_codebooks(&codebooks), Quantizer::new(assigner))
+            }
+        }
+    };
+    if args.validate_codebook {
+        validate_codebook(codebook.view())?;
+    }
+    let gmm = if args.fisher {
+        Some(load_gmm(&args.vocabulary, &vocab_name)?)
+    } else {
+        None
+    };
+    let idf = match args.idf {
+        Some(ref path) => {
+            let idf = load_idf(path)?;
+            if idf.len() != quantizer.n_bins() {
+                return Err(failure(
+                    Category::DimensionMismatch,
+                    format!(
+                        "IDF vector has {} elements, but the codebook has {} codewords",
+                        idf.len(),
+                        quantizer.n_bins()
+                    ),
+                ));
+            }
+            Some(idf)
+        }
+        None => None,
+    };
+    let features_name = match args.features_dataset_name {
+        Some(ref name) => name.clone(),
+        None => vocabulary_features_name(&args.vocabulary, &vocab_name)?,
+    };
+    let feature_cols = match args.feature_cols {
+        Some(range) => Some(range),
+        None => vocabulary_feature_cols(&args.vocabulary, &vocab_name)?,
+    };
+    // the hash recorded in the file is only that of its default codebook
+    let vocab_hash = if args.vocab_name.is_some() || codebook_offsets.is_some() {
+        codebook_hash(codebook.view())
+    } else {
+        vocabulary_hash(&vocab_file, codebook.view())?
+    };
+    let quantizer = match args.exclude_codewords {
+        Some(ref path) => {
+            let excluded = load_codeword_list(path, quantizer.n_bins())?;
+            quantizer.excluding(excluded)?
+        }
+        None => quantizer,
+    };
+    drop(progress);
+
+    let mut ctx = QuantizeContext {
+        codebook,
+        quantizer,
+        gmm,
+        idf,
+        mean: load_mean(&args.vocabulary)?,
+        dims: load_dims(&args.vocabulary)?,
+        features_name,
+        feature_cols,
+        vocab_hash,
+        codebook_offsets,
+        item_renames: match args.rename_items {
+            Some(ref path) => Some(load_item_renames(path)?),
+            None => None,
+        },
+    };
+
+    if args.features_stdin {
+        quantize_stdin(&args, &mut ctx)?;
+    } else {
+        let outputs = output_paths(&args.features, &args.out)?;
+        for (features_path, out_path) in args.features.iter().zip(&outputs) {
+            quantize_file(&args, &mut ctx, features_path, out_path)?;
+            output::commit()?;
+        }
+    }
+    if let Some(ref path) = args.codeword_stats {
+        let (assigner, _) = ctx.quantizer.split();
+        let stats = assigner
+            .stats()
+            .expect("codeword statistics should be collected");
+        write_codeword_stats(path, stats)?;
+    }
+    Ok(())
+}
+
+/// Save the number of features assigned to each codeword and their mean
+/// distance to it, as the `counts` and `mean_dist` data sets of a new file.
+fn write_codeword_stats(path: &Path, stats: &CodewordStats) -> DynResult<()> {
+    let file = open_output(path, false)?;
+    let k = stats.counts.len();
+    file.new_dataset::<u64>()
+        .no_chunk()
+        .create("counts", (k,))?
+        .write_raw(&stats.counts)?;
+    file.new_dataset::<f32>()
+        .no_chunk()
+        .create("mean_dist", (k,))?
+        .write_raw(&stats.mean_distances())?;
+    println!("Codeword statistics saved: {}", path.display());
+    Ok(())
+}
+
+/// Load the codebooks of the vocabularies added to the main `codebook`,
+/// returning all of them in order. The added vocabularies should have flat
+/// codebooks trained on the same features, with the same preprocessing.
+fn load_added_codebooks(args: &QuantizeArgs, codebook: Array2<f32>) -> DynResult<Vec<Array2<f32>>> {
+    let mean = load_mean(&args.vocabulary)?;
+    let dims = load_dims(&args.vocabulary)?;
+    let mut codebooks = vec![codebook];
+    for path in &args.add_vocabulary {
+        let name = default_codebook_name(path)?;
+        if load_product_codebooks(&File::open(path, "r")?, &name)?.is_some() {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "{} has a product codebook, which cannot be combined with other vocabularies",
+                    path.display()
+                ),
+            ));
+        }
+        if load_mean(path)? != mean || load_dims(path)? != dims {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "{} was trained on differently centered features or dimensions",
+                    path.display()
+                ),
+            ));
+        }
+        let codebook = load_codebook(path, &name)?;
+        check_codebook_shape(codebook.dim(), args.max_dim);
+        codebooks.push(codebook);
+    }
+    Ok(codebooks)
+}
+
+/// Warn the user if a codebook of `k` codewords of `d` dimensions
+/// looks implausible, before building an index over it.
+fn check_codebook_shape((k, d): (usize, usize), max_dim: usize) {
+    if d > k {
+        eprintln!(
+            "Warning: the codebook has {} codewords of {} dimensions, \
+             it may have been transposed",
+            k, d
+        );
+    }
+    if d > max_dim {
+        eprintln!(
+            "Warning: the codewords have {} dimensions, more than the expected maximum of {} \
+             (see --max-dim)",
+            d, max_dim
+        );
+    }
+}
+
+/// Fail if the codebook has non-finite values or if all of its centroids
+/// are the same, and warn about all-zero and duplicate centroids.
+fn validate_codebook(codebook: ArrayView2<f32>) -> DynResult<()> {
+    if let Some(i) = codebook
+        .outer_iter()
+        .position(|row| row.iter().any(|v| !v.is_finite()))
+    {
+        return Err(failure(
+            Category::BadInput,
+            format!("codeword {} of the codebook has non-finite values", i),
+        ));
+    }
+    let zeros = codebook
+        .outer_iter()
+        .filter(|row| row.iter().all(|&v| v == 0.))
+        .count();
+    if zeros > 0 {
+        eprintln!("Warning: {} codewords of the codebook are all zeros", zeros);
+    }
+    let mut seen = HashSet::with_capacity(codebook.rows());
+    let mut bytes = Vec::with_capacity(codebook.cols() * 4);
+    let distinct = codebook
+        .outer_iter()
+        .filter(|row| seen.insert(row_hash(row.view(), &mut bytes)))
+        .count();
+    if codebook.rows() > 1 && distinct == 1 {
+        return Err(failure(
+            Category::BadInput,
+            "all codewords of the codebook are the same",
+        ));
+    }
+    if distinct < codebook.rows() {
+        eprintln!(
+            "Warning: the codebook has {} duplicate codewords",
+            codebook.rows() - distinct
+        );
+    }
+    Ok(())
+}
+
+/// Load a one-dimensional vector of inverse document frequencies.
+fn load_idf(path: &Path) -> DynResult<Array1<f32>> {
+    let file = File::open(path, "r")?;
+    Ok(file.dataset("data")?.read_1d()?)
+}
+
+/// Load a list of codewords from a text file of one codeword index
+/// per line, returning whether each of the `k` codewords is listed.
+/// Blank lines and anything after a `#` are ignored.
+fn load_codeword_list(path: &Path, k: usize) -> DynResult<Vec<bool>> {
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        failure(
+            Category::Io,
+            format!("could not read {}: {}", path.display(), e),
+        )
+    })?;
+    let mut listed = vec![false; k];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.splitn(2, '#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let codeword: usize = line.parse().map_err(|_| {
+            failure(
+                Category::BadInput,
+                format!(
+                    "{}:{}: invalid codeword index `{}`",
+                    path.display(),
+                    i + 1,
+                    line
+                ),
+            )
+        })?;
+        if codeword >= k {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "{}:{}: codeword {} is out of range, the codebook has {} codewords",
+                    path.display(),
+                    i + 1,
+                    codeword,
+                    k
+                ),
+            ));
+        }
+        listed[codeword] = true;
+    }
+    Ok(listed)
+}
+
+/// Determine the output file of each feature file to quantize.
+///
+/// A single feature file is written to `out` as is.
+/// Otherwise, each output is placed next to `out`,
+/// named after the stem of its features file.
+fn output_paths(features: &[PathBuf], out: &Path) -> DynResult<Vec<PathBuf>> {
+    if features.len() == 1 {
+        return Ok(vec![out.to_path_buf()]);
+    }
+    let out_name = out
+        .file_name()
+        .ok_or_else(|| {
+            failure(
+                Category::BadInput,
+                format!("invalid output file {}", out.display()),
+            )
+        })?
+        .to_string_lossy();
+    let mut outputs: Vec<PathBuf> = Vec::with_capacity(features.len());
+    for path in features {
+        let stem = path
+            .file_stem()
+            .ok_or_else(|| {
+                failure(
+                    Category::BadInput,
+                    format!("invalid features file {}", path.display()),
+                )
+            })?
+            .to_string_lossy();
+        let out_path = out.with_file_name(format!("{}_{}", stem, out_name));
+        if outputs.contains(&out_path) {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "more than one features file would be saved to {}",
+                    out_path.display()
+                ),
+            ));
+        }
+        outputs.push(out_path);
+    }
+    Ok(outputs)
+}
+
+/// Quantize the features in one file into bags, saving them to `out_path`.
+fn quantize_file(
+    args: &QuantizeArgs,
+    ctx: &mut QuantizeContext,
+    features_path: &Path,
+    out_path: &Path,
+) -> DynResult<()> {
+    let file = File::open(features_path, "r")?;
+    let mut features = FeatureSet::open(&file, &ctx.features_name, args.features_transpose)?;
+    if let Some(range) = ctx.feature_cols {
+        features.select_cols(range)?;
+    }
+    if let Some(max_features) = args.max_features {
+        if max_features < features.rows() {
+            warn_incomplete(max_features, features.rows());
+            features.truncate(max_features);
+        }
+    }
+    if let Some(ref dims) = ctx.dims {
+        features.select_dims(dims.clone())?;
+    }
+    check_feature_dim(
+        &features,
+        ctx.quantizer.dim(),
+        features_path,
+        !args.features_transpose,
+    )?;
+    if let Some(ref mean) = ctx.mean {
+        features.center(mean.clone())?;
+    }
+    if args.mmap && !features.map_to_memory()? {
+        eprintln!(
+            "Warning: features in {} cannot be mapped to memory, reading them instead",
+            features_path.display()
+        );
+    }
+    features.prefetch(args.prefetch);
+    let batch_size = match args.limit_memory {
+        Some(budget) => memory_batch_size(budget, features.cols(), features.batches_held()),
+        None => BATCH_SIZE,
+    };
+    let batch_size = if args.read_chunk_aligned {
+        chunk_aligned_batch_size(features.primary(), features.row_axis(), batch_size)
+    } else {
+        batch_size
+    };
+    if args.limit_memory.is_some() {
+        status!("Reading {} features at a time", batch_size);
+    }
+
+    let item_offsets = match args.item_offsets {
+        Some(ref name) => Some(read_item_offsets(
+            &file.dataset(name)?,
+            features.total_rows(),
+        )?),
+        None => None,
+    };
+    let mut item_names = if args.single_item {
+        None
+    } else {
+        let names = read_item_names(&file, args)?;
+        match ctx.item_renames {
+            Some(ref renames) => Some(rename_items(names, renames)?),
+            None => Some(names),
+        }
+    };
+    let (id_slice_dset, n_items) = match (&item_names, &item_offsets) {
+        (None, _) => (None, 1),
+        (Some(names), Some(offsets)) => {
+            if names.len() != offsets.len() {
+                return Err(failure(
+                    Category::BadInput,
+                    format!(
+                        "there are offsets for {} items, but {} item names",
+                        offsets.len(),
+                        names.len()
+                    ),
+                ));
+            }
+            (None, offsets.len())
+        }
+        (Some(names), None) if args.item_id_rle => (None, names.len()),
+        (Some(names), None) => (Some(file.dataset(&args.item_id)?), names.len()),
+    };
+    let n_items = match (&id_slice_dset, &mut item_names) {
+        (Some(dset), Some(names)) => {
+            reconcile_item_names(dset, features.rows(), names, args.trust_item_id)?
+        }
+        _ => n_items,
+    };
+    let item_runs = if args.item_id_rle {
+        Some(read_item_runs(
+            &file.dataset(&args.item_id)?,
+            features.total_rows(),
+            n_items,
+        )?)
+    } else {
+        None
+    };
+
+    let items = match (&item_offsets, &item_runs, &id_slice_dset) {
+        (Some(offsets), _, _) => Items::Offsets(offsets),
+        (None, Some(runs), _) => Items::Runs(runs),
+        (None, None, Some(dset)) if args.assert_monotonic_item_id => Items::SortedIds(dset),
+        (None, None, Some(dset)) => Items::Ids(dset),
+        (None, None, None) => Items::Single,
+    };
+
+    let valid_mask_dset = match args.valid_mask {
+        Some(ref name) => {
+            let dset = file.dataset(name)?;
+            if dset.ndim() != 1 || dset.shape()[0] < features.rows() {
+                return Err(failure(
+                    Category::BadInput,
+                    format!(
+                        "valid mask `{}` has shape {:?}, but there are {} features",
+                        name,
+                        dset.shape(),
+                        features.rows()
+                    ),
+                ));
+            }
+            Some(dset)
+        }
+        None => None,
+    };
+    let weights_dset = match args.feature_weights {
+        Some(ref name) => {
+            let dset = file.dataset(name)?;
+            if dset.ndim() != 1 || dset.shape()[0] < features.rows() {
+                return Err(failure(
+                    Category::BadInput,
+                    format!(
+                        "feature weights `{}` have shape {:?}, but there are {} features",
+                        name,
+                        dset.shape(),
+                        features.rows()
+                    ),
+                ));
+            }
+            Some(dset)
+        }
+        None => None,
+    };
+
+    let width = match ctx.gmm {
+        Some(ref gmm) => 2 * gmm.k() * gmm.d(),
+        None => ctx.quantizer.n_bins(),
+    };
+    check_bags_size(n_items, width, args)?;
+
+    let item_progress = args.progress_items && id_slice_dset.is_some();
+    let progress = ProgressBar::new(if item_progress {
+        n_items as u64
+    } else {
+        features.rows() as u64
+    });
+    progress.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:50} {pos:>7}/{len:7} {msg}"),
+    );
+    // items are counted one by one, features batch by batch
+    throttle_progress(
+        &progress,
+        args.progress_every,
+        if item_progress { 1 } else { batch_size },
+    );
+    let items_done = Cell::new(0_u64);
+    let tick_fn = |n: u32, ids: Option<&[u32]>| {
+        if !item_progress {
+            progress.inc(u64::from(n));
+            return;
+        }
+        // the items before the last ID of the batch are complete,
+        // including those without features
+        if let Some(&last) = ids.and_then(|ids| ids.last()) {
+            if u64::from(last) > items_done.get() {
+                items_done.set(u64::from(last));
+                progress.set_position(u64::from(last));
+            }
+        }
+    };
+
+    interrupt::set_deferred(true);
+    if args.two_pass_idf {
+        progress.set_length(2 * features.rows() as u64);
+        progress.set_message("Building bags ...");
+        let (out, bows_dset) =
+            create_descriptors(out_path, (n_items, width), "item-major", ctx, args)?;
+        let (assigner, excluded) = ctx.quantizer.split();
+        let idf = construct_two_pass_tfidf(
+            &features,
+            id_slice_dset
+                .as_ref()
+                .expect("item IDs should be available with multiple items"),
+            valid_mask_dset.as_ref(),
+            n_items,
+            assigner,
+            excluded,
+            args.normalize,
+            batch_size,
+            &bows_dset,
+            &tick_fn,
+        )?;
+        interrupt::set_deferred(false);
+        drop(progress);
+        if args.feature_count_hist {
+            print_feature_count_hist(&count_item_features(items, n_items, features.rows())?);
+        }
+
+        let progress = ProgressBar::new_spinner();
+        progress.set_message("Saving to file ...");
+        remove_existing(&out, "idf")?;
+        out.new_dataset::<f32>()
+            .no_chunk()
+            .create("idf", (idf.len(),))?
+            .write(idf.view())?;
+        return finish_bags(
+            args,
+            item_names,
+            out,
+            n_items,
+            features_path,
+            out_path,
+            progress,
+        );
+    }
+    let mut labels = match args.write_labels {
+        Some(ref name) => Some(LabelsWriter::create(
+            open_output(out_path, args.force)?,
+            name,
+            features.rows(),
+            ctx.quantizer.bins_per_feature(),
+        )?),
+        None => None,
+    };
+    let bows = if let Some(ref gmm) = ctx.gmm {
+        progress.set_message("Building Fisher vectors ...");
+        Descriptors::Real(construct_fisher_vectors(
+            &features,
+            id_slice_dset.as_ref(),
+            n_items,
+            gmm,
+            batch_size,
+            tick_fn,
+        )?)
+    } else if args.residual_mag {
+        progress.set_message("Building residual magnitudes ...");
+        Descriptors::Real(construct_residual_magnitudes(
+            &features,
+            items,
+            valid_mask_dset.as_ref(),
+            n_items,
+            ctx.quantizer.split().0,
+            ctx.codebook.view(),
+            batch_size,
+            tick_fn,
+        )?)
+    } else if weights_dset.is_some()
+        || args.float_counts
+        || ctx.idf.is_some()
+        || ctx.quantizer.weighted()
+    {
+        progress.set_message("Building bags ...");
+        Descriptors::Real(construct_bows(
+            &features,
+            items,
+            valid_mask_dset.as_ref(),
+            weights_dset.as_ref(),
+            n_items,
+            &mut ctx.quantizer,
+            labels.as_mut(),
+            batch_size,
+            tick_fn,
+        )?)
+    } else {
+        progress.set_message("Building bags ...");
+        Descriptors::Counts(construct_bows(
+            &features,
+            items,
+            valid_mask_dset.as_ref(),
+            None,
+            n_items,
+            &mut ctx.quantizer,
+            labels.as_mut(),
+            batch_size,
+            tick_fn,
+        )?)
+    };
+    if item_progress && !interrupt::interrupted() {
+        progress.set_position(n_items as u64);
+    }
+    interrupt::set_deferred(false);
+    drop(progress);
+    if args.feature_count_hist {
+        print_feature_count_hist(&count_item_features(items, n_items, features.rows())?);
+    }
+
+    let raw = if args.keep_raw {
+        Some(bows.clone())
+    } else {
+        None
+    };
+    let bows = weigh_descriptors(bows, ctx.idf.as_ref(), args.normalize);
+
+    // save them
+    let progress = ProgressBar::new_spinner();
+    progress.set_message("Saving to file ...");
+
+    let n_items = bows.dim().0;
+    let out = save_descriptors(out_path, bows, ctx, args)?;
+    if let Some(raw) = raw {
+        save_raw_counts(&out, raw, args.transpose)?;
+    }
+    // the labels were written as they were assigned
+    drop(labels);
+    finish_bags(
+        args,
+        item_names,
+        out,
+        n_items,
+        features_path,
+        out_path,
+        progress,
+    )
+}
+
+/// Complete an output file of bags with the item data sets and the manifest.
+fn finish_bags(
+    args: &QuantizeArgs,
+    item_names: Option<Vec<VarLenUnicode>>,
+    out: File,
+    n_items: usize,
+    features_path: &Path,
+    out_path: &Path,
+    progress: ProgressBar,
+) -> DynResult<()> {
+    write_manifest(
+        &out,
+        &[
+            ("features_file_hash", features_path),
+            ("vocabulary_file_hash", args.vocabulary.as_path()),
+        ],
+        !args.no_manifest_hash,
+    )?;
+
+    if let Some(item_names) = item_names {
+        // write sequential range to `id_slice`
+        remove_existing(&out, &args.item_id)?;
+        if !args.no_item_id_out {
+            let id_slice_dset_out = out
+                .new_dataset::<u32>()
+                .no_chunk()
+                .create(&args.item_id, (n_items,))?;
+            id_slice_dset_out.write_raw(&(0..n_items).collect::<Vec<_>>())?;
+        }
+
+        // replicate `id_item` to the output file
+        let item_name = args.item_name.as_ref().map_or(ITEM_NAME, String::as_str);
+        remove_existing(&out, item_name)?;
+        let id_item_dset_out = out
+            .new_dataset::<VarLenUnicode>()
+            .no_chunk()
+            .create(item_name, (item_names.len(),))?;
+        id_item_dset_out.write_raw(&item_names)?;
+    }
+
+    if interrupt::interrupted() {
+        write_attr(&out, "partial", &true)?;
+        return Err(format!("interrupted, partial bags saved to {}", out_path.display()).into());
+    }
+    progress.finish_and_clear();
+    println!("Bags saved: {}", out_path.display());
+    Ok(())
+}
+
+/// Quantize the features of a single item streamed through standard input
+/// into one bag, saving it to the output file.
+fn quantize_stdin(args: &QuantizeArgs, ctx: &mut QuantizeContext) -> DynResult<()> {
+    let stdin = std::io::stdin();
+    let mut features = FeatureStream::new(stdin.lock())?;
+    let cols = match ctx.feature_cols {
+        Some(range) if range.end > features.cols() => {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "column range {} is out of bounds for features of {} dimensions",
+                    range,
+                    features.cols()
+                ),
+            ));
+        }
+        Some(range) => range.end - range.start,
+        None => features.cols(),
+    };
+    let cols = match ctx.dims {
+        Some(ref dims) => {
+            if let Some(&dim) = dims.iter().find(|&&dim| dim >= cols) {
+                return Err(failure(
+                    Category::BadInput,
+                    format!(
+                        "dimension {} is out of bounds for features of {} dimensions",
+                        dim, cols
+                    ),
+                ));
+            }
+            dims.len()
+        }
+        None => cols,
+    };
+    if cols != ctx.codebook.cols() {
+        return Err(failure(
+            Category::DimensionMismatch,
+            format!(
+                "streamed features have {} dimensions, but the codebook has {}",
+                cols,
+                ctx.codebook.cols()
+            ),
+        ));
+    }
+
+    let progress = ProgressBar::new_spinner();
+    progress.set_message("Building bags from standard input ...");
+
+    let quantizer = &mut ctx.quantizer;
+    let mut counts = Array1::<u32>::zeros(quantizer.n_bins());
+    let mut remaining = args.max_features.unwrap_or(std::usize::MAX);
+    interrupt::set_deferred(true);
+    while remaining > 0 && !interrupt::interrupted() {
+        let batch = match features.next_batch(usize::min(BATCH_SIZE, remaining))? {
+            Some(batch) => batch,
+            None => break,
+        };
+        remaining -= batch.rows();
+        let batch = match ctx.feature_cols {
+            Some(range) => batch.slice(s![.., range.start..range.end]).to_owned(),
+            None => batch,
+        };
+        let batch = match ctx.dims {
+            Some(ref dims) => batch.select(Axis(1), dims),
+            None => batch,
+        };
+        let batch = match ctx.mean {
+            Some(ref mean) => batch - mean,
+            None => batch,
+        };
+        quantizer.add_histogram(batch.view(), counts.view_mut())?;
+        progress.tick();
+    }
+    interrupt::set_deferred(false);
+    // the input may also have ended right at the limit
+    if remaining == 0 && features.has_more()? {
+        eprintln!(
+            "Warning: stopped after {} features, the bag is incomplete",
+            args.max_features.unwrap_or_default()
+        );
+    }
+    let (total, skipped) = quantizer.assignments();
+    warn_unassigned(skipped, total);
+    drop(progress);
+
+    let counts = counts.insert_axis(Axis(0));
+    let bows = if args.float_counts || ctx.idf.is_some() {
+        Descriptors::Real(counts.mapv(|c| c as f32))
+    } else {
+        Descriptors::Counts(counts)
+    };
+    let raw = if args.keep_raw {
+        Some(bows.clone())
+    } else {
+        None
+    };
+    let bows = weigh_descriptors(bows, ctx.idf.as_ref(), args.normalize);
+
+    let progress = ProgressBar::new_spinner();
+    progress.set_message("Saving to file ...");
+    let out = save_descriptors(&args.out, bows, ctx, args)?;
+    if let Some(raw) = raw {
+        save_raw_counts(&out, raw, args.transpose)?;
+    }
+    write_manifest(
+        &out,
+        &[("vocabulary_file_hash", args.vocabulary.as_path())],
+        !args.no_manifest_hash,
+    )?;
+    if interrupt::interrupted() {
+        write_attr(&out, "partial", &true)?;
+        return Err(format!("interrupted, partial bag saved to {}", args.out.display()).into());
+    }
+    progress.finish_and_clear();
+    println!("Bags saved: {}", args.out.display());
+    Ok(())
+}
+
+/// Record the provenance of an output file in its attributes:
+/// the content hash of each of the given input files (unless `hash` is
+/// false, as they are read in whole), the command line and the time of the
+/// run (in seconds since the Unix epoch).
+fn write_manifest(out: &File, inputs: &[(&str, &Path)], hash: bool) -> DynResult<()> {
+    if hash {
+        for (name, path) in inputs {
+            write_str_attr(out, name, &file_hash(path)?)?;
+        }
+    }
+    let command_line: Vec<String> = std::env::args().collect();
+    write_str_attr(out, "command_line", &command_line.join(" "))?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    write_attr(out, "timestamp", &timestamp)?;
+    Ok(())
+}
+
+/// Compute the content hash of a whole file.
+fn file_hash(path: &Path) -> DynResult<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Check that the bags of `n_items x width` components fit in memory,
+/// unless they are written as they are built with `--two-pass-idf`.
+fn check_bags_size(n_items: usize, width: usize, args: &QuantizeArgs) -> DynResult<()> {
+    if args.two_pass_idf {
+        return Ok(());
+    }
+    check_dense_size(n_items, width, args.max_dense_bytes)
+}
+
+/// Check that a dense matrix of `n_items x width` 4-byte descriptors
+/// fits in `max_bytes`, or in the memory currently available to the system.
+fn check_dense_size(n_items: usize, width: usize, max_bytes: Option<u64>) -> DynResult<()> {
+    let bytes = n_items as u64 * width as u64 * 4;
+    let (limit, what) = match max_bytes {
+        Some(limit) => (limit, "the limit of"),
+        None => match available_memory() {
+            Some(limit) => (limit, "the available"),
+            None => return Ok(()),
+        },
+    };
+    if bytes > limit {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "bags of {} items x {} components would take {} bytes, more than {} {} bytes \
+             (see --max-dense-bytes)",
+                n_items, width, bytes, what, limit
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Retrieve the memory available to the system in bytes,
+/// from `/proc/meminfo` where there is one.
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Apply TF-IDF weighting to real-valued descriptors, if requested.
+fn weigh_descriptors(
+    bows: Descriptors,
+    idf: Option<&Array1<f32>>,
+    normalize: Normalization,
+) -> Descriptors {
+    let bows = match (bows, idf) {
+        (Descriptors::Real(mut bows), Some(idf)) => {
+            apply_tfidf(bows.view_mut(), idf.view());
+            Descriptors::Real(bows)
+        }
+        (bows, _) => bows,
+    };
+    if normalize == Normalization::None {
+        return bows;
+    }
+    let mut bows = bows.into_real();
+    normalize_rows(bows.view_mut(), normalize);
+    Descriptors::Real(bows)
+}
+
+/// Scale each row to unit norm, in place. Rows of zeros are left as is.
+fn normalize_rows(mut bows: ArrayViewMut2<f32>, normalize: Normalization) {
+    for mut row in bows.outer_iter_mut() {
+        let norm = match normalize {
+            Normalization::None => return,
+            Normalization::L1 => row.iter().map(|v| v.abs()).sum::<f32>(),
+            Normalization::L2 => row.iter().map(|v| v * v).sum::<f32>().sqrt(),
+        };
+        if norm > 0. {
+            row.mapv_inplace(|v| v / norm);
+        }
+    }
+}
+
+/// Print the mean, median and maximum number of components of each bag
+/// which are not zero, and the fraction of all components which are not.
+fn print_nonzero_report(bows: &Descriptors) {
+    let (n_items, k) = bows.dim();
+    let mut nonzeros = bows.nonzeros_per_row();
+    if nonzeros.is_empty() {
+        return;
+    }
+    nonzeros.sort_unstable();
+    let total: usize = nonzeros.iter().sum();
+    println!(
+        "Nonzero components per item ({} items, {} components): mean {:.2}, median {}, max {}",
+        n_items,
+        k,
+        total as f64 / n_items as f64,
+        nonzeros[n_items / 2],
+        nonzeros[n_items - 1]
+    );
+    println!(
+        "Density: {:.4}% of the components are not zero",
+        if k == 0 {
+            0.
+        } else {
+            total as f64 / (n_items * k) as f64 * 100.
+        }
+    );
+}
+
+/// Write the bags as they were before weighting and normalization
+/// to the `counts` data set, in the same orientation as `data`.
+fn save_raw_counts(out: &File, raw: Descriptors, transpose: bool) -> DynResult<()> {
+    let raw = if transpose { raw.transposed() } else { raw };
+    remove_existing(out, "counts")?;
+    let dset = match raw {
+        Descriptors::Counts(_) => out
+            .new_dataset::<u32>()
+            .no_chunk()
+            .create("counts", raw.dim())?,
+        Descriptors::Real(_) => out
+            .new_dataset::<f32>()
+            .no_chunk()
+            .create("counts", raw.dim())?,
+    };
+    raw.write_to(&dset)?;
+    Ok(())
+}
+
+/// Create the output file and write the descriptors to its `data` data set,
+/// along with the hash of the vocabulary that produced them
+/// and the settings with which they were built.
+fn save_descriptors(
+    out_path: &Path,
+    bows: Descriptors,
+    ctx: &QuantizeContext,
+    args: &QuantizeArgs,
+) -> DynResult<File> {
+    if args.report_nonzero_per_item {
+        print_nonzero_report(&bows);
+    }
+    let sparse = match args.sparse_threshold {
+        Some(threshold) => {
+            let density = bows.density();
+            status!(
+                "{:.2}% of the bags' components are not zero",
+                density * 100.
+            );
+            density < threshold
+        }
+        None => false,
+    };
+    let (bows, orientation) = if args.transpose {
+        (bows.transposed(), "codeword-major")
+    } else {
+        (bows, "item-major")
+    };
+    if sparse {
+        let out = open_descriptors_output(out_path, ctx, args)?;
+        let group = out.create_group("data")?;
+        write_descriptor_attrs(&out, &group, "sparse", orientation, ctx, args)?;
+        bows.write_sparse_to(&group)?;
+        return Ok(out);
+    }
+    if args.format == BagsFormat::Coo {
+        let out = open_descriptors_output(out_path, ctx, args)?;
+        let group = out.create_group("data")?;
+        write_descriptor_attrs(&out, &group, "coo", orientation, ctx, args)?;
+        bows.write_coo_to(&group)?;
+        return Ok(out);
+    }
+    let (out, bows_dset) = create_descriptors(out_path, bows.dim(), orientation, ctx, args)?;
+    bows.write_to(&bows_dset)?;
+    Ok(out)
+}
+
+/// Create the output file and its `data` data set of descriptors
+/// of the given shape, to be written in the given orientation,
+/// recording the settings with which they are built.
+fn create_descriptors(
+    out_path: &Path,
+    dim: (usize, usize),
+    orientation: &str,
+    ctx: &QuantizeContext,
+    args: &QuantizeArgs,
+) -> DynResult<(File, Dataset)> {
+    let out = open_descriptors_output(out_path, ctx, args)?;
+    let bows_dset = out.new_dataset::<f32>().no_chunk().create("data", dim)?;
+    write_descriptor_attrs(&out, &bows_dset, "dense", orientation, ctx, args)?;
+    Ok((out, bows_dset))
+}
+
+/// Create the output file of descriptors, without its `data`,
+/// along with the hash and (if requested) a copy of the vocabulary.
+fn open_descriptors_output(
+    out_path: &Path,
+    ctx: &QuantizeContext,
+    args: &QuantizeArgs,
+) -> DynResult<File> {
+    let out = open_output(out_path, args.force)?;
+    remove_existing(&out, "data")?;
+    write_str_attr(&out, "vocab_hash", &ctx.vocab_hash)?;
+    if args.embed_vocab {
+        embed_vocabulary(&out, ctx)?;
+    }
+    Ok(out)
+}
+
+/// Record the layout and orientation of the descriptors in `data`,
+/// and the settings with which they are built, along with the format
+/// version and the encoding of the descriptors in the output file.
+fn write_descriptor_attrs(
+    out: &File,
+    data: &Location,
+    layout: &str,
+    orientation: &str,
+    ctx: &QuantizeContext,
+    args: &QuantizeArgs,
+) -> DynResult<()> {
+    write_attr(out, "format_version", &FORMAT_VERSION)?;
+    write_str_attr(out, "encoding", &descriptor_encoding(layout, ctx, args))?;
+    write_str_attr(data, "layout", layout)?;
+    write_str_attr(data, "orientation", orientation)?;
+    // features are hard assigned to their nearest codeword,
+    // and histograms are not binarized
+    write_attr(data, "knn", &1_u32)?;
+    write_attr(data, "soft_sigma", &0_f32)?;
+    write_str_attr(data, "normalize", args.normalize.as_str())?;
+    write_attr(data, "tfidf", &(ctx.idf.is_some() || args.two_pass_idf))?;
+    write_attr(data, "binary", &false)?;
+    if let Some(ref name) = args.feature_weights {
+        write_str_attr(data, "feature_weights", name)?;
+    }
+    if let Some(ref offsets) = ctx.codebook_offsets {
+        data.new_attribute::<u64>()
+            .create("codebook_offsets", offsets.len())?
+            .write_raw(offsets)?;
+    }
+    Ok(())
+}
+
+/// The encoding of the descriptors, as `<kind>-<layout>-<type>`: the kind
+/// of descriptors (`bow`, `weighted-bow`, `tfidf`, `fisher` or `residual`),
+/// their layout (`dense`, `csr` or `coo`) and the type of their values.
+fn descriptor_encoding(layout: &str, ctx: &QuantizeContext, args: &QuantizeArgs) -> String {
+    let kind = if ctx.gmm.is_some() {
+        "fisher"
+    } else if args.residual_mag {
+        "residual"
+    } else if ctx.idf.is_some() || args.two_pass_idf {
+        "tfidf"
+    } else if args.feature_weights.is_some() {
+        "weighted-bow"
+    } else {
+        "bow"
+    };
+    let layout = if layout == "sparse" { "csr" } else { layout };
+    // counts are also saved as floats
+    format!("{}-{}-f32", kind, layout)
+}
+
+/// The same encoding as `encoding`, with another layout.
+fn encoding_with_layout(encoding: &str, layout: &str) -> String {
+    let layout = if layout == "sparse" { "csr" } else { layout };
+    let mut parts = encoding.rsplitn(3, '-');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(ty), Some(_), Some(kind)) => format!("{}-{}-{}", kind, layout, ty),
+        _ => encoding.to_string(),
+    }
+}
+
+/// Fail if the bags file is of a newer format version than supported.
+fn check_format_version(file: &File) -> DynResult<()> {
+    match read_attr::<u32>(file, "format_version")? {
+        Some(version) if version > FORMAT_VERSION => Err(failure(
+            Category::BadInput,
+            format!(
+                "{} has bags of format version {}, but only up to {} is supported",
+                file.filename(),
+                version,
+                FORMAT_VERSION
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Copy the codebook in use to the `vocabulary` group of the output, along
+/// with its mixture model, mean and subset of dimensions, if any.
+/// Product codebooks are copied as their stacked sub-codebooks.
+fn embed_vocabulary(out: &File, ctx: &QuantizeContext) -> DynResult<()> {
+    remove_existing(out, "vocabulary")?;
+    let group = out.create_group("vocabulary")?;
+    group
+        .new_dataset::<f32>()
+        .no_chunk()
+        .create("data", ctx.codebook.dim())?
+        .write(ctx.codebook.view())?;
+    if let Some(ref gmm) = ctx.gmm {
+        group
+            .new_dataset::<f32>()
+            .no_chunk()
+            .create("variances", gmm.variances.dim())?
+            .write(gmm.variances.view())?;
+        group
+            .new_dataset::<f32>()
+            .no_chunk()
+            .create("weights", (gmm.k(),))?
+            .write(gmm.weights.view())?;
+    }
+    write_mean(&group, ctx.mean.as_ref())?;
+    write_dims(&group, ctx.dims.as_ref().map(Vec::as_slice))?;
+    write_str_attr(&group, "vocab_hash", &ctx.vocab_hash)?;
+    Ok(())
+}
+
+fn generate_labels(args: AssignArgs) -> DynResult<()> {
+    status::set_to_stderr(args.progress_to_stderr);
+    retry::set_retries(args.io_retries);
+    check_results_output(&args.out, args.out_format)?;
+
+    let file = File::open(&args.features, "r")?;
+    let features = open_vocabulary_features(
+        &args.vocabulary,
+        &file,
+        args.features_dataset_name.as_ref().map(String::as_str),
+        args.feature_cols,
+    )?;
+    check_flat_vocabulary_dim(&args.vocabulary, &features, &args.features)?;
+    let mut index = load_codebook_index(&args.vocabulary)?;
+    let max_labels = match args.assign_dtype {
+        LabelType::U16 => u64::from(std::u16::MAX) + 1,
+        LabelType::U32 => u64::from(std::u32::MAX) + 1,
+        LabelType::I64 => std::i64::MAX as u64,
+    };
+    if index.ntotal() > max_labels {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "{} codewords do not fit in labels of type {:?}",
+                index.ntotal(),
+                args.assign_dtype
+            ),
+        ));
+    }
+
+    let batch_size = if args.read_chunk_aligned {
+        chunk_aligned_batch_size(features.primary(), features.row_axis(), BATCH_SIZE)
+    } else {
+        BATCH_SIZE
+    };
+
+    let progress = ProgressBar::new(features.rows() as u64);
+    progress.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:50} {pos:>7}/{len:7} {msg}"),
+    );
+    throttle_progress(&progress, args.progress_every, batch_size);
+    progress.set_message("Assigning features ...");
+    let labels = construct_labels(&features, &mut index, args.stable_assign, batch_size, |n| {
+        progress.inc(u64::from(n));
+    })?;
+    progress.finish();
+
+    // save them
+    let progress = ProgressBar::new_spinner();
+    progress.set_message("Saving to file ...");
+
+    if args.assign_dtype != LabelType::I64 && labels.iter().any(|&b| b < 0) {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "some features have no valid assignment, which cannot be saved as {:?}",
+                args.assign_dtype
+            ),
+        ));
+    }
+
+    if args.out_format != ResultsFormat::Hdf5 {
+        let shape = [labels.len()];
+        let out = open_results_output(&args.out)?;
+        match args.assign_dtype {
+            LabelType::U16 => {
+                let labels: Vec<u16> = labels.iter().map(|&b| b as u16).collect();
+                write_results(out, args.out_format, &shape, &labels)?;
+            }
+            LabelType::U32 => {
+                let labels: Vec<u32> = labels.iter().map(|&b| b as u32).collect();
+                write_results(out, args.out_format, &shape, &labels)?;
+            }
+            LabelType::I64 => write_results(out, args.out_format, &shape, &labels)?,
+        }
+        if is_stdout(&args.out) {
+            progress.finish_and_clear();
+        } else {
+            progress.finish_and_clear();
+            println!("Labels saved: {}", args.out.display());
+        }
+        return Ok(());
+    }
+
+    let out = open_output(&args.out, args.force)?;
+    write_manifest(
+        &out,
+        &[
+            ("features_file_hash", args.features.as_path()),
+            ("vocabulary_file_hash", args.vocabulary.as_path()),
+        ],
+        !args.no_manifest_hash,
+    )?;
+    match args.assign_dtype {
+        LabelType::U16 => {
+            let labels: Vec<u16> = labels.iter().map(|&b| b as u16).collect();
+            write_labels(&out, &args.labels_dataset_name, &labels)?;
+        }
+        LabelType::U32 => {
+            let labels: Vec<u32> = labels.iter().map(|&b| b as u32).collect();
+            write_labels(&out, &args.labels_dataset_name, &labels)?;
+        }
+        LabelType::I64 => write_labels(&out, &args.labels_dataset_name, &labels)?,
+    }
+
+    progress.finish_and_clear();
+    println!("Labels saved: {}", args.out.display());
+    Ok(())
+}
+
+/// Open the features in `file` as the given vocabulary expects them:
+/// with its feature composition and column range, unless overridden,
+/// and centered on its mean, if any.
+fn open_vocabulary_features(
+    vocabulary: &Path,
+    file: &File,
+    name: Option<&str>,
+    cols: Option<ColumnRange>,
+) -> DynResult<FeatureSet> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => vocabulary_features_name(vocabulary, "data")?,
+    };
+    let mut features = FeatureSet::open(file, &name, false)?;
+    let cols = match cols {
+        Some(range) => Some(range),
+        None => vocabulary_feature_cols(vocabulary, "data")?,
+    };
+    if let Some(range) = cols {
+        features.select_cols(range)?;
+    }
+    if let Some(dims) = load_dims(vocabulary)? {
+        features.select_dims(dims)?;
+    }
+    if let Some(mean) = load_mean(vocabulary)? {
+        features.center(mean)?;
+    }
+    Ok(features)
+}
+
+fn evaluate_vocabulary(args: EvaluateArgs) -> DynResult<()> {
+    status::set_to_stderr(args.progress_to_stderr);
+    retry::set_retries(args.io_retries);
+    let file = File::open(&args.features, "r")?;
+    let mut features = open_vocabulary_features(
+        &args.vocabulary,
+        &file,
+        args.features_dataset_name.as_ref().map(String::as_str),
+        args.feature_cols,
+    )?;
+    if let Some(n) = args.n {
+        features.truncate(n);
+    }
+    check_flat_vocabulary_dim(&args.vocabulary, &features, &args.features)?;
+    let codebook = load_codebook(&args.vocabulary, "data")?;
+    let mut index = codebook_index(codebook.view())?;
+    let k = codebook.rows();
+
+    let progress = ProgressBar::new(features.rows() as u64);
+    progress.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:50} {pos:>7}/{len:7} {msg}"),
+    );
+    progress.set_message("Assigning features ...");
+    let mut counts = vec![0_u64; k];
+    let mut stats = args.codeword_stats.as_ref().map(|_| CodewordStats::new(k));
+    let mut total_error = 0_f64;
+    let mut assigned = 0_u64;
+    for batch in features.batches(BATCH_SIZE) {
+        let batch = batch?;
+        let feature_batch = batch.view();
+        let labels = nearest(
+            &mut index,
+            feature_batch
+                .as_slice()
+                .expect("features should be in standard layout"),
+            false,
+        )?;
+        for (feature, &label) in feature_batch.outer_iter().zip(&labels) {
+            if label < 0 {
+                continue;
+            }
+            let label = label as usize;
+            counts[label] += 1;
+            assigned += 1;
+            let centroid = codebook.row(label);
+            let error = Iterator::zip(feature.iter(), centroid.iter())
+                .map(|(x, c)| f64::from((x - c) * (x - c)))
+                .sum::<f64>();
+            total_error += error;
+            if let Some(ref mut stats) = stats {
+                stats.add(label, error);
+            }
+        }
+        progress.inc(feature_batch.rows() as u64);
+    }
+    progress.finish_and_clear();
+    warn_unassigned(features.rows() - assigned as usize, features.rows());
+
+    println!(
+        "Mean squared quantization error: {}",
+        total_error / assigned.max(1) as f64
+    );
+
+    if args.report_coverage {
+        let used = counts.iter().filter(|&&c| c > 0).count();
+        println!(
+            "Coverage: {}/{} codewords ({:.2}%)",
+            used,
+            k,
+            used as f64 / k as f64 * 100.
+        );
+        let mut by_use: Vec<usize> = (0..k).collect();
+        by_use.sort_by_key(|&i| (counts[i], i));
+        println!("Least used codewords:");
+        for &i in by_use.iter().take(args.least_used) {
+            println!("  {}: {}", i, counts[i]);
+        }
+    }
+    if let (Some(path), Some(stats)) = (&args.codeword_stats, &stats) {
+        write_codeword_stats(path, stats)?;
+    }
+    Ok(())
+}
+
+fn verify_bows(args: VerifyArgs) -> DynResult<()> {
+    let bows = File::open(&args.bows, "r")?;
+    check_format_version(&bows)?;
+    let vocabulary_file = match args.vocabulary {
+        Some(ref path) => Some(File::open(path, "r")?),
+        None => None,
+    };
+    let embedded;
+    let vocabulary: &Group = match vocabulary_file {
+        Some(ref file) => file,
+        None => {
+            embedded = bows.group("vocabulary").map_err(|_| {
+                failure(
+                    Category::BadInput,
+                    "no vocabulary given, and the bags file has no embedded vocabulary",
+                )
+            })?;
+            &embedded
+        }
+    };
+    // the bins of a product codebook are those of all of its sub-codebooks
+    let vocab_name = if vocabulary.link_exists("codebook") {
+        "codebook"
+    } else {
+        "data"
+    };
+    let (k, d) = codebook_shape(vocabulary, vocab_name)?;
+
+    let sparse;
+    let dense;
+    let (data, (n_items, width)) = match bows.group("data") {
+        Ok(group) => {
+            sparse = group;
+            let data: &Location = &sparse;
+            (data, sparse_shape(&sparse)?)
+        }
+        Err(_) => {
+            dense = bows.dataset("data")?;
+            let shape = match dense.shape()[..] {
+                [n_items, width] => (n_items, width),
+                ref shape => {
+                    return Err(failure(
+                        Category::BadInput,
+                        format!("bags should be two-dimensional, found shape {:?}", shape),
+                    ))
+                }
+            };
+            let data: &Location = &dense;
+            (data, shape)
+        }
+    };
+    let (n_items, width) = match read_str_attr(data, "orientation")? {
+        Some(ref orientation) if orientation == "codeword-major" => (width, n_items),
+        _ => (n_items, width),
+    };
+
+    let mut problems = Vec::new();
+    if width != k && width != 2 * k * d {
+        problems.push(format!(
+            "bags have {} components, but the vocabulary has {} codewords",
+            width, k
+        ));
+    }
+
+    if let Some(bows_hash) = read_str_attr(&bows, "vocab_hash")? {
+        let expected_hash = match read_str_attr(vocabulary, "vocab_hash")? {
+            Some(hash) => hash,
+            None => codebook_hash(read_centroids(&vocabulary.dataset(vocab_name)?)?.view()),
+        };
+        if bows_hash != expected_hash {
+            problems.push(format!(
+                "bags were built against vocabulary {}, not {}",
+                bows_hash, expected_hash
+            ));
+        }
+    }
+
+    for name in &[&args.item_id, &args.item_name] {
+        if !bows.link_exists(name) {
+            continue;
+        }
+        let len = bows.dataset(name)?.shape()[0];
+        if len != n_items {
+            problems.push(format!(
+                "`{}` has {} elements, but there are {} bags",
+                name, len, n_items
+            ));
+        }
+    }
+
+    if problems.is_empty() {
+        println!("{}: OK", args.bows.display());
+        if let Some(knn) = read_attr::<u32>(data, "knn")? {
+            println!(
+                "Built with knn = {}, soft_sigma = {}, normalize = {}, tfidf = {}, binary = {}",
+                knn,
+                read_attr::<f32>(data, "soft_sigma")?.unwrap_or_default(),
+                read_str_attr(data, "normalize")?.unwrap_or_else(|| "none".to_string()),
+                read_attr::<bool>(data, "tfidf")?.unwrap_or_default(),
+                read_attr::<bool>(data, "binary")?.unwrap_or_default(),
+            );
+        }
+        if let Some(name) = read_str_attr(data, "feature_weights")? {
+            println!("Features weighted by `{}`", name);
+        }
+        Ok(())
+    } else {
+        Err(failure(Category::BadInput, problems.join("\n")))
+    }
+}
+
+/// Open an output file. When forced, an existing file is kept as is,
+/// so that only the data sets written to it are replaced.
+/// Otherwise, the file is created anew.
+/// It is written under a temporary name until the run succeeds.
+fn open_output(path: &Path, force: bool) -> DynResult<File> {
+    output::open(path, force)
+}
+
+/// Remove the object at `name` from an output file, if it exists,
+/// so that it can be created again.
+fn remove_existing(file: &File, name: &str) -> DynResult<()> {
+    if file.link_exists(name) {
+        file.unlink(name)?;
+        status!("Overwriting `{}` in {}", name, file.filename());
+    }
+    Ok(())
+}
+
+/// Load the bags of a bags file, one row per item, whatever its orientation.
+fn load_bags(path: &Path) -> DynResult<Array2<f32>> {
+    let file = File::open(path, "r")?;
+    check_format_version(&file)?;
+    let (bags, orientation) = match file.group("data") {
+        Ok(group) => (
+            read_sparse_bags(&group)?,
+            read_str_attr(&group, "orientation")?,
+        ),
+        Err(_) => {
+            let dset = file.dataset("data")?;
+            (dset.read_2d()?, read_str_attr(&dset, "orientation")?)
+        }
+    };
+    match orientation {
+        Some(ref orientation) if orientation == "codeword-major" => {
+            Ok(Descriptors::Real(bags).transposed().into_real())
+        }
+        _ => Ok(bags),
+    }
+}
+
+fn search_bows(args: SearchArgs) -> DynResult<()> {
+    status::set_to_stderr(args.progress_to_stderr);
+    check_results_output(&args.out, args.out_format)?;
+    let progress = ProgressBar::new_spinner();
+    progress.set_message("Reading bags ...");
+    let mut database = load_bags(&args.database)?;
+    let mut queries = load_bags(&args.queries)?;
+    progress.finish_and_clear();
+    if database.cols() != queries.cols() {
+        return Err(failure(
+            Category::DimensionMismatch,
+            format!(
+                "database bags have {} components, but query bags have {}",
+                database.cols(),
+                queries.cols()
+            ),
+        ));
+    }
+    let database_hash = read_str_attr(&File::open(&args.database, "r")?, "vocab_hash")?;
+    let queries_hash = read_str_attr(&File::open(&args.queries, "r")?, "vocab_hash")?;
+    if let (Some(a), Some(b)) = (database_hash, queries_hash) {
+        if a != b {
+            eprintln!(
+                "Warning: database bags were built against vocabulary {}, but query bags against {}",
+                a, b
+            );
+        }
+    }
+
+    if database.rows() == 0 || args.top == 0 {
+        return Err(failure(
+            Category::EmptyData,
+            "nothing to search, the database is empty or no results were requested",
+        ));
+    }
+    let (n_queries, k) = (queries.rows(), usize::min(args.top, database.rows()));
+    let (labels, scores) = match args.score {
+        Score::L2 | Score::Cosine => {
+            let progress = ProgressBar::new_spinner();
+            progress.set_message("Searching ...");
+            progress.enable_steady_tick(300);
+            let d = database.cols() as u32;
+            // the cosine similarity is the inner product of L2 normalized bags
+            let mut index = if args.score == Score::Cosine {
+                normalize_rows(database.view_mut(), Normalization::L2);
+                normalize_rows(queries.view_mut(), Normalization::L2);
+                FlatIndex::new_ip(d)?
+            } else {
+                FlatIndex::new_l2(d)?
+            };
+            index.add(
+                database
+                    .as_slice()
+                    .expect("bags should be in standard layout"),
+            )?;
+            let result = index.search(
+                queries
+                    .as_slice()
+                    .expect("bags should be in standard layout"),
+                k,
+            )?;
+            progress.finish_and_clear();
+            (result.labels, result.distances)
+        }
+        Score::Intersection => {
+            // not supported by faiss, so the database is scanned for each query
+            let progress = ProgressBar::new(n_queries as u64);
+            progress.set_style(
+                ProgressStyle::default_bar()
+                    .template("[{elapsed_precise}] {bar:50} {pos:>7}/{len:7} {msg}"),
+            );
+            progress.set_message("Scoring ...");
+            let mut labels = Vec::with_capacity(n_queries * k);
+            let mut scores = Vec::with_capacity(n_queries * k);
+            for query in queries.outer_iter() {
+                let mut ranked: Vec<(f32, usize)> = database
+                    .outer_iter()
+                    .enumerate()
+                    .map(|(i, bag)| {
+                        let score = Iterator::zip(query.iter(), bag.iter())
+                            .map(|(q, b)| q.min(*b))
+                            .sum();
+                        (score, i)
+                    })
+                    .collect();
+                ranked.sort_by(|a, b| {
+                    b.0.partial_cmp(&a.0)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                        .then(a.1.cmp(&b.1))
+                });
+                for &(score, i) in ranked.iter().take(k) {
+                    labels.push(i as i64);
+                    scores.push(score);
+                }
+                progress.inc(1);
+            }
+            progress.finish_and_clear();
+            (labels, scores)
+        }
+    };
+
+    match args.out_format {
+        ResultsFormat::Hdf5 => {}
+        ResultsFormat::Text => {
+            let mut out = open_results_output(&args.out)?;
+            for (i, (labels, scores)) in labels.chunks(k).zip(scores.chunks(k)).enumerate() {
+                for (label, score) in labels.iter().zip(scores) {
+                    writeln!(out, "{}\t{}\t{}", i, label, score)?;
+                }
+            }
+            out.flush()?;
+            if !is_stdout(&args.out) {
+                println!("Results saved: {}", args.out.display());
+            }
+            return Ok(());
+        }
+        ResultsFormat::Npy => {
+            let out = open_results_output(&args.out)?;
+            write_results(out, ResultsFormat::Npy, &[n_queries, k], &labels)?;
+            if !is_stdout(&args.out) {
+                println!("Results saved: {}", args.out.display());
+            }
+            return Ok(());
+        }
+    }
+
+    let out = open_output(&args.out, args.force)?;
+    for name in &["indices", "scores"] {
+        remove_existing(&out, name)?;
+    }
+    out.new_dataset::<i64>()
+        .no_chunk()
+        .create("indices", (n_queries, k))?
+        .write_raw(&labels)?;
+    let scores_dset = out
+        .new_dataset::<f32>()
+        .no_chunk()
+        .create("scores", (n_queries, k))?;
+    scores_dset.write_raw(&scores)?;
+    write_str_attr(&scores_dset, "score", args.score.as_str())?;
+    write_manifest(
+        &out,
+        &[
+            ("database_file_hash", args.database.as_path()),
+            ("queries_file_hash", args.queries.as_path()),
+        ],
+        !args.no_manifest_hash,
+    )?;
+    println!("Results saved: {}", args.out.display());
+    Ok(())
+}
+
+/// Whether results are written to standard output rather than a file.
+fn is_stdout(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Fail early if results are to be written to standard output as hdf5.
+fn check_results_output(path: &Path, format: ResultsFormat) -> DynResult<()> {
+    if is_stdout(path) && format == ResultsFormat::Hdf5 {
+        return Err(failure(
+            Category::BadInput,
+            "hdf5 output cannot be written to standard output, \
+             pass `--out-format text` or `--out-format npy` with `-o -`",
+        ));
+    }
+    Ok(())
+}
+
+/// Open the output of results in text or `.npy` format:
+/// standard output for `-`, or a new file otherwise.
+fn open_results_output(path: &Path) -> DynResult<Box<dyn Write>> {
+    if is_stdout(path) {
+        return Ok(Box::new(BufWriter::new(io::stdout())));
+    }
+    let file = std::fs::File::create(path).map_err(|e| {
+        failure(
+            Category::Io,
+            format!("could not create {}: {}", path.display(), e),
+        )
+    })?;
+    Ok(Box::new(BufWriter::new(file)))
+}
+
+/// Write results of the given (one or two-dimensional) shape in text
+/// or `.npy` format, as one line of values per row in text.
+fn write_results<T: NpyElement + Display>(
+    mut out: Box<dyn Write>,
+    format: ResultsFormat,
+    shape: &[usize],
+    values: &[T],
+) -> DynResult<()> {
+    match format {
+        ResultsFormat::Npy => write_npy(&mut out, shape, values)?,
+        ResultsFormat::Text => {
+            let cols = shape.get(1).cloned().unwrap_or(1).max(1);
+            for row in values.chunks(cols) {
+                let row: Vec<String> = row.iter().map(T::to_string).collect();
+                writeln!(out, "{}", row.join(" "))?;
+            }
+        }
+        ResultsFormat::Hdf5 => unreachable!("hdf5 results are written as data sets"),
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Convert bags between the dense and sparse layouts, keeping their
+/// orientation, the settings they were built with and their items.
+fn sparsify_bows(args: SparsifyArgs) -> DynResult<()> {
+    status::set_to_stderr(args.progress_to_stderr);
+    let input = File::open(&args.bows, "r")?;
+    check_format_version(&input)?;
+    let group;
+    let dset;
+    let (data, bags) = match input.group("data") {
+        Ok(g) => {
+            if !args.densify {
+                return Err(failure(
+                    Category::BadInput,
+                    "the bags are not dense (pass --densify to convert them to dense bags)",
+                ));
+            }
+            group = g;
+            let data: &Location = &group;
+            (data, read_sparse_bags(&group)?)
+        }
+        Err(_) => {
+            if args.densify {
+                return Err(failure(Category::BadInput, "the bags are already dense"));
+            }
+            dset = input.dataset("data")?;
+            let data: &Location = &dset;
+            (data, dset.read_2d::<f32>()?)
+        }
+    };
+    let bags = Descriptors::Real(bags);
+    status!(
+        "{:.2}% of the bags' components are not zero",
+        bags.density() * 100.
+    );
+
+    let out = open_output(&args.out, args.force)?;
+    remove_existing(&out, "data")?;
+    if args.densify {
+        let dset = out
+            .new_dataset::<f32>()
+            .no_chunk()
+            .create("data", bags.dim())?;
+        copy_bags_attrs(data, &dset, "dense")?;
+        bags.write_to(&dset)?;
+    } else {
+        let group = out.create_group("data")?;
+        copy_bags_attrs(data, &group, "sparse")?;
+        bags.write_sparse_to(&group)?;
+    }
+
+    if let Some(hash) = read_str_attr(&input, "vocab_hash")? {
+        write_str_attr(&out, "vocab_hash", &hash)?;
+    }
+    if let Some(encoding) = read_str_attr(&input, "encoding")? {
+        let layout = if args.densify { "dense" } else { "sparse" };
+        write_attr(&out, "format_version", &FORMAT_VERSION)?;
+        write_str_attr(&out, "encoding", &encoding_with_layout(&encoding, layout))?;
+    }
+    if input.link_exists(&args.item_id) {
+        let ids: Vec<u32> = input.dataset(&args.item_id)?.read_raw()?;
+        write_labels(&out, &args.item_id, &ids)?;
+    }
+    if input.link_exists(&args.item_name) {
+        let names: Vec<VarLenUnicode> = input.dataset(&args.item_name)?.read_raw()?;
+        write_labels(&out, &args.item_name, &names)?;
+    }
+    write_manifest(
+        &out,
+        &[("bags_file_hash", args.bows.as_path())],
+        !args.no_manifest_hash,
+    )?;
+    println!("Bags saved: {}", args.out.display());
+    Ok(())
+}
+
+/// Copy the attributes recorded by `quantize` from the bags in `from`
+/// to those in `to`, recording the given layout.
+fn copy_bags_attrs(from: &Location, to: &Location, layout: &str) -> DynResult<()> {
+    write_str_attr(to, "layout", layout)?;
+    for name in &["orientation", "normalize", "feature_weights"] {
+        if let Some(value) = read_str_attr(from, name)? {
+            write_str_attr(to, name, &value)?;
+        }
+    }
+    for name in &["tfidf", "binary"] {
+        if let Some(value) = read_attr::<bool>(from, name)? {
+            write_attr(to, name, &value)?;
+        }
+    }
+    if let Some(knn) = read_attr::<u32>(from, "knn")? {
+        write_attr(to, "knn", &knn)?;
+    }
+    if let Some(sigma) = read_attr::<f32>(from, "soft_sigma")? {
+        write_attr(to, "soft_sigma", &sigma)?;
+    }
+    if from
+        .attribute_names()?
+        .iter()
+        .any(|n| n == "codebook_offsets")
+    {
+        let offsets: Vec<u64> = from.attribute("codebook_offsets")?.read_raw()?;
+        to.new_attribute::<u64>()
+            .create("codebook_offsets", offsets.len())?
+            .write_raw(&offsets)?;
+    }
+    Ok(())
+}
+
+/// Write a one-dimensional labels data set.
+fn write_labels<T: H5Type>(file: &File, name: &str, labels: &[T]) -> DynResult<()> {
+    remove_existing(file, name)?;
+    file.new_dataset::<T>()
+        .no_chunk()
+        .create(name, (labels.len(),))?
+        .write_raw(labels)?;
+    Ok(())
+}
+
+/// The labels of each feature, written to a data set of an output file
+/// batch by batch as they are assigned, rather than held in memory.
+struct LabelsWriter {
+    /// The output file, kept open along with the data set
+    _file: File,
+    dset: Dataset,
+    bins_per_feature: usize,
+    /// The labels of the current batch
+    batch: Vec<i64>,
+}
+
+impl LabelsWriter {
+    /// Create the labels data set `name` of `n` features in `file`, with
+    /// `bins_per_feature` labels per feature (one row per feature if more
+    /// than one), all `-1` until they are written.
+    fn create(file: File, name: &str, n: usize, bins_per_feature: usize) -> DynResult<Self> {
+        remove_existing(&file, name)?;
+        let dset = if bins_per_feature == 1 {
+            file.new_dataset::<i64>().no_chunk().create(name, (n,))?
+        } else {
+            file.new_dataset::<i64>()
+                .no_chunk()
+                .create(name, (n, bins_per_feature))?
+        };
+        let mut writer = LabelsWriter {
+            _file: file,
+            dset,
+            bins_per_feature,
+            batch: Vec::new(),
+        };
+        // features which are never read, being outside of any item,
+        // are left unassigned
+        for begin in (0..n).step_by(BATCH_SIZE) {
+            writer.start(usize::min(BATCH_SIZE, n - begin));
+            writer.finish(begin)?;
+        }
+        Ok(writer)
+    }
+
+    /// The labels of the next batch of `n` features, all `-1` until assigned.
+    fn start(&mut self, n: usize) -> &mut [i64] {
+        self.batch.clear();
+        self.batch.resize(n * self.bins_per_feature, -1);
+        &mut self.batch
+    }
+
+    /// Write the labels of the batch to its features, from the `begin`-th.
+    fn finish(&self, begin: usize) -> DynResult<()> {
+        let n = self.batch.len() / self.bins_per_feature;
+        if self.bins_per_feature == 1 {
+            self.dset
+                .write_slice(ArrayView1::from(&self.batch[..]), s![begin..begin + n])?;
+        } else {
+            let labels = ArrayView2::from_shape((n, self.bins_per_feature), &self.batch[..])?;
+            self.dset.write_slice(labels, s![begin..begin + n, ..])?;
+        }
+        Ok(())
+    }
+}
+
+/// Snap the given batch size to a multiple of the data set's chunk length
+/// along the axis of the features, so that each chunk is only decompressed once.
+/// Contiguous data sets keep the original batch size.
+fn chunk_aligned_batch_size(dset: &Dataset, axis: usize, batch_size: usize) -> usize {
+    match dset.chunks() {
+        Some(ref chunks) if chunks.len() > axis && chunks[axis] > 0 => {
+            let chunk_len = chunks[axis];
+            usize::max(1, (batch_size + chunk_len / 2) / chunk_len) * chunk_len
+        }
+        _ => batch_size,
+    }
+}
+
+/// The largest batch of features of `d` dimensions which fits in `budget`
+/// bytes as `f32` vectors, `held` batches at a time,
+/// within `MIN_BATCH_SIZE..=MAX_BATCH_SIZE`.
+fn memory_batch_size(budget: u64, d: usize, held: usize) -> usize {
+    let size = budget / (held.max(1) as u64 * d.max(1) as u64 * 4);
+    if size < MIN_BATCH_SIZE as u64 {
+        eprintln!(
+            "Warning: {} bytes do not fit {} batches of {} features of {} dimensions, reading {} at a time",
+            budget, held, MIN_BATCH_SIZE, d, MIN_BATCH_SIZE
+        );
+    }
+    u64::min(size, MAX_BATCH_SIZE as u64).max(MIN_BATCH_SIZE as u64) as usize
+}
+
+/// Iterate over a one-dimensional data set in batches of `batch_size` elements.
+/// The length of the data set is taken once, so that elements appended
+/// to an extendible data set while iterating are left out.
+fn batched_1d<'a, T>(
+    dset: &'a Dataset,
+    batch_size: usize,
+) -> impl Iterator<Item = h5::Result<Array1<T>>> + 'a
+where
+    T: h5::H5Type,
+{
+    let len = dset.shape()[0];
+    let batch_offset = len % batch_size;
+    let nbatches = len / batch_size + if batch_offset > 0 { 1 } else { 0 };
+
+    (0..nbatches).map(move |i| {
+        let begin = i * batch_size;
+        let end = usize::min(begin + batch_size, len);
+        retry::read(|| dset.read_slice_1d::<T, _>(s![begin..end]))
+    })
+}
+
+/// Assign every feature in the data set to its nearest codeword,
+/// keeping the same order as the input rows.
+/// Features without a valid assignment are labelled `-1`.
+fn construct_labels<F>(
+    features: &FeatureSet,
+    index: &mut Index,
+    stable: bool,
+    batch_size: usize,
+    tick_fn: F,
+) -> DynResult<Vec<i64>>
+where
+    F: Fn(u32),
+{
+    let mut labels = Vec::with_capacity(features.rows());
+    for batch in features.batches(batch_size) {
+        let batch = batch?;
+        let feature_batch = batch.view();
+        let b_size = feature_batch.shape()[0];
+        labels.extend(nearest(
+            index,
+            feature_batch
                 .as_slice()
                 .expect("features should be in standard layout"),
-            1,
-        )?;
-        for b in nearest.labels.into_iter() {
-            if b >= 0 {
-                *bows
-                    .get_mut([b as usize])
-                    .unwrap_or_else(|| panic!("invalid BoW index ({})", b)) += 1_u32;
+            stable,
+        )?);
+
+        tick_fn(b_size as u32);
+    }
+    let skipped = labels.iter().filter(|&&b| b < 0).count();
+    warn_unassigned(skipped, labels.len());
+    Ok(labels)
+}
+
+fn construct_fisher_vectors<F>(
+    features: &FeatureSet,
+    id_slice_dset: Option<&Dataset>,
+    n_items: usize,
+    gmm: &Gmm,
+    batch_size: usize,
+    tick_fn: F,
+) -> DynResult<Array2<f32>>
+where
+    F: Fn(u32, Option<&[u32]>),
+{
+    let mut encoder = FisherEncoder::new(gmm, n_items);
+    if let Some(id_slice_dset) = id_slice_dset {
+        for (batch, item_batch) in Iterator::zip(
+            features.batches(batch_size),
+            batched_1d::<u32>(&id_slice_dset, batch_size),
+        ) {
+            if interrupt::interrupted() {
+                break;
+            }
+            let (batch, item_batch) = (batch?, item_batch?);
+            let feature_batch = batch.view();
+            let b_size = feature_batch.shape()[0];
+            encoder.add(
+                feature_batch.view(),
+                item_batch.iter().map(|&id| id as usize),
+            )?;
+            tick_fn(b_size as u32, item_batch.as_slice());
+        }
+    } else {
+        for batch in features.batches(batch_size) {
+            if interrupt::interrupted() {
+                break;
             }
+            let batch = batch?;
+            let feature_batch = batch.view();
+            let b_size = feature_batch.shape()[0];
+            encoder.add(feature_batch.view(), std::iter::repeat(0))?;
+            tick_fn(b_size as u32, None);
         }
+    }
+    Ok(encoder.finish())
+}
 
-        tick_fn(b_size as u32);
+/// Weight codeword counts by term frequency-inverse document frequency,
+/// in place. Each count is divided by the total count of its item,
+/// then multiplied by the codeword's inverse document frequency.
+fn apply_tfidf(mut bows: ArrayViewMut2<f32>, idf: ArrayView1<f32>) {
+    for mut row in bows.outer_iter_mut() {
+        let total: f32 = row.iter().sum();
+        if total > 0. {
+            row.mapv_inplace(|c| c / total);
+        }
+        row *= &idf;
     }
-    Ok(bows)
 }
 
-fn construct_bows<F>(
-    features_dset: &Dataset,
+/// Build TF-IDF weighted bags in two passes over the features, without
+/// holding all bags in memory, writing them to `bows_dset`.
+///
+/// The first pass counts the number of items in which each codeword occurs.
+/// The second one builds the bag of each item in turn, writing it as soon as
+/// the next item begins, which requires item IDs in non-decreasing order.
+/// Returns the inverse document frequencies.
+#[allow(clippy::too_many_arguments)]
+fn construct_two_pass_tfidf<F>(
+    features: &FeatureSet,
     id_slice_dset: &Dataset,
+    valid_mask_dset: Option<&Dataset>,
     n_items: usize,
-    index: &mut Index,
+    assigner: &mut Assigner,
+    excluded: &[bool],
+    normalize: Normalization,
+    batch_size: usize,
+    bows_dset: &Dataset,
     tick_fn: F,
-) -> DynResult<Array2<u32>>
+) -> DynResult<Array1<f32>>
 where
-    F: Fn(u32),
+    F: Fn(u32, Option<&[u32]>),
 {
-    let batch_size = 1024;
-    let mut bows = Array2::<u32>::zeros([n_items, index.ntotal() as usize]);
-    for (feature_batch, item_batch) in Iterator::zip(
-        batched_2d::<f32>(&features_dset, batch_size),
-        batched_1d::<u32>(&id_slice_dset, batch_size),
-    ) {
+    let k = assigner.n_bins();
+    let mut invalid_item = None;
+
+    // first pass: document frequencies
+    let mut df = vec![0_u32; k];
+    let mut current = None;
+    let mut seen = vec![false; k];
+    let mut touched = Vec::new();
+    for_each_assignment(
+        features,
+        Items::Ids(id_slice_dset),
+        valid_mask_dset,
+        None,
+        assigner,
+        None,
+        batch_size,
+        |item, _, b, _| {
+            if current != Some(item) {
+                if item >= n_items || current.map_or(false, |c| item < c) {
+                    invalid_item = invalid_item.or(Some(item));
+                }
+                for &t in &touched {
+                    seen[t] = false;
+                }
+                touched.clear();
+                current = Some(item);
+            }
+            if !seen[b] && !excluded[b] {
+                seen[b] = true;
+                touched.push(b);
+                df[b] += 1;
+            }
+        },
+        &tick_fn,
+    )?;
+    if let Some(item) = invalid_item {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "item {} is out of order or out of range, \
+             two-pass TF-IDF requires item IDs in non-decreasing order",
+                item
+            ),
+        ));
+    }
+    let idf: Array1<f32> = df
+        .iter()
+        .map(|&df| {
+            if df > 0 {
+                (n_items as f32 / df as f32).ln()
+            } else {
+                0.
+            }
+        })
+        .collect();
+
+    // second pass: weighted bags, one item at a time
+    let mut bow = Array2::<f32>::zeros((1, k));
+    let mut current = None;
+    let mut write_result = Ok(());
+    let mut flush = |item: usize, bow: &mut Array2<f32>| {
+        if write_result.is_ok() {
+            apply_tfidf(bow.view_mut(), idf.view());
+            normalize_rows(bow.view_mut(), normalize);
+            write_result = bows_dset.write_slice(bow.view(), s![item..item + 1, ..]);
+        }
+        bow.fill(0.);
+    };
+    for_each_assignment(
+        features,
+        Items::Ids(id_slice_dset),
+        valid_mask_dset,
+        None,
+        assigner,
+        None,
+        batch_size,
+        |item, _, b, _| {
+            if current != Some(item) {
+                if let Some(c) = current {
+                    flush(c, &mut bow);
+                }
+                current = Some(item);
+            }
+            if !excluded[b] {
+                bow[(0, b)] += 1.;
+            }
+        },
+        &tick_fn,
+    )?;
+    if let Some(c) = current {
+        flush(c, &mut bow);
+    }
+    write_result?;
+    Ok(idf)
+}
+
+/// Where the item of each feature is defined.
+#[derive(Clone, Copy)]
+enum Items<'a> {
+    /// All features belong to item 0
+    Single,
+    /// The item ID of each feature is in a data set, row by row
+    Ids(&'a Dataset),
+    /// Like `Ids`, checked to never decrease as the features are read
+    SortedIds(&'a Dataset),
+    /// The features of each item are the rows `start..end` of its offsets
+    Offsets(&'a [(usize, usize)]),
+    /// The item IDs of consecutive features, as `(item_id, run_length)` runs
+    Runs(&'a [(u32, usize)]),
+}
+
+/// Read the names of the items in a features file, from the item_name
+/// data set or from the JSON map in the item_name_attr attribute.
+fn read_item_names(file: &File, args: &QuantizeArgs) -> DynResult<Vec<VarLenUnicode>> {
+    let attr = match args.item_name_attr {
+        Some(ref attr) => attr,
+        None => {
+            let name = args.item_name.as_ref().map_or(ITEM_NAME, String::as_str);
+            if !file.link_exists(name) {
+                return Err(failure(
+                    Category::BadInput,
+                    format!(
+                        "features file has no `{}` data set of item names \
+                     (use --item_name or --item-name-attr)",
+                        name
+                    ),
+                ));
+            }
+            let dset = file.dataset(name)?;
+            if dset.ndim() == 2 && dset.dtype()? == Datatype::from_type::<u8>()? {
+                return decode_byte_names(&dset);
+            }
+            return Ok(dset.read_raw()?);
+        }
+    };
+    let json = read_str_attr(file, attr)?.ok_or_else(|| {
+        failure(
+            Category::BadInput,
+            format!("features file has no `{}` attribute of item names", attr),
+        )
+    })?;
+    parse_item_names(&json)
+        .map_err(|e| {
+            failure(
+                Category::BadInput,
+                format!("invalid item names in attribute `{}`: {}", attr, e),
+            )
+        })?
+        .into_iter()
+        .map(|name| {
+            name.parse()
+                .map_err(|_| failure(Category::BadInput, format!("invalid item name `{}`", name)))
+        })
+        .collect()
+}
+
+/// Decode item names stored as a two-dimensional `u8` data set, one name per
+/// row of UTF-8 bytes, padded with trailing NULs to the width of the rows.
+fn decode_byte_names(dset: &Dataset) -> DynResult<Vec<VarLenUnicode>> {
+    let bytes = dset.read_2d::<u8>()?;
+    bytes
+        .outer_iter()
+        .enumerate()
+        .map(|(i, row)| -> DynResult<VarLenUnicode> {
+            let row = row.to_vec();
+            let len = row.iter().rposition(|&b| b != 0).map_or(0, |end| end + 1);
+            let name = std::str::from_utf8(&row[..len]).map_err(|e| {
+                failure(
+                    Category::BadInput,
+                    format!("item name {} is not valid UTF-8: {}", i, e),
+                )
+            })?;
+            Ok(name.parse().map_err(|_| {
+                failure(Category::BadInput, format!("invalid item name `{}`", name))
+            })?)
+        })
+        .collect()
+}
+
+/// Load a mapping of item names from a CSV file of `from,to` lines.
+/// Blank lines and lines starting with `#` are ignored.
+fn load_item_renames(path: &Path) -> DynResult<HashMap<String, String>> {
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        failure(
+            Category::Io,
+            format!("could not read {}: {}", path.display(), e),
+        )
+    })?;
+    let mut renames = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(2, ',');
+        let (from, to) = match (fields.next(), fields.next()) {
+            (Some(from), Some(to)) => (from.trim(), to.trim()),
+            _ => {
+                return Err(failure(
+                    Category::BadInput,
+                    format!(
+                        "{}:{}: expected a `from,to` pair of item names",
+                        path.display(),
+                        i + 1
+                    ),
+                ))
+            }
+        };
+        if renames.insert(from.to_string(), to.to_string()).is_some() {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "{}:{}: item `{}` is renamed more than once",
+                    path.display(),
+                    i + 1,
+                    from
+                ),
+            ));
+        }
+    }
+    Ok(renames)
+}
+
+/// Rename the items listed in `renames`, reporting how many were renamed.
+fn rename_items(
+    names: Vec<VarLenUnicode>,
+    renames: &HashMap<String, String>,
+) -> DynResult<Vec<VarLenUnicode>> {
+    let mut renamed = 0;
+    let names = names
+        .into_iter()
+        .map(|name| match renames.get(name.as_str()) {
+            Some(to) => {
+                renamed += 1;
+                to.parse()
+                    .map_err(|_| failure(Category::BadInput, format!("invalid item name `{}`", to)))
+            }
+            None => Ok(name),
+        })
+        .collect::<DynResult<Vec<_>>>()?;
+    status!("Renamed {} of {} items", renamed, names.len());
+    Ok(names)
+}
+
+/// Parse item names from either a JSON object mapping each item ID
+/// in `0..n` to its name, or a JSON array of the names in item order.
+fn parse_item_names(json: &str) -> DynResult<Vec<String>> {
+    fn name(value: Value) -> DynResult<String> {
+        match value {
+            Value::String(name) => Ok(name),
+            value => Err(failure(
+                Category::BadInput,
+                format!("expected a string name, found `{}`", value),
+            )),
+        }
+    }
+    match serde_json::from_str(json)? {
+        Value::Array(values) => values.into_iter().map(name).collect(),
+        Value::Object(map) => {
+            let mut names = vec![None; map.len()];
+            for (id, value) in map {
+                let i = match id.parse::<usize>() {
+                    Ok(i) if i < names.len() => i,
+                    _ => {
+                        return Err(failure(
+                            Category::BadInput,
+                            format!("`{}` is not an item ID in 0..{}", id, names.len()),
+                        ))
+                    }
+                };
+                if names[i].is_some() {
+                    return Err(failure(
+                        Category::BadInput,
+                        format!("item ID {} is mapped more than once", i),
+                    ));
+                }
+                names[i] = Some(name(value)?);
+            }
+            // as many distinct IDs in range as there are names, so none is missing
+            Ok(names
+                .into_iter()
+                .map(|name| name.expect("every item should have a name"))
+                .collect())
+        }
+        _ => Err(failure(
+            Category::BadInput,
+            "expected an object from item IDs to names, or an array of names",
+        )),
+    }
+}
+
+/// Read the offsets of each item's features, a `n_items x 2` data set
+/// of `[start, end)` row ranges within `rows` features.
+fn read_item_offsets(dset: &Dataset, rows: usize) -> DynResult<Vec<(usize, usize)>> {
+    if dset.ndim() != 2 || dset.shape()[1] != 2 {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "item offsets should have shape (n_items, 2), found {:?}",
+                dset.shape()
+            ),
+        ));
+    }
+    let offsets = dset.read_2d::<u64>()?;
+    offsets
+        .outer_iter()
+        .enumerate()
+        .map(|(item, range)| {
+            let (start, end) = (range[0] as usize, range[1] as usize);
+            if start > end || end > rows {
+                return Err(failure(
+                    Category::BadInput,
+                    format!(
+                        "item {} has invalid offsets [{}, {}) for {} features",
+                        item, start, end, rows
+                    ),
+                ));
+            }
+            Ok((start, end))
+        })
+        .collect()
+}
+
+/// Read run-length encoded item IDs, a `n_runs x 2` data set
+/// of `(item_id, run_length)` pairs which should cover all `rows`
+/// features, with item IDs below `n_items`.
+fn read_item_runs(dset: &Dataset, rows: usize, n_items: usize) -> DynResult<Vec<(u32, usize)>> {
+    if dset.ndim() != 2 || dset.shape()[1] != 2 {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "run-length encoded item IDs should have shape (n_runs, 2), found {:?}",
+                dset.shape()
+            ),
+        ));
+    }
+    let runs = dset.read_2d::<u64>()?;
+    let runs: Vec<(u32, usize)> = runs
+        .outer_iter()
+        .map(|run| (run[0] as u32, run[1] as usize))
+        .collect();
+    if let Some(&(id, _)) = runs.iter().find(|&&(id, _)| id as usize >= n_items) {
+        return Err(failure(
+            Category::BadInput,
+            format!("item ID {} is out of range for {} items", id, n_items),
+        ));
+    }
+    let total: usize = runs.iter().map(|&(_, len)| len).sum();
+    if total != rows {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "run-length encoded item IDs expand to {} features, but there are {}",
+                total, rows
+            ),
+        ));
+    }
+    Ok(runs)
+}
+
+/// Expand run-length encoded item IDs in batches of `batch_size` elements.
+fn run_batches<'a>(
+    runs: &'a [(u32, usize)],
+    batch_size: usize,
+) -> impl Iterator<Item = h5::Result<Array1<u32>>> + 'a {
+    let mut ids = runs
+        .iter()
+        .flat_map(|&(id, len)| std::iter::repeat(id).take(len));
+    std::iter::from_fn(move || {
+        let batch: Array1<u32> = ids.by_ref().take(batch_size).collect();
+        if batch.is_empty() {
+            None
+        } else {
+            Some(Ok(batch))
+        }
+    })
+}
+
+/// Check that the item IDs of the first `rows` features are all within
+/// the item names, returning the number of items. With `trust_item_id`,
+/// the names are instead padded with the IDs of the extra items,
+/// or truncated, to one more than the largest item ID.
+fn reconcile_item_names(
+    dset: &Dataset,
+    rows: usize,
+    names: &mut Vec<VarLenUnicode>,
+    trust_item_id: bool,
+) -> DynResult<usize> {
+    let mut max_id = None;
+    let mut seen = 0;
+    for ids in batched_1d::<u32>(dset, BATCH_SIZE) {
+        let ids = ids?;
+        if let Some(&id) = ids.iter().take(rows - seen).max() {
+            max_id = Some(max_id.map_or(id, |max: u32| max.max(id)));
+        }
+        seen = usize::min(seen + ids.len(), rows);
+        if seen == rows {
+            break;
+        }
+    }
+    let referenced = max_id.map_or(0, |id| id as usize + 1);
+    if !trust_item_id {
+        if referenced > names.len() {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "item IDs go up to {}, but there are only {} item names \
+                     (pass --trust-item-id to size the bags by the item IDs)",
+                    referenced - 1,
+                    names.len()
+                ),
+            ));
+        }
+        return Ok(names.len());
+    }
+    if referenced != names.len() {
+        eprintln!(
+            "Warning: sizing the bags by the item IDs, {} items for {} item names",
+            referenced,
+            names.len()
+        );
+        names.truncate(referenced);
+        for id in names.len()..referenced {
+            names.push(
+                id.to_string()
+                    .parse()
+                    .expect("item IDs should be valid item names"),
+            );
+        }
+    }
+    Ok(referenced)
+}
+
+/// Count the features of each item, among the first `rows` features.
+fn count_item_features(items: Items, n_items: usize, rows: usize) -> DynResult<Vec<u32>> {
+    let mut counts = vec![0_u32; n_items];
+    match items {
+        Items::Single => counts[0] = rows as u32,
+        Items::Ids(dset) | Items::SortedIds(dset) => {
+            let mut seen = 0;
+            for ids in batched_1d::<u32>(dset, BATCH_SIZE) {
+                let ids = ids?;
+                for &id in ids.iter().take(rows - seen) {
+                    let count = counts.get_mut(id as usize).ok_or_else(|| {
+                        failure(
+                            Category::BadInput,
+                            format!("item ID {} is out of range", id),
+                        )
+                    })?;
+                    *count += 1;
+                }
+                seen = usize::min(seen + ids.len(), rows);
+                if seen == rows {
+                    break;
+                }
+            }
+        }
+        Items::Offsets(offsets) => {
+            for (count, &(start, end)) in counts.iter_mut().zip(offsets) {
+                *count = usize::min(end, rows).saturating_sub(start) as u32;
+            }
+        }
+        Items::Runs(runs) => {
+            let mut seen = 0;
+            for &(id, len) in runs {
+                let len = usize::min(len, rows - seen);
+                counts[id as usize] += len as u32;
+                seen += len;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+/// Print a histogram of the number of features per item,
+/// in buckets of powers of two.
+fn print_feature_count_hist(counts: &[u32]) {
+    if counts.is_empty() {
+        return;
+    }
+    // bucket 0 holds the items without features,
+    // bucket `i` those with `2^(i-1)..2^i` features
+    let bucket = |c: u32| (32 - c.leading_zeros()) as usize;
+    let mut buckets = vec![0_usize; bucket(*counts.iter().max().unwrap_or(&0)) + 1];
+    for &c in counts {
+        buckets[bucket(c)] += 1;
+    }
+    let mut sorted = counts.to_vec();
+    sorted.sort_unstable();
+    println!(
+        "Features per item ({} items, min {}, median {}, max {}):",
+        counts.len(),
+        sorted[0],
+        sorted[sorted.len() / 2],
+        sorted[sorted.len() - 1]
+    );
+    let widest = buckets.iter().cloned().max().unwrap_or(1).max(1);
+    for (i, &n) in buckets.iter().enumerate() {
+        let range = match i {
+            0 => "0".to_string(),
+            1 => "1".to_string(),
+            _ => format!("{}-{}", 1_u64 << (i - 1), (1_u64 << i) - 1),
+        };
+        println!(
+            "{:>15} | {:>8} {}",
+            range,
+            n,
+            "#".repeat((n * 40 + widest - 1) / widest)
+        );
+    }
+}
+
+/// A batch of features read by `for_each_item_batch`,
+/// with what was read along with them.
+struct ItemBatch<'a> {
+    /// The features of the batch
+    features: ArrayView2<'a, f32>,
+    /// The index of the first feature of the batch
+    begin: usize,
+    /// The item of all features of the batch, if known as a whole
+    item: Option<usize>,
+    /// The item ID of each feature of the batch, if read
+    item_ids: Option<&'a Array1<u32>>,
+    /// Whether each feature of the batch is valid, if read
+    mask: Option<&'a Array1<bool>>,
+    /// The weight of each feature of the batch, if read
+    weights: Option<&'a Array1<f32>>,
+}
+
+impl ItemBatch<'_> {
+    /// The item of the `i`-th feature of the batch.
+    fn item(&self, i: usize) -> usize {
+        match self.item {
+            Some(item) => item,
+            None => self.item_ids.map_or(0, |ids| ids[i] as usize),
+        }
+    }
+}
+
+/// Read the features batch by batch, along with their items,
+/// valid mask and weights, if any, calling `batch_fn` with each batch.
+///
+/// With item offsets, the features of each item are read separately,
+/// and features outside of any item are never read.
+/// `tick_fn` is called after each batch with its number of features
+/// and their item IDs, if they were read.
+fn for_each_item_batch<B, F>(
+    features: &FeatureSet,
+    items: Items,
+    valid_mask_dset: Option<&Dataset>,
+    weights_dset: Option<&Dataset>,
+    batch_size: usize,
+    mut batch_fn: B,
+    tick_fn: F,
+) -> DynResult<()>
+where
+    B: FnMut(&ItemBatch) -> DynResult<()>,
+    F: Fn(u32, Option<&[u32]>),
+{
+    if let Items::Offsets(offsets) = items {
+        let mut buffer = Array2::zeros((0, 0));
+        'items: for (item, &(start, end)) in offsets.iter().enumerate() {
+            // features past a truncation are left out
+            let end = usize::min(end, features.rows());
+            for begin in (start..end).step_by(batch_size) {
+                if interrupt::interrupted() {
+                    break 'items;
+                }
+                let batch_end = usize::min(begin + batch_size, end);
+                let batch = features.batch_into(begin, batch_end, &mut buffer)?;
+                let mask = match valid_mask_dset {
+                    Some(dset) => Some(retry::read(|| {
+                        dset.read_slice_1d::<bool, _>(s![begin..batch_end])
+                    })?),
+                    None => None,
+                };
+                let weights = match weights_dset {
+                    Some(dset) => Some(retry::read(|| {
+                        dset.read_slice_1d::<f32, _>(s![begin..batch_end])
+                    })?),
+                    None => None,
+                };
+                batch_fn(&ItemBatch {
+                    features: batch,
+                    begin,
+                    item: Some(item),
+                    item_ids: None,
+                    mask: mask.as_ref(),
+                    weights: weights.as_ref(),
+                })?;
+                tick_fn((batch_end - begin) as u32, None);
+            }
+        }
+        return Ok(());
+    }
+
+    let mut item_batches: Option<Box<dyn Iterator<Item = h5::Result<Array1<u32>>> + '_>> =
+        match items {
+            Items::Ids(dset) | Items::SortedIds(dset) => {
+                Some(Box::new(batched_1d::<u32>(dset, batch_size)))
+            }
+            Items::Runs(runs) => Some(Box::new(run_batches(runs, batch_size))),
+            _ => None,
+        };
+    let mut mask_batches = valid_mask_dset.map(|dset| batched_1d::<bool>(dset, batch_size));
+    let mut weight_batches = weights_dset.map(|dset| batched_1d::<f32>(dset, batch_size));
+    let mut last_id = None;
+    let mut begin = 0;
+    let start = Instant::now();
+    let waited = features.for_each_batch(batch_size, |feature_batch| {
+        if interrupt::interrupted() {
+            return Ok(false);
+        }
+        let item_batch = match item_batches {
+            Some(ref mut batches) => match batches.next() {
+                Some(batch) => Some(batch?),
+                None => return Ok(false),
+            },
+            None => None,
+        };
+        if let (Items::SortedIds(_), Some(ids)) = (items, item_batch.as_ref()) {
+            check_monotonic_ids(ids, begin, begin / batch_size, &mut last_id)?;
+        }
+        let mask_batch = match mask_batches.as_mut().and_then(|batches| batches.next()) {
+            Some(batch) => Some(batch?),
+            None => None,
+        };
+        let weight_batch = match weight_batches.as_mut().and_then(|batches| batches.next()) {
+            Some(batch) => Some(batch?),
+            None => None,
+        };
         let b_size = feature_batch.shape()[0];
-        // build bows
-        let nearest = index.assign(
-            feature_batch
-                .as_slice()
-                .expect("features should be in standard layout"),
-            1,
-        )?;
-        for (b, vol_id) in Iterator::zip(nearest.labels.into_iter(), item_batch.into_iter()) {
+        batch_fn(&ItemBatch {
+            features: feature_batch,
+            begin,
+            item: None,
+            item_ids: item_batch.as_ref(),
+            mask: mask_batch.as_ref(),
+            weights: weight_batch.as_ref(),
+        })?;
+        begin += b_size;
+
+        tick_fn(
+            b_size as u32,
+            item_batch.as_ref().and_then(|ids| ids.as_slice()),
+        );
+        Ok(true)
+    })?;
+    if features.prefetches() {
+        status!(
+            "Waited {:.2} s of {:.2} s for features to be read",
+            waited.as_secs_f64(),
+            start.elapsed().as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+/// Assign each feature to its histogram bins, batch by batch
+/// (see `for_each_item_batch`).
+///
+/// `visit_fn` is called with the item, the feature vector, the bin and the
+/// weight of every valid assignment (more than one per feature for product
+/// codebooks). The weights are read from a weights data set, if any, and are
+/// 1 otherwise.
+/// With a valid mask data set, features marked as invalid are skipped.
+/// With `labels_out`, the labels of each feature are also written to it,
+/// `bins_per_feature()` per feature in input order, leaving skipped ones
+/// unassigned.
+#[allow(clippy::too_many_arguments)]
+fn for_each_assignment<V, F>(
+    features: &FeatureSet,
+    items: Items,
+    valid_mask_dset: Option<&Dataset>,
+    weights_dset: Option<&Dataset>,
+    assigner: &mut Assigner,
+    mut labels_out: Option<&mut LabelsWriter>,
+    batch_size: usize,
+    mut visit_fn: V,
+    tick_fn: F,
+) -> DynResult<()>
+where
+    V: FnMut(usize, ArrayView1<f32>, usize, f32),
+    F: Fn(u32, Option<&[u32]>),
+{
+    let mut counts = (0, 0);
+    for_each_item_batch(
+        features,
+        items,
+        valid_mask_dset,
+        weights_dset,
+        batch_size,
+        |batch| {
+            visit_batch(
+                assigner,
+                batch.features.view(),
+                |i| batch.item(i),
+                batch.mask,
+                batch.weights,
+                labels_out
+                    .as_mut()
+                    .map(|out| out.start(batch.features.rows())),
+                &mut visit_fn,
+                &mut counts,
+            )?;
+            match labels_out {
+                Some(ref out) => out.finish(batch.begin),
+                None => Ok(()),
+            }
+        },
+        tick_fn,
+    )?;
+    warn_unassigned(counts.1, counts.0);
+    Ok(())
+}
+
+/// Check that the item IDs of a batch starting at feature `begin` never
+/// decrease, continuing from the last ID of the previous batch.
+fn check_monotonic_ids(
+    ids: &Array1<u32>,
+    begin: usize,
+    batch: usize,
+    last_id: &mut Option<u32>,
+) -> DynResult<()> {
+    for (i, &id) in ids.iter().enumerate() {
+        if let Some(last) = *last_id {
+            if id < last {
+                return Err(failure(
+                    Category::BadInput,
+                    format!(
+                        "item ID decreases from {} to {} at feature {} (batch {})",
+                        last,
+                        id,
+                        begin + i,
+                        batch
+                    ),
+                ));
+            }
+        }
+        *last_id = Some(id);
+    }
+    Ok(())
+}
+
+/// Assign a batch of features and visit their valid assignments,
+/// the `i`-th feature belonging to `item_fn(i)`, skipping those
+/// marked as invalid in `mask`, and weighing the `i`-th feature by
+/// `weights[i]`, if any. The labels of the features which are not
+/// skipped are copied to `labels_out`, if any. `counts` accumulates the number
+/// of assignments and the number of them which were not valid.
+#[allow(clippy::too_many_arguments)]
+fn visit_batch<I, V>(
+    assigner: &mut Assigner,
+    feature_batch: ArrayView2<f32>,
+    item_fn: I,
+    mask: Option<&Array1<bool>>,
+    weights: Option<&Array1<f32>>,
+    mut labels_out: Option<&mut [i64]>,
+    visit_fn: &mut V,
+    counts: &mut (usize, usize),
+) -> DynResult<()>
+where
+    I: Fn(usize) -> usize,
+    V: FnMut(usize, ArrayView1<f32>, usize, f32),
+{
+    let bins_per_feature = assigner.bins_per_feature();
+    let labels = assigner.assign(feature_batch.view())?;
+    for (i, (bins, feature)) in
+        Iterator::zip(labels.chunks(bins_per_feature), feature_batch.outer_iter()).enumerate()
+    {
+        if mask.map_or(false, |mask| !mask[i]) {
+            continue;
+        }
+        if let Some(ref mut out) = labels_out {
+            out[i * bins_per_feature..(i + 1) * bins_per_feature].copy_from_slice(bins);
+        }
+        counts.0 += bins_per_feature;
+        let item = item_fn(i);
+        let weight = weights.map_or(1., |weights| weights[i]);
+        for &b in bins {
             if b >= 0 {
-                *bows
-                    .get_mut((*vol_id as usize, b as usize))
-                    .unwrap_or_else(|| panic!("invalid BoW index ({}, {})", *vol_id, b)) += 1_u32;
+                visit_fn(item, feature, b as usize, weight);
+            } else {
+                counts.1 += 1;
             }
         }
+    }
+    Ok(())
+}
 
-        tick_fn(b_size as u32);
+/// Only redraw the progress bar once it has advanced by `every` steps
+/// of `step` positions, rather than on every update.
+fn throttle_progress(progress: &ProgressBar, every: usize, step: usize) {
+    if every > 1 {
+        progress.set_draw_delta((every * step) as u64);
+    }
+}
+
+/// Warn the user that only the first `n` of `total` features are quantized.
+fn warn_incomplete(n: usize, total: usize) {
+    eprintln!(
+        "Warning: only quantizing the first {} of {} features, the bags are incomplete",
+        n, total
+    );
+}
+
+/// Warn the user if too many features could not be assigned to a codeword,
+/// which usually means that the index was misconfigured.
+fn warn_unassigned(skipped: usize, total: usize) {
+    if total == 0 {
+        return;
     }
+    let fraction = skipped as f64 / total as f64;
+    if fraction > UNASSIGNED_WARN_FRACTION {
+        eprintln!(
+            "Warning: {} out of {} features ({:.2}%) could not be assigned to any codeword",
+            skipped,
+            total,
+            fraction * 100.
+        );
+    }
+}
+
+/// Build histograms of codeword occurrences with the quantizer, with counts
+/// of type `T`. With a weights data set, each feature counts as its weight.
+#[allow(clippy::too_many_arguments)]
+fn construct_bows<T, F>(
+    features: &FeatureSet,
+    items: Items,
+    valid_mask_dset: Option<&Dataset>,
+    weights_dset: Option<&Dataset>,
+    n_items: usize,
+    quantizer: &mut Quantizer,
+    mut labels_out: Option<&mut LabelsWriter>,
+    batch_size: usize,
+    tick_fn: F,
+) -> DynResult<Array2<T>>
+where
+    T: Count,
+    F: Fn(u32, Option<&[u32]>),
+{
+    let mut bows = Array2::<T>::from_elem([n_items, quantizer.n_bins()], T::default());
+    let (assigned, unassigned) = quantizer.assignments();
+    for_each_item_batch(
+        features,
+        items,
+        valid_mask_dset,
+        weights_dset,
+        batch_size,
+        |batch| {
+            quantizer.add_to_histograms(
+                batch.features.view(),
+                |i| batch.item(i),
+                batch.mask.map(Array1::view),
+                batch.weights.map(Array1::view),
+                bows.view_mut(),
+                labels_out
+                    .as_mut()
+                    .map(|out| out.start(batch.features.rows())),
+            )?;
+            match labels_out {
+                Some(ref out) => out.finish(batch.begin),
+                None => Ok(()),
+            }
+        },
+        tick_fn,
+    )?;
+    let (total, skipped) = quantizer.assignments();
+    warn_unassigned(skipped - unassigned, total - assigned);
     Ok(bows)
 }
+
+/// Build histograms of the mean distance between the features
+/// assigned to each codeword and the codeword itself.
+fn construct_residual_magnitudes<F>(
+    features: &FeatureSet,
+    items: Items,
+    valid_mask_dset: Option<&Dataset>,
+    n_items: usize,
+    assigner: &mut Assigner,
+    codebook: ArrayView2<f32>,
+    batch_size: usize,
+    tick_fn: F,
+) -> DynResult<Array2<f32>>
+where
+    F: Fn(u32, Option<&[u32]>),
+{
+    let k = codebook.rows();
+    let mut sums = Array2::<f32>::zeros([n_items, k]);
+    let mut counts = Array2::<u32>::zeros([n_items, k]);
+    // the first item without a histogram, reported once all are visited
+    let mut invalid_item = None;
+    for_each_assignment(
+        features,
+        items,
+        valid_mask_dset,
+        None,
+        assigner,
+        None,
+        batch_size,
+        |item, feature, b, _| {
+            if item >= n_items {
+                invalid_item = invalid_item.or(Some(item));
+                return;
+            }
+            let centroid = codebook.row(b);
+            let magnitude = Iterator::zip(feature.iter(), centroid.iter())
+                .map(|(x, c)| (x - c) * (x - c))
+                .sum::<f32>()
+                .sqrt();
+            sums[(item, b)] += magnitude;
+            counts[(item, b)] += 1;
+        },
+        tick_fn,
+    )?;
+    if let Some(item) = invalid_item {
+        return Err(failure(
+            Category::BadInput,
+            format!("item ID {} is out of range for {} items", item, n_items),
+        ));
+    }
+    Zip::from(&mut sums).and(&counts).apply(|sum, &count| {
+        if count > 0 {
+            *sum /= count as f32;
+        }
+    });
+    Ok(sums)
+}
+
+/// Write a scalar attribute to the given object.
+fn write_attr<T: H5Type>(loc: &Location, name: &str, value: &T) -> DynResult<()> {
+    if loc.attribute_names()?.iter().any(|n| n == name) {
+        // left over from a previous run in a reused file
+        loc.attribute(name)?.write_scalar(value)?;
+        return Ok(());
+    }
+    loc.new_attribute::<T>()
+        .create(name, ())?
+        .write_scalar(value)?;
+    Ok(())
+}
+
+/// Read a scalar attribute from the given object, if it exists.
+fn read_attr<T: H5Type>(loc: &Location, name: &str) -> DynResult<Option<T>> {
+    if !loc.attribute_names()?.iter().any(|n| n == name) {
+        return Ok(None);
+    }
+    Ok(Some(loc.attribute(name)?.read_scalar()?))
+}
+
+/// Write a variable length string attribute to the given object.
+fn write_str_attr(loc: &Location, name: &str, value: &str) -> DynResult<()> {
+    let value: VarLenUnicode = value.parse().map_err(|_| {
+        failure(
+            Category::BadInput,
+            format!("invalid value for attribute `{}`", name),
+        )
+    })?;
+    write_attr(loc, name, &value)
+}
+
+/// Read a variable length string attribute from the given object,
+/// if it exists.
+fn read_str_attr(loc: &Location, name: &str) -> DynResult<Option<String>> {
+    let value: Option<VarLenUnicode> = read_attr(loc, name)?;
+    Ok(value.map(|v| v.as_str().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    /// Counts the allocations made by a thread within `count_allocations`,
+    /// and only allocates through the system allocator everywhere else,
+    /// so that the other tests are not affected.
+    struct CountingAlloc;
+
+    thread_local! {
+        /// The allocations counted on this thread, if counting
+        static ALLOCATIONS: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get().map(|n| n + 1)));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    /// Run `f`, returning the number of allocations it made on this thread.
+    fn count_allocations(f: impl FnOnce()) -> usize {
+        ALLOCATIONS.with(|n| n.set(Some(0)));
+        f();
+        ALLOCATIONS
+            .with(|n| n.replace(None))
+            .expect("allocations should be counted")
+    }
+
+    /// A features file of `n x d` features in the `data` data set,
+    /// the `j`-th column of feature `i` being `i * d + j`.
+    fn features_file(name: &str, n: usize, d: usize) -> (PathBuf, Array2<f32>) {
+        let path = std::env::temp_dir().join(format!("cluster-bob-test-{}.h5", name));
+        let x = Array2::from_shape_fn((n, d), |(i, j)| (i * d + j) as f32);
+        let file = File::open(&path, "w").unwrap();
+        file.new_dataset::<f32>()
+            .no_chunk()
+            .create("data", (n, d))
+            .unwrap()
+            .write(x.view())
+            .unwrap();
+        (path, x)
+    }
+
+    #[test]
+    fn read_rows_into_selects_dims_and_centers() {
+        let (path, x) = features_file("select", 10, 6);
+        let file = File::open(&path, "r").unwrap();
+        let mut features = FeatureSet::open(&file, "data", false).unwrap();
+        features.select_cols("1:5".parse().unwrap()).unwrap();
+        features.select_dims(vec![3, 0]).unwrap();
+        features.center(Array1::from_vec(vec![1., 2.])).unwrap();
+        let mut buffer = Array2::zeros((0, 0));
+        features.read_rows_into(2, 7, &mut buffer).unwrap();
+        let expected = Array2::from_shape_fn((5, 2), |(i, j)| x[(i + 2, [4, 1][j])] - [1., 2.][j]);
+        assert_eq!(buffer, expected);
+    }
+
+    /// Batches are read into a reused buffer rather than a fresh array each,
+    /// which takes fewer allocations per batch.
+    #[test]
+    fn for_each_batch_reuses_buffer() {
+        let (n, d, batch_size) = (4096, 16, 256);
+        let (path, x) = features_file("batches", n, d);
+        let file = File::open(&path, "r").unwrap();
+        let features = FeatureSet::open(&file, "data", false).unwrap();
+
+        let owned = count_allocations(|| {
+            for begin in (0..n).step_by(batch_size) {
+                features.read_rows(begin, begin + batch_size).unwrap();
+            }
+        });
+
+        let mut begin = 0;
+        let reused = count_allocations(|| {
+            features
+                .for_each_batch(batch_size, |batch| {
+                    assert_eq!(batch, x.slice(s![begin..begin + batch_size, ..]));
+                    begin += batch_size;
+                    Ok(true)
+                })
+                .unwrap();
+        });
+        assert_eq!(begin, n);
+        assert!(
+            reused < owned,
+            "{} allocations reusing a buffer, {} without",
+            reused,
+            owned
+        );
+    }
+
+    /// Prefetching yields the same batches as reading them in turn.
+    #[test]
+    fn for_each_batch_prefetch_yields_same_batches() {
+        let (n, d, batch_size) = (1 << 14, 16, 1000);
+        let (path, x) = features_file("prefetch", n, d);
+        let file = File::open(&path, "r").unwrap();
+        let mut features = FeatureSet::open(&file, "data", false).unwrap();
+        for &prefetch in &[false, true] {
+            features.prefetch(prefetch);
+            assert_eq!(features.prefetches(), prefetch);
+            let mut begin = 0;
+            features
+                .for_each_batch(batch_size, |batch| {
+                    let end = usize::min(begin + batch_size, n);
+                    assert_eq!(batch, x.slice(s![begin..end, ..]));
+                    begin = end;
+                    Ok(true)
+                })
+                .unwrap();
+            assert_eq!(begin, n);
+        }
+    }
+
+    /// Mapped features yield the same batches as owned reads,
+    /// viewed in place with fewer allocations.
+    #[test]
+    fn for_each_batch_mmap_allocates_less() {
+        let (n, d, batch_size) = (1 << 14, 16, 1024);
+        let (path, x) = features_file("mmap", n, d);
+        let file = File::open(&path, "r").unwrap();
+        let mut features = FeatureSet::open(&file, "data", false).unwrap();
+        let mut allocations = [0; 2];
+        for (mapped, allocations) in allocations.iter_mut().enumerate() {
+            if mapped == 1 {
+                assert!(features.map_to_memory().unwrap());
+            }
+            let mut begin = 0;
+            *allocations = count_allocations(|| {
+                features
+                    .for_each_batch(batch_size, |batch| {
+                        assert_eq!(batch, x.slice(s![begin..begin + batch_size, ..]));
+                        begin += batch_size;
+                        Ok(true)
+                    })
+                    .unwrap();
+            });
+            assert_eq!(begin, n);
+        }
+        let [owned, mapped] = allocations;
+        assert!(
+            mapped < owned,
+            "{} allocations mapped, {} owned",
+            mapped,
+            owned
+        );
+    }
+
+    /// Points around the corners of a square, with a small offset per point.
+    fn corner_points(n: usize) -> Array2<f32> {
+        Array2::from_shape_fn((n, 2), |(i, j)| {
+            let corner = [(0., 0.), (10., 0.), (0., 10.), (10., 10.)][i % 4];
+            let offset = (i / 4) as f32 * 0.01;
+            if j == 0 {
+                corner.0 + offset
+            } else {
+                corner.1 - offset
+            }
+        })
+    }
+
+    #[test]
+    fn index_centroids_round_trip() {
+        let x = corner_points(400);
+        let mut params = ClusteringParameters::new();
+        params.set_niter(10);
+        let mut cluster = Clustering::new_with_params(2, 4, &params).unwrap();
+        let mut index = FlatIndex::new_l2(2).unwrap();
+        cluster.train(x.as_slice().unwrap(), &mut index).unwrap();
+
+        let centroids = index_centroids(&index, (4, 2)).unwrap().to_owned();
+        let mut rebuilt = codebook_index(centroids.view()).unwrap();
+        let queries = x.as_slice().unwrap();
+        assert_eq!(
+            nearest(&mut index, queries, true).unwrap(),
+            nearest(&mut rebuilt, queries, true).unwrap()
+        );
+    }
+
+    #[test]
+    fn index_centroids_checks_shape() {
+        let mut index = FlatIndex::new_l2(2).unwrap();
+        index.add(&[0., 0., 1., 1., 2., 2.]).unwrap();
+        assert!(index_centroids(&index, (3, 2)).is_ok());
+        assert!(index_centroids(&index, (2, 2)).is_err());
+        assert!(index_centroids(&index, (4, 2)).is_err());
+    }
+
+    #[test]
+    fn labels_writer_writes_each_batch() {
+        let path = std::env::temp_dir().join("cluster-bob-test-labels.h5");
+        let file = File::open(&path, "w").unwrap();
+        let mut labels = LabelsWriter::create(file, "labels", 5, 2).unwrap();
+        labels.start(2).copy_from_slice(&[1, 2, 3, 4]);
+        labels.finish(0).unwrap();
+        // the second feature of this batch is skipped
+        labels.start(2)[..2].copy_from_slice(&[5, 6]);
+        labels.finish(3).unwrap();
+        drop(labels);
+
+        let file = File::open(&path, "r").unwrap();
+        let written: Array2<i64> = file.dataset("labels").unwrap().read_2d().unwrap();
+        let expected = ndarray::arr2(&[[1, 2], [3, 4], [-1, -1], [5, 6], [-1, -1]]);
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    fn two_pass_idf_skips_the_dense_size_check() {
+        let args = |extra: &[&str]| {
+            let base = [
+                "quantize",
+                "vocabulary.h5",
+                "features.h5",
+                "--max-dense-bytes",
+                "1000",
+            ];
+            QuantizeArgs::from_iter(base.iter().chain(extra))
+        };
+        // 100 items of 100 components take 40000 bytes as a dense matrix
+        assert!(check_bags_size(100, 100, &args(&[])).is_err());
+        assert!(check_bags_size(100, 100, &args(&["--two-pass-idf"])).is_ok());
+        assert!(check_bags_size(10, 10, &args(&[])).is_ok());
+    }
+
+    #[test]
+    fn cluster_with_frozen_reports_objective_of_returned_centroids() {
+        let x = corner_points(400);
+        let frozen = ndarray::arr2(&[[0., 0.]]);
+        let mut params = ClusteringParameters::new();
+        params.set_niter(5);
+        let (centroids, objective) =
+            cluster_with_frozen(frozen.view(), x.view(), 4, &params).unwrap();
+        assert_eq!(centroids.row(0), frozen.row(0));
+        let expected: f32 = x
+            .outer_iter()
+            .map(|p| {
+                centroids
+                    .outer_iter()
+                    .map(|c| (&p - &c).mapv(|v| v * v).sum())
+                    .fold(std::f32::INFINITY, f32::min)
+            })
+            .sum();
+        assert!((objective - expected).abs() <= 1e-3 * expected.max(1.));
+    }
+}
@@ -0,0 +1,202 @@
+//! Reading feature matrices from NumPy `.npy` files, and writing results to them.
+//!
+//! Only two-dimensional arrays of little-endian 32-bit or 64-bit floats
+//! in C order are supported, which covers what `numpy.save` writes
+//! for a feature matrix on common platforms.
+use crate::exit::{failure, Category};
+use crate::DynResult;
+use ndarray::Array2;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// The magic string at the start of every `.npy` file.
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// Read a two-dimensional `.npy` file into a matrix of 32-bit floats.
+pub fn read_npy(path: &Path) -> DynResult<Array2<f32>> {
+    let bytes = fs::read(path).map_err(|e| {
+        failure(
+            Category::Io,
+            format!("could not read {}: {}", path.display(), e),
+        )
+    })?;
+    if bytes.len() < 10 || &bytes[..6] != MAGIC {
+        return Err(failure(
+            Category::BadInput,
+            format!("{} is not a .npy file", path.display()),
+        ));
+    }
+    // the header length is a u16 in version 1, and a u32 from version 2 on
+    let (header_start, header_len) = match bytes[6] {
+        1 => (10, u16::from_le_bytes([bytes[8], bytes[9]]) as usize),
+        2 | 3 if bytes.len() >= 12 => (
+            12,
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+        ),
+        version => {
+            return Err(failure(
+                Category::BadInput,
+                format!("unsupported .npy version {}", version),
+            ))
+        }
+    };
+    let data_start = header_start + header_len;
+    if bytes.len() < data_start {
+        return Err(failure(
+            Category::BadInput,
+            format!("{} has a truncated header", path.display()),
+        ));
+    }
+    let header = String::from_utf8_lossy(&bytes[header_start..data_start]);
+
+    let descr = header_value(&header, "descr")
+        .map(|v| v.trim_matches(|c| c == '\'' || c == '"'))
+        .ok_or_else(|| failure(Category::BadInput, "missing `descr` in .npy header"))?;
+    if header_value(&header, "fortran_order") != Some("False") {
+        return Err(failure(
+            Category::BadInput,
+            "only .npy arrays in C order are supported",
+        ));
+    }
+    let shape = header_value(&header, "shape")
+        .ok_or_else(|| failure(Category::BadInput, "missing `shape` in .npy header"))?;
+    let shape: Vec<usize> = shape
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| {
+            failure(
+                Category::BadInput,
+                format!("invalid shape `{}` in .npy header", shape),
+            )
+        })?;
+    let (rows, cols) = match shape[..] {
+        [rows, cols] => (rows, cols),
+        _ => {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "features should be two-dimensional, found shape {:?}",
+                    shape
+                ),
+            ))
+        }
+    };
+
+    let data = &bytes[data_start..];
+    let mut values: Vec<f32> = match descr {
+        "<f4" => data
+            .chunks_exact(4)
+            .map(|b| f32::from_bits(u32::from_le_bytes([b[0], b[1], b[2], b[3]])))
+            .collect(),
+        "<f8" => data
+            .chunks_exact(8)
+            .map(|b| {
+                let mut v = [0; 8];
+                v.copy_from_slice(b);
+                f64::from_bits(u64::from_le_bytes(v)) as f32
+            })
+            .collect(),
+        _ => {
+            return Err(failure(
+                Category::BadInput,
+                format!("unsupported .npy element type `{}`", descr),
+            ))
+        }
+    };
+    if values.len() < rows * cols {
+        return Err(failure(
+            Category::BadInput,
+            format!(
+                "{} has {} values, but {} were expected",
+                path.display(),
+                values.len(),
+                rows * cols
+            ),
+        ));
+    }
+    values.truncate(rows * cols);
+    Ok(Array2::from_shape_vec((rows, cols), values)?)
+}
+
+/// Find the raw value of `key` in the dictionary literal of a `.npy` header.
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header
+        .find(&format!("'{}'", key))
+        .or_else(|| header.find(&format!("\"{}\"", key)))?;
+    let rest = header[start + key.len() + 2..].trim_start();
+    let rest = rest.strip_prefix(':')?.trim_start();
+    // tuples may contain commas, other values end at the next one
+    let end = if rest.starts_with('(') {
+        rest.find(')')? + 1
+    } else {
+        rest.find(|c| c == ',' || c == '}')
+            .unwrap_or_else(|| rest.len())
+    };
+    Some(rest[..end].trim())
+}
+
+/// An element type which can be written to a `.npy` file.
+pub trait NpyElement: Copy {
+    /// The `descr` of the type in `.npy` headers.
+    const DESCR: &'static str;
+
+    /// Write the value in little-endian byte order.
+    fn write_le<W: Write>(self, out: &mut W) -> io::Result<()>;
+}
+
+macro_rules! impl_npy_element {
+    ($($t:ty => $descr:expr),*) => {
+        $(
+            impl NpyElement for $t {
+                const DESCR: &'static str = $descr;
+
+                fn write_le<W: Write>(self, out: &mut W) -> io::Result<()> {
+                    out.write_all(&self.to_le_bytes())
+                }
+            }
+        )*
+    };
+}
+
+impl_npy_element!(u16 => "<u2", u32 => "<u4", i64 => "<i8", f32 => "<f4");
+
+/// Write an array of the given shape in C order as a version 1 `.npy` file.
+pub fn write_npy<T: NpyElement, W: Write>(
+    out: &mut W,
+    shape: &[usize],
+    values: &[T],
+) -> io::Result<()> {
+    let shape = match shape {
+        [n] => format!("({},)", n),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(usize::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        T::DESCR,
+        shape
+    );
+    // the data starts at a multiple of 64 bytes, after a newline
+    let padding = 63 - (MAGIC.len() + 4 + header.len()) % 64;
+    header.extend(std::iter::repeat(' ').take(padding));
+    header.push('\n');
+    out.write_all(MAGIC)?;
+    out.write_all(&[1, 0])?;
+    out.write_all(&(header.len() as u16).to_le_bytes())?;
+    out.write_all(header.as_bytes())?;
+    for &value in values {
+        value.write_le(out)?;
+    }
+    Ok(())
+}
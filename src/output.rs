@@ -0,0 +1,87 @@
+//! Writing output files under a temporary name (`out.h5.tmp` for `out.h5`),
+//! renamed into place once all of their data sets were written,
+//! so that a failed run never leaves a truncated file at the final path.
+use crate::exit::{failure, Category};
+use crate::DynResult;
+use h5::File;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The outputs opened under their temporary name and not renamed yet.
+static PENDING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// The temporary path of the output at `path`.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Open an output file under its temporary name. When forced, the temporary
+/// file starts as a copy of the existing output, if any, so that only the
+/// data sets written to it are replaced. Otherwise, it is created anew.
+/// Opening it again before it is renamed keeps what was written to it.
+pub fn open(path: &Path, force: bool) -> DynResult<File> {
+    let temp = temp_path(path);
+    let mut pending = PENDING
+        .lock()
+        .expect("pending outputs should not be poisoned");
+    let reopened = pending.iter().any(|p| p == path);
+    if !reopened {
+        if force && path.exists() {
+            fs::copy(path, &temp).map_err(|e| {
+                failure(
+                    Category::Io,
+                    format!(
+                        "could not copy {} to {}: {}",
+                        path.display(),
+                        temp.display(),
+                        e
+                    ),
+                )
+            })?;
+        }
+        pending.push(path.to_path_buf());
+    }
+    Ok(File::open(
+        &temp,
+        if force || reopened { "a" } else { "w" },
+    )?)
+}
+
+/// Rename the outputs opened so far into place.
+/// All handles to them must be closed by then, so that they are flushed.
+pub fn commit() -> DynResult<()> {
+    let mut pending = PENDING
+        .lock()
+        .expect("pending outputs should not be poisoned");
+    for path in pending.drain(..) {
+        let temp = temp_path(&path);
+        fs::rename(&temp, &path).map_err(|e| {
+            failure(
+                Category::Io,
+                format!(
+                    "could not move {} to {}: {}",
+                    temp.display(),
+                    path.display(),
+                    e
+                ),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Remove the outputs opened so far without renaming them,
+/// leaving any previous files at their final paths as they were.
+pub fn discard() {
+    let mut pending = PENDING
+        .lock()
+        .expect("pending outputs should not be poisoned");
+    for path in pending.drain(..) {
+        // there is nothing else to do if it cannot be removed
+        let _ = fs::remove_file(temp_path(&path));
+    }
+}
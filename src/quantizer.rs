@@ -0,0 +1,558 @@
+//! Building histograms of codeword counts from features in memory.
+use crate::assign::Assigner;
+use crate::DynResult;
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2, ArrayViewMut1, ArrayViewMut2, Axis};
+use std::ops::AddAssign;
+
+/// The type of the bins of a histogram.
+pub trait Count: Copy + Default + AddAssign {
+    /// Whether the bins can hold weights, rather than only whole counts.
+    const WEIGHTED: bool;
+
+    /// The bin increment of one feature of the given weight.
+    fn from_weight(weight: f32) -> Self;
+}
+
+impl Count for u32 {
+    const WEIGHTED: bool = false;
+
+    fn from_weight(_: f32) -> Self {
+        1
+    }
+}
+
+impl Count for f32 {
+    const WEIGHTED: bool = true;
+
+    fn from_weight(weight: f32) -> Self {
+        weight
+    }
+}
+
+/// Builds histograms of codeword counts (bags of words),
+/// one batch of features at a time.
+pub struct Quantizer {
+    assigner: Assigner,
+    excluded: Vec<bool>,
+    /// The width of the kernel spreading each feature over its nearest
+    /// codewords, if soft assigned.
+    soft_sigma: Option<f32>,
+    /// The weight of each bin, if any.
+    bin_weights: Option<Vec<f32>>,
+    /// The number of assignments made so far
+    assigned: usize,
+    /// The number of assignments which could not be made so far
+    unassigned: usize,
+    /// The weights of the assignments of the last batch, reused by the next.
+    weights: Vec<f32>,
+}
+
+/// The options of a quantizer over a flat codebook.
+pub struct QuantizerBuilder<'a> {
+    codebook: ArrayView2<'a, f32>,
+    stable: bool,
+    collect_stats: bool,
+    subset: Option<Vec<usize>>,
+    excluded: Option<Vec<bool>>,
+    knn: usize,
+    soft_sigma: Option<f32>,
+    bin_weights: Option<Vec<f32>>,
+}
+
+impl<'a> QuantizerBuilder<'a> {
+    /// Break ties between equally near codewords by the lowest index.
+    pub fn stable(mut self, stable: bool) -> Self {
+        self.stable = stable;
+        self
+    }
+
+    /// Collect the number of features assigned to each codeword
+    /// and their distances to it.
+    pub fn collect_stats(mut self, collect: bool) -> Self {
+        self.collect_stats = collect;
+        self
+    }
+
+    /// Only assign features to the given codewords.
+    pub fn subset(mut self, codewords: Vec<usize>) -> Self {
+        self.subset = Some(codewords);
+        self
+    }
+
+    /// Leave out the bins marked as excluded from the histograms,
+    /// one flag per codeword.
+    pub fn excluded(mut self, excluded: Vec<bool>) -> Self {
+        self.excluded = Some(excluded);
+        self
+    }
+
+    /// Count each feature in the bins of its `knn` nearest codewords.
+    pub fn knn(mut self, knn: usize) -> Self {
+        self.knn = knn;
+        self
+    }
+
+    /// Spread each feature over the bins of its nearest codewords
+    /// (see [`knn`](#method.knn)), in proportion to
+    /// `exp(-d² / (2 sigma²))` for a squared distance `d²` to each,
+    /// so that each feature adds up to one. This needs real valued
+    /// histograms.
+    pub fn soft(mut self, sigma: f32) -> Self {
+        self.soft_sigma = Some(sigma);
+        self
+    }
+
+    /// Weigh the counts of each bin, as by their inverse document
+    /// frequencies, one weight per codeword. This needs real valued
+    /// histograms.
+    pub fn bin_weights(mut self, weights: Vec<f32>) -> Self {
+        self.bin_weights = Some(weights);
+        self
+    }
+
+    /// Build the quantizer.
+    pub fn build(self) -> DynResult<Quantizer> {
+        let assigner = match self.subset {
+            Some(ref codewords) => Assigner::flat_subset(self.codebook, codewords)?,
+            None => Assigner::flat(self.codebook)?,
+        };
+        let mut quantizer = Quantizer::new(
+            assigner
+                .stable(self.stable)
+                .collect_stats(self.collect_stats)
+                .knn(self.knn)?,
+        );
+        if let Some(sigma) = self.soft_sigma {
+            quantizer = quantizer.soft(sigma)?;
+        }
+        if let Some(weights) = self.bin_weights {
+            quantizer = quantizer.weighing_bins(weights)?;
+        }
+        match self.excluded {
+            Some(excluded) => quantizer.excluding(excluded),
+            None => Ok(quantizer),
+        }
+    }
+}
+
+impl Quantizer {
+    /// Create a quantizer over the given assigner, without excluded bins.
+    pub fn new(assigner: Assigner) -> Self {
+        let n_bins = assigner.n_bins();
+        Quantizer {
+            assigner,
+            excluded: vec![false; n_bins],
+            soft_sigma: None,
+            bin_weights: None,
+            assigned: 0,
+            unassigned: 0,
+            weights: Vec::new(),
+        }
+    }
+
+    /// Start building a quantizer over a flat codebook.
+    pub fn builder(codebook: ArrayView2<f32>) -> QuantizerBuilder {
+        QuantizerBuilder {
+            codebook,
+            stable: false,
+            collect_stats: false,
+            subset: None,
+            excluded: None,
+            knn: 1,
+            soft_sigma: None,
+            bin_weights: None,
+        }
+    }
+
+    /// Leave out the bins marked as excluded from the histograms.
+    pub fn excluding(mut self, excluded: Vec<bool>) -> DynResult<Self> {
+        if excluded.len() != self.n_bins() {
+            return Err(format!(
+                "{} bins are marked for exclusion, but there are {}",
+                excluded.len(),
+                self.n_bins()
+            )
+            .into());
+        }
+        self.excluded = excluded;
+        Ok(self)
+    }
+
+    /// Spread each feature over the bins of its nearest codewords
+    /// with a kernel of width `sigma` (see
+    /// [`QuantizerBuilder::soft`](struct.QuantizerBuilder.html#method.soft)).
+    pub fn soft(mut self, sigma: f32) -> DynResult<Self> {
+        if !(sigma.is_finite() && sigma > 0.) {
+            return Err(format!("soft assignment width {} is not positive", sigma).into());
+        }
+        self.soft_sigma = Some(sigma);
+        Ok(self)
+    }
+
+    /// Weigh the counts of each bin, one weight per bin.
+    pub fn weighing_bins(mut self, weights: Vec<f32>) -> DynResult<Self> {
+        if weights.len() != self.n_bins() {
+            return Err(format!(
+                "there are {} bin weights, but {} bins",
+                weights.len(),
+                self.n_bins()
+            )
+            .into());
+        }
+        self.bin_weights = Some(weights);
+        Ok(self)
+    }
+
+    /// The number of bins of each histogram.
+    pub fn n_bins(&self) -> usize {
+        self.assigner.n_bins()
+    }
+
+    /// The dimensionality of the features to quantize.
+    pub fn dim(&self) -> usize {
+        self.assigner.dim()
+    }
+
+    /// The number of bins assigned to each feature.
+    pub fn bins_per_feature(&self) -> usize {
+        self.assigner.bins_per_feature()
+    }
+
+    /// Whether features count as real valued weights rather than once
+    /// in each of their bins, so that real valued histograms are needed.
+    pub fn weighted(&self) -> bool {
+        self.soft_sigma.is_some() || self.bin_weights.is_some()
+    }
+
+    /// The assigner and the excluded bins, for building other kinds
+    /// of descriptors with the same assignments.
+    pub fn split(&mut self) -> (&mut Assigner, &[bool]) {
+        (&mut self.assigner, &self.excluded)
+    }
+
+    /// The number of assignments made so far by the histogram methods,
+    /// and how many of them could not be made.
+    pub fn assignments(&self) -> (usize, usize) {
+        (self.assigned, self.unassigned)
+    }
+
+    /// Build the histogram of a batch of features.
+    pub fn histogram(&mut self, features: ArrayView2<f32>) -> DynResult<Array1<u32>> {
+        let mut histogram = Array1::zeros(self.n_bins());
+        self.add_histogram(features, histogram.view_mut())?;
+        Ok(histogram)
+    }
+
+    /// Build the real valued histogram of a batch of features,
+    /// the `i`-th feature counting as `weights[i]`, if any.
+    pub fn weighted_histogram(
+        &mut self,
+        features: ArrayView2<f32>,
+        weights: Option<ArrayView1<f32>>,
+    ) -> DynResult<Array1<f32>> {
+        let mut histogram = Array1::zeros(self.n_bins());
+        self.add_to_histograms(
+            features,
+            |_| 0,
+            None,
+            weights,
+            histogram.view_mut().insert_axis(Axis(0)),
+            None,
+        )?;
+        Ok(histogram)
+    }
+
+    /// Add the codeword counts of a batch of features to a histogram,
+    /// so that the features of one item can be fed batch by batch.
+    pub fn add_histogram(
+        &mut self,
+        features: ArrayView2<f32>,
+        histogram: ArrayViewMut1<u32>,
+    ) -> DynResult<()> {
+        self.add_to_histograms(
+            features,
+            |_| 0,
+            None,
+            None,
+            histogram.insert_axis(Axis(0)),
+            None,
+        )
+    }
+
+    /// Build one histogram per item of a batch of features,
+    /// the `i`-th feature belonging to item `item_ids[i]`.
+    pub fn histograms_by_item(
+        &mut self,
+        features: ArrayView2<f32>,
+        item_ids: ArrayView1<u32>,
+        n_items: usize,
+    ) -> DynResult<Array2<u32>> {
+        if item_ids.len() != features.rows() {
+            return Err(format!(
+                "there are {} item IDs for {} features",
+                item_ids.len(),
+                features.rows()
+            )
+            .into());
+        }
+        if let Some(&id) = item_ids.iter().find(|&&id| id as usize >= n_items) {
+            return Err(format!("item ID {} is out of range for {} items", id, n_items).into());
+        }
+        let mut histograms = Array2::zeros((n_items, self.n_bins()));
+        self.add_to_histograms(
+            features,
+            |i| item_ids[i] as usize,
+            None,
+            None,
+            histograms.view_mut(),
+            None,
+        )?;
+        Ok(histograms)
+    }
+
+    /// Add a batch of features to one histogram per item, the `i`-th
+    /// feature belonging to item `item_fn(i)` and counting as `weights[i]`,
+    /// if any. Features marked as invalid in `mask` are skipped, and the
+    /// labels of the others are copied to `labels_out`, if any,
+    /// `bins_per_feature()` per feature. Fails at the first item without
+    /// a histogram.
+    pub fn add_to_histograms<T, I>(
+        &mut self,
+        features: ArrayView2<f32>,
+        item_fn: I,
+        mask: Option<ArrayView1<bool>>,
+        weights: Option<ArrayView1<f32>>,
+        mut histograms: ArrayViewMut2<T>,
+        mut labels_out: Option<&mut [i64]>,
+    ) -> DynResult<()>
+    where
+        T: Count,
+        I: Fn(usize) -> usize,
+    {
+        if !T::WEIGHTED && (self.weighted() || weights.is_some()) {
+            return Err("weighted features need real valued histograms".into());
+        }
+        if histograms.cols() != self.n_bins() {
+            return Err(format!(
+                "histograms have {} bins, but the quantizer has {}",
+                histograms.cols(),
+                self.n_bins()
+            )
+            .into());
+        }
+        let bins_per_feature = self.assigner.bins_per_feature();
+        let labels = match self.soft_sigma {
+            Some(sigma) => {
+                let (labels, distances) = self.assigner.assign_with_distances(features)?;
+                soft_weights(
+                    labels,
+                    distances,
+                    bins_per_feature,
+                    sigma,
+                    &mut self.weights,
+                );
+                labels
+            }
+            None => self.assigner.assign(features)?,
+        };
+        for (i, bins) in labels.chunks(bins_per_feature).enumerate() {
+            if mask.as_ref().map_or(false, |mask| !mask[i]) {
+                continue;
+            }
+            if let Some(ref mut out) = labels_out {
+                out[i * bins_per_feature..(i + 1) * bins_per_feature].copy_from_slice(bins);
+            }
+            let item = item_fn(i);
+            if item >= histograms.rows() {
+                return Err(format!(
+                    "item ID {} is out of range for {} items",
+                    item,
+                    histograms.rows()
+                )
+                .into());
+            }
+            let weight = weights.as_ref().map_or(1., |weights| weights[i]);
+            for (j, &b) in bins.iter().enumerate() {
+                self.assigned += 1;
+                if b < 0 {
+                    self.unassigned += 1;
+                    continue;
+                }
+                let b = b as usize;
+                if self.excluded[b] {
+                    continue;
+                }
+                let mut w = weight;
+                if self.soft_sigma.is_some() {
+                    w *= self.weights[i * bins_per_feature + j];
+                }
+                if let Some(ref bin_weights) = self.bin_weights {
+                    w *= bin_weights[b];
+                }
+                histograms[(item, b)] += T::from_weight(w);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The soft assignment weights of each label into `out`, `bins` per
+/// feature adding up to one, given their squared distances.
+fn soft_weights(labels: &[i64], distances: &[f32], bins: usize, sigma: f32, out: &mut Vec<f32>) {
+    out.clear();
+    let scale = 1. / (2. * sigma * sigma);
+    for (labels, distances) in labels.chunks(bins).zip(distances.chunks(bins)) {
+        // relative to the nearest codeword, so that the kernel does not
+        // vanish for features far from all of them
+        let nearest = labels
+            .iter()
+            .zip(distances)
+            .filter(|&(&label, _)| label >= 0)
+            .map(|(_, &d)| d)
+            .fold(std::f32::INFINITY, f32::min);
+        let start = out.len();
+        out.extend(labels.iter().zip(distances).map(|(&label, &d)| {
+            if label >= 0 {
+                (-(d - nearest) * scale).exp()
+            } else {
+                0.
+            }
+        }));
+        let total: f32 = out[start..].iter().sum();
+        if total > 0. {
+            for w in &mut out[start..] {
+                *w /= total;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::s;
+
+    fn codebook() -> Array2<f32> {
+        Array2::from_shape_vec((3, 2), vec![0., 0., 10., 0., 0., 10.]).unwrap()
+    }
+
+    fn features() -> Array2<f32> {
+        Array2::from_shape_vec((4, 2), vec![9., 1., 1., 9., 1., 1., 8., 2.]).unwrap()
+    }
+
+    #[test]
+    fn histogram_counts_nearest_codewords() {
+        let codebook = codebook();
+        let mut quantizer = Quantizer::builder(codebook.view()).build().unwrap();
+        let histogram = quantizer.histogram(features().view()).unwrap();
+        assert_eq!(histogram.to_vec(), vec![1, 2, 1]);
+        assert_eq!(quantizer.assignments(), (4, 0));
+    }
+
+    #[test]
+    fn histogram_leaves_out_excluded_bins() {
+        let codebook = codebook();
+        let mut quantizer = Quantizer::builder(codebook.view())
+            .excluded(vec![false, true, false])
+            .build()
+            .unwrap();
+        let histogram = quantizer.histogram(features().view()).unwrap();
+        assert_eq!(histogram.to_vec(), vec![1, 0, 1]);
+    }
+
+    #[test]
+    fn histogram_knn_counts_each_neighbour() {
+        let codebook = codebook();
+        let mut quantizer = Quantizer::builder(codebook.view())
+            .knn(2)
+            .stable(true)
+            .build()
+            .unwrap();
+        assert_eq!(quantizer.bins_per_feature(), 2);
+        let histogram = quantizer.histogram(features().view()).unwrap();
+        // every feature is nearest to 0 or 1, then to the other of them,
+        // except (1, 9), nearest to 2 then 0
+        assert_eq!(histogram.to_vec(), vec![4, 3, 1]);
+    }
+
+    #[test]
+    fn histograms_by_item_split_counts() {
+        let codebook = codebook();
+        let mut quantizer = Quantizer::builder(codebook.view()).build().unwrap();
+        let item_ids = Array1::from_vec(vec![0, 2, 0, 2]);
+        let histograms = quantizer
+            .histograms_by_item(features().view(), item_ids.view(), 3)
+            .unwrap();
+        let expected = Array2::from_shape_vec((3, 3), vec![1, 1, 0, 0, 0, 0, 0, 1, 1]).unwrap();
+        assert_eq!(histograms, expected);
+    }
+
+    #[test]
+    fn histograms_by_item_checks_ids() {
+        let codebook = codebook();
+        let mut quantizer = Quantizer::builder(codebook.view()).build().unwrap();
+        let short = Array1::from_vec(vec![0, 1]);
+        assert!(quantizer
+            .histograms_by_item(features().view(), short.view(), 2)
+            .is_err());
+        let out_of_range = Array1::from_vec(vec![0, 1, 2, 1]);
+        assert!(quantizer
+            .histograms_by_item(features().view(), out_of_range.view(), 2)
+            .is_err());
+    }
+
+    #[test]
+    fn add_to_histograms_checks_items() {
+        let codebook = codebook();
+        let mut quantizer = Quantizer::builder(codebook.view()).build().unwrap();
+        let mut histograms = Array2::<u32>::zeros((2, 3));
+        let result = quantizer.add_to_histograms(
+            features().view(),
+            |i| i,
+            None,
+            None,
+            histograms.view_mut(),
+            None,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn soft_histogram_adds_up_to_feature_weights() {
+        let codebook = codebook();
+        let mut quantizer = Quantizer::builder(codebook.view())
+            .knn(3)
+            .soft(5.)
+            .build()
+            .unwrap();
+        assert!(quantizer.histogram(features().view()).is_err());
+        let weights = Array1::from_vec(vec![1., 2., 0.5, 1.]);
+        let histogram = quantizer
+            .weighted_histogram(features().view(), Some(weights.view()))
+            .unwrap();
+        assert!((histogram.sum() - 4.5).abs() < 1e-5);
+        // (9, 1) is nearest to codeword 1, then 0, then 2
+        let histogram = quantizer
+            .weighted_histogram(features().slice(s![..1, ..]), None)
+            .unwrap();
+        assert!((histogram.sum() - 1.).abs() < 1e-5);
+        assert!(histogram[1] > histogram[0] && histogram[0] > histogram[2]);
+    }
+
+    #[test]
+    fn bin_weights_scale_counts() {
+        let codebook = codebook();
+        let mut quantizer = Quantizer::builder(codebook.view())
+            .bin_weights(vec![2., 0.5, 1.])
+            .build()
+            .unwrap();
+        let histogram = quantizer
+            .weighted_histogram(features().view(), None)
+            .unwrap();
+        assert_eq!(histogram.to_vec(), vec![2., 1., 1.]);
+        assert!(Quantizer::builder(codebook.view())
+            .bin_weights(vec![1.])
+            .build()
+            .is_err());
+    }
+}
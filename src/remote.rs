@@ -0,0 +1,77 @@
+//! Fetching input files from `http(s)://` and `s3://` URLs,
+//! since HDF5 can only open local files.
+//!
+//! Files are downloaded once to a cache directory and opened from there.
+//! `s3://bucket/key` URLs are fetched from the public endpoint of the bucket,
+//! so only objects which can be read anonymously are supported.
+use crate::exit::{failure, Category};
+use crate::DynResult;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Whether the given input path is a URL to fetch.
+pub fn is_url(path: &Path) -> bool {
+    path.to_str().map_or(false, |s| {
+        s.starts_with("http://") || s.starts_with("https://") || s.starts_with("s3://")
+    })
+}
+
+/// The local path of the given input: the path itself for local files,
+/// or that of a cached copy of a URL, downloading it if it is not cached yet.
+/// Without a cache directory, the system's temporary directory is used.
+pub fn fetch(path: &Path, cache_dir: Option<&Path>) -> DynResult<PathBuf> {
+    if !is_url(path) {
+        return Ok(path.to_path_buf());
+    }
+    let url = path.to_str().expect("URLs should be valid UTF-8");
+    let url = match url.strip_prefix("s3://") {
+        Some(rest) => match rest.find('/') {
+            Some(i) => format!("https://{}.s3.amazonaws.com/{}", &rest[..i], &rest[i + 1..]),
+            None => {
+                return Err(failure(
+                    Category::BadInput,
+                    format!("S3 URL `{}` has no object key", url),
+                ))
+            }
+        },
+        None => url.to_string(),
+    };
+
+    // one directory per URL, keeping the file name for the outputs named after it
+    let name = url
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .unwrap_or("features.h5");
+    let name = name.split(|c| c == '?' || c == '#').next().unwrap_or(name);
+    let hash = blake3::hash(url.as_bytes()).to_hex();
+    let dir = cache_dir
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| std::env::temp_dir().join("cluster-bob"))
+        .join(&hash[..16]);
+    let local = dir.join(name);
+    if local.exists() {
+        status!("Using cached copy of {}: {}", url, local.display());
+        return Ok(local);
+    }
+
+    fs::create_dir_all(&dir).map_err(|e| {
+        failure(
+            Category::Io,
+            format!("could not create cache directory {}: {}", dir.display(), e),
+        )
+    })?;
+    status!("Downloading {} to {} ...", url, local.display());
+    let response = ureq::get(&url)
+        .call()
+        .map_err(|e| failure(Category::Io, format!("could not download {}: {}", url, e)))?;
+    // downloaded under a temporary name, so that an interrupted download
+    // is never mistaken for a cached copy
+    let partial = dir.join(format!("{}.part", name));
+    let mut out = File::create(&partial)?;
+    io::copy(&mut response.into_reader(), &mut out)
+        .map_err(|e| failure(Category::Io, format!("could not download {}: {}", url, e)))?;
+    fs::rename(&partial, &local)?;
+    Ok(local)
+}
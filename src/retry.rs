@@ -0,0 +1,41 @@
+//! Retrying reads that fail with transient errors,
+//! such as those of files on network file systems.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// The number of times a failed read is retried.
+static RETRIES: AtomicUsize = AtomicUsize::new(0);
+
+/// The delay before the first retry, doubled on each of the next ones.
+const BASE_DELAY_MS: u64 = 100;
+
+/// Set the number of times a failed read is retried.
+pub fn set_retries(retries: u32) {
+    RETRIES.store(retries as usize, Ordering::SeqCst);
+}
+
+/// Run the read `f`, retrying it with an exponential backoff while it fails
+/// with an error of the HDF5 library. Other errors, such as reads out of
+/// the bounds of a data set, are returned right away.
+pub fn read<T, F>(mut f: F) -> h5::Result<T>
+where
+    F: FnMut() -> h5::Result<T>,
+{
+    let retries = RETRIES.load(Ordering::SeqCst);
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(ref e) if attempt < retries && matches!(e, h5::Error::HDF5(_)) => {
+                let delay = BASE_DELAY_MS << attempt.min(16);
+                attempt += 1;
+                eprintln!(
+                    "Warning: read failed ({}), retrying in {} ms ({}/{}) ...",
+                    e, delay, attempt, retries
+                );
+                thread::sleep(Duration::from_millis(delay));
+            }
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,27 @@
+//! Human status messages, kept apart from the results printed to stdout
+//! so that the results can be piped.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether status messages go to stderr rather than stdout.
+static TO_STDERR: AtomicBool = AtomicBool::new(true);
+
+/// Set whether status messages go to stderr rather than stdout.
+pub fn set_to_stderr(to_stderr: bool) {
+    TO_STDERR.store(to_stderr, Ordering::SeqCst);
+}
+
+/// Check whether status messages go to stderr rather than stdout.
+pub fn to_stderr() -> bool {
+    TO_STDERR.load(Ordering::SeqCst)
+}
+
+/// Print a status message, to stderr unless told otherwise.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if crate::status::to_stderr() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
@@ -0,0 +1,97 @@
+//! Reading feature matrices from a binary stream.
+//!
+//! The stream starts with the dimensionality `d` of the features
+//! as a little-endian `u32`, followed by any number of features,
+//! each made of `d` little-endian `f32` values, until the end of the stream.
+use crate::exit::{failure, Category};
+use crate::DynResult;
+use ndarray::Array2;
+use std::io::{ErrorKind, Read};
+
+/// A stream of features in the binary framing described above.
+#[derive(Debug)]
+pub struct FeatureStream<R> {
+    reader: R,
+    d: usize,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> FeatureStream<R> {
+    /// Start reading features, consuming the header of the stream.
+    pub fn new(mut reader: R) -> DynResult<Self> {
+        let mut header = [0_u8; 4];
+        reader.read_exact(&mut header).map_err(|e| {
+            failure(
+                Category::Io,
+                format!("could not read feature stream header: {}", e),
+            )
+        })?;
+        let d = u32::from_le_bytes(header) as usize;
+        if d == 0 {
+            return Err(failure(
+                Category::BadInput,
+                "feature stream has zero dimensions",
+            ));
+        }
+        Ok(FeatureStream {
+            reader,
+            d,
+            buf: Vec::new(),
+        })
+    }
+
+    /// The dimensionality of each feature.
+    pub fn cols(&self) -> usize {
+        self.d
+    }
+
+    /// Read the next batch of up to `batch_size` features,
+    /// or `None` once the stream has ended.
+    pub fn next_batch(&mut self, batch_size: usize) -> DynResult<Option<Array2<f32>>> {
+        let row_bytes = self.d * 4;
+        self.buf.resize(batch_size * row_bytes, 0);
+
+        // fill the buffer until it is full or the stream ends
+        let mut len = 0;
+        while len < self.buf.len() {
+            match self.reader.read(&mut self.buf[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if len % row_bytes != 0 {
+            return Err(failure(
+                Category::BadInput,
+                format!(
+                    "feature stream ended in the middle of a feature ({} trailing bytes)",
+                    len % row_bytes
+                ),
+            ));
+        }
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let values = self.buf[..len]
+            .chunks(4)
+            .map(|b| f32::from_bits(u32::from_le_bytes([b[0], b[1], b[2], b[3]])))
+            .collect();
+        let batch = Array2::from_shape_vec((len / row_bytes, self.d), values)
+            .expect("batch shape should be consistent");
+        Ok(Some(batch))
+    }
+
+    /// Whether anything is left to read, consuming up to one byte of it.
+    pub fn has_more(&mut self) -> DynResult<bool> {
+        let mut byte = [0];
+        loop {
+            match self.reader.read(&mut byte) {
+                Ok(n) => return Ok(n > 0),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
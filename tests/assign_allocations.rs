@@ -0,0 +1,64 @@
+//! Allocations made by the assigner, counted by an allocator of their own,
+//! installed in this test binary only.
+use cluster_bob::assign::{codebook_index, nearest, Assigner};
+use ndarray::Array2;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// Counts the allocations made by each thread.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+/// The assigner reuses its label buffer, so that it makes no more
+/// allocations per batch than the faiss search itself.
+#[test]
+fn assign_reuses_labels() {
+    let codebook = Array2::from_shape_vec((3, 2), vec![0., 0., 10., 0., 0., 10.]).unwrap();
+    let batch = Array2::from_shape_fn((256, 2), |(i, j)| ((i * 7 + j * 3) % 11) as f32);
+    let x = batch.as_slice().unwrap();
+    let batches = 100;
+
+    // a fresh label vector per batch, as handed out by faiss
+    let mut index = codebook_index(codebook.view()).unwrap();
+    nearest(&mut index, x, false).unwrap();
+    let before = allocations();
+    for _ in 0..batches {
+        nearest(&mut index, x, false).unwrap();
+    }
+    let searches = allocations() - before;
+
+    let mut assigner = Assigner::flat(codebook.view()).unwrap();
+    assigner.assign(batch.view()).unwrap();
+    let before = allocations();
+    for _ in 0..batches {
+        assigner.assign(batch.view()).unwrap();
+    }
+    let assigned = allocations() - before;
+
+    assert_eq!(
+        assigned, searches,
+        "{} allocations by the assigner, {} by faiss searches",
+        assigned, searches
+    );
+}